@@ -0,0 +1,8149 @@
+//! Chunked file storage on Discord webhooks, with the catalogue kept in SQLite.
+//!
+//! The `octo-potato` binary is a thin CLI over this crate; [`ingest_file`] with
+//! [`IngestOptions`] and [`export_file`] are the main entry points.
+#![warn(missing_docs)]
+
+use aes_gcm::aead::consts::U12;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{aes, Aes128Gcm, Aes256Gcm, AesGcm, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use clap::ValueEnum;
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::Figment;
+use futures_util::StreamExt;
+use indicatif::{
+    HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
+};
+use r2d2_sqlite::SqliteConnectionManager;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{multipart, Client};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{IsTerminal, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+pub mod utils;
+
+pub use utils::format_size;
+
+const DEFAULT_DB: &str = "app-data/store.db";
+const DEFAULT_CHUNK_SIZE: usize = 7_000_000;
+const DEFAULT_CONCURRENCY: usize = 3;
+/// Upper bound for `--concurrency`
+pub const MAX_CONCURRENCY: usize = 10;
+/// zstd level used when `--compress` is given without `--compress-level`
+pub const DEFAULT_COMPRESS_LEVEL: i32 = 3;
+const NONCE_LEN: usize = 12;
+const CURRENT_VERSION: i64 = 24;
+/// Attempts per chunk download before an export gives up
+pub const DEFAULT_DOWNLOAD_RETRIES: u32 = 3;
+/// Default for `--max-retries`
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+const BACKUP_VERSION: i64 = 1;
+/// Piece size chunk uploads are streamed in; each piece bumps the byte counter
+const UPLOAD_PIECE: usize = 64 * 1024;
+/// How far back the upload speed is averaged
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// Tables saved by `backup`, parents before the rows that reference them
+const BACKUP_TABLES: [&str; 5] = ["directories", "files", "file_chunks", "tags", "file_tags"];
+/// `--auto-chunk` aims for this many chunks, within the size bounds below
+const AUTO_CHUNK_TARGET: u64 = 100;
+const AUTO_CHUNK_MIN: u64 = 1_000_000;
+/// Discord rejects attachments over 25 MB
+const AUTO_CHUNK_MAX: u64 = 24_000_000;
+/// Upload retries wait a random time up to `min(BACKOFF_CAP, BACKOFF_BASE * 2^attempt)`
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_CAP: Duration = Duration::from_secs(60);
+/// Writes to a locked database wait a random time up to `min(DB_BACKOFF_CAP, DB_BACKOFF_BASE * 2^attempt)`
+const DB_BACKOFF_BASE: Duration = Duration::from_millis(50);
+const DB_BACKOFF_CAP: Duration = Duration::from_secs(2);
+/// How many times a write to a locked database is retried
+pub const DEFAULT_DB_RETRY_COUNT: u32 = 10;
+/// Chunks registered with `import-from-dir --skip-upload` have this URL
+/// scheme followed by the path of the chunk file, which is read instead of
+/// downloaded
+pub const LOCAL_URL_PREFIX: &str = "local://";
+/// Seconds paused after each chunk upload, picked at random from this range
+pub const DEFAULT_UPLOAD_DELAY: RangeInclusive<u64> = 2..=6;
+/// Seconds waited when Discord rate limits a delete, picked at random from this range
+pub const DEFAULT_RATE_LIMIT_DELAY: RangeInclusive<u64> = 5..=15;
+/// How long a single HTTP request may take
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many chunks move at once, how often uploads are retried, where
+/// their progress bars are drawn and whether uploaded chunks are also kept
+/// under `storage/`
+#[derive(Clone, Debug)]
+pub struct TransferOptions {
+    /// Chunks uploaded or downloaded at once
+    pub concurrency: usize,
+    /// Attempts per chunk before giving up
+    pub max_retries: u32,
+    /// Where progress bars are drawn; hidden by default
+    pub progress: MultiProgress,
+    /// Delays, timeout and HTTP client settings
+    pub pacing: UploadConfig,
+    /// Keep a copy of each chunk in `storage/{file_id}/`, relative to the
+    /// current directory
+    pub local_storage: bool,
+}
+
+/// Random pauses, in seconds, that keep requests from bunching up: one after
+/// every chunk upload and one whenever Discord answers a delete with 429.
+/// Also how long any single HTTP request may take and what it is sent with.
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    upload_delay: RangeInclusive<u64>,
+    rate_limit_delay: RangeInclusive<u64>,
+    timeout: Duration,
+    user_agent: String,
+    headers: HeaderMap,
+    proxy: ProxySetting,
+}
+
+/// Which proxy HTTP requests go through
+#[derive(Clone, Debug)]
+pub enum ProxySetting {
+    /// Whatever `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` say
+    System,
+    /// `--proxy ""`: connect directly even if a system proxy is set
+    Disabled,
+    /// An explicit proxy URL
+    Url(Box<reqwest::Proxy>),
+}
+
+impl ProxySetting {
+    /// Reads a `--proxy` value: `None` keeps the system proxy, an empty string turns proxies off
+    pub fn parse(url: Option<&str>) -> Result<Self> {
+        let Some(url) = url else {
+            return Ok(ProxySetting::System);
+        };
+        if url.is_empty() {
+            return Ok(ProxySetting::Disabled);
+        }
+        let parsed =
+            reqwest::Url::parse(url).with_context(|| format!("invalid proxy URL '{url}'"))?;
+        if !["http", "https", "socks5", "socks5h"].contains(&parsed.scheme()) {
+            bail!("invalid proxy URL '{url}': expected an http://, https:// or socks5:// URL");
+        }
+        if parsed.host_str().is_none() {
+            bail!("invalid proxy URL '{url}': no host");
+        }
+        let proxy =
+            reqwest::Proxy::all(parsed).with_context(|| format!("invalid proxy URL '{url}'"))?;
+        Ok(ProxySetting::Url(Box::new(proxy)))
+    }
+
+    /// `--socks5 host:port`
+    pub fn socks5(addr: &str) -> Result<Self> {
+        if addr.contains("://") {
+            bail!("--socks5 takes host:port, not a URL; use --proxy for '{addr}'");
+        }
+        let Some((host, port)) = addr.rsplit_once(':') else {
+            bail!("invalid --socks5 address '{addr}': expected host:port");
+        };
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            bail!("invalid --socks5 address '{addr}': expected host:port");
+        }
+        Self::parse(Some(&format!("socks5://{addr}")))
+    }
+}
+
+impl UploadConfig {
+    /// Pacing from the given ranges and timeout, sending `config`'s user
+    /// agent and extra headers through `proxy`.
+    pub fn new(
+        upload_delay: RangeInclusive<u64>,
+        rate_limit_delay: RangeInclusive<u64>,
+        timeout: Duration,
+        config: &Config,
+        proxy: ProxySetting,
+    ) -> Result<Self> {
+        for (what, range) in [
+            ("upload delay", &upload_delay),
+            ("rate limit delay", &rate_limit_delay),
+        ] {
+            if range.is_empty() {
+                bail!(
+                    "{what}: the minimum ({}) must not be greater than the maximum ({})",
+                    range.start(),
+                    range.end()
+                );
+            }
+        }
+        // retrying a rate-limited request without waiting only gets it rate limited again
+        if *rate_limit_delay.start() == 0 {
+            bail!("rate limit delay: the minimum must be at least 1 second");
+        }
+        Ok(Self {
+            upload_delay,
+            rate_limit_delay,
+            timeout,
+            user_agent: config.user_agent.clone(),
+            headers: parse_extra_headers(&config.extra_header)?,
+            proxy,
+        })
+    }
+
+    /// HTTP client that gives up on a request after `timeout`, and on
+    /// connecting after half of it.
+    pub fn client(&self) -> Result<Client> {
+        let builder = Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.timeout / 2)
+            .user_agent(&self.user_agent)
+            .default_headers(self.headers.clone());
+        let builder = match &self.proxy {
+            ProxySetting::System => builder,
+            ProxySetting::Disabled => builder.no_proxy(),
+            ProxySetting::Url(proxy) => builder.proxy(*proxy.clone()),
+        };
+        builder.build().context("building HTTP client")
+    }
+
+    fn upload_pause(&self) -> Duration {
+        Duration::from_secs(rand::rng().random_range(self.upload_delay.clone()))
+    }
+
+    fn rate_limit_pause(&self) -> Duration {
+        Duration::from_secs(rand::rng().random_range(self.rate_limit_delay.clone()))
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            upload_delay: DEFAULT_UPLOAD_DELAY,
+            rate_limit_delay: DEFAULT_RATE_LIMIT_DELAY,
+            timeout: DEFAULT_TIMEOUT,
+            user_agent: default_user_agent(),
+            headers: HeaderMap::new(),
+            proxy: ProxySetting::System,
+        }
+    }
+}
+
+impl Default for TransferOptions {
+    /// The CLI's defaults, without progress bars.
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_CONCURRENCY,
+            max_retries: DEFAULT_MAX_RETRIES,
+            progress: MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            pacing: UploadConfig::default(),
+            local_storage: true,
+        }
+    }
+}
+
+/// `octo-potato/<version>`, sent unless `user_agent` is configured.
+pub fn default_user_agent() -> String {
+    format!("octo-potato/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// How chunk bytes are transformed between being read and being uploaded
+#[derive(Clone, Default)]
+struct ChunkEncoding {
+    compress_level: Option<i32>,
+    cipher: Option<ChunkCipher>,
+    hash_algo: HashAlgo,
+    /// Bytes of the previous chunk repeated at the start of each chunk
+    overlap: usize,
+}
+
+/// Chunk size, transfer settings and encoding for the `ingest_*` functions.
+///
+/// ```no_run
+/// # use octo_potato::{HashAlgo, IngestOptions};
+/// let options = IngestOptions::new()
+///     .chunk_size(8_000_000)
+///     .concurrency(4)
+///     .compress(Some(3))
+///     .hash_algo(HashAlgo::Blake3);
+/// ```
+#[derive(Clone)]
+pub struct IngestOptions {
+    chunk_size: usize,
+    transfer: TransferOptions,
+    encoding: ChunkEncoding,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            transfer: TransferOptions::default(),
+            encoding: ChunkEncoding::default(),
+        }
+    }
+}
+
+impl IngestOptions {
+    /// 7 MB chunks, uncompressed and unencrypted, with `TransferOptions::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes of the original file per chunk.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Replace every transfer setting at once.
+    pub fn transfer(mut self, transfer: TransferOptions) -> Self {
+        self.transfer = transfer;
+        self
+    }
+
+    /// How many chunks are uploaded at the same time.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.transfer.concurrency = concurrency;
+        self
+    }
+
+    /// How many times a failed chunk upload is retried.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.transfer.max_retries = max_retries;
+        self
+    }
+
+    /// Whether chunks are also written to `storage/` for `reupload`.
+    pub fn local_storage(mut self, local_storage: bool) -> Self {
+        self.transfer.local_storage = local_storage;
+        self
+    }
+
+    /// zstd-compress each chunk at this level; `None` uploads them as read.
+    pub fn compress(mut self, level: Option<i32>) -> Self {
+        self.encoding.compress_level = level;
+        self
+    }
+
+    /// Encrypt each chunk with this cipher; `None` uploads them in the clear.
+    pub fn encrypt(mut self, cipher: Option<ChunkCipher>) -> Self {
+        self.encoding.cipher = cipher;
+        self
+    }
+
+    /// Algorithm for the whole-file and per-chunk hashes.
+    pub fn hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.encoding.hash_algo = hash_algo;
+        self
+    }
+
+    /// Start every chunk after the first with the last `overlap` bytes of
+    /// the one before it, so a damaged boundary can be pieced together from
+    /// either side. Must be less than the chunk size.
+    pub fn chunk_overlap(mut self, overlap: usize) -> Self {
+        self.encoding.overlap = overlap;
+        self
+    }
+
+    fn check_overlap(&self) -> Result<()> {
+        if self.encoding.overlap >= self.chunk_size {
+            bail!(
+                "--chunk-overlap ({}) must be less than the chunk size ({})",
+                self.encoding.overlap,
+                self.chunk_size
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A chunk that made it to Discord, ready to be recorded in `file_chunks`
+struct UploadedChunk {
+    idx: usize,
+    message_id: String,
+    url: String,
+    sha256: String,
+    nonce_hex: Option<String>,
+    /// Position in `--webhook` of the webhook that accepted the upload
+    webhook_idx: usize,
+    /// Bytes actually uploaded, after compression and encryption
+    stored_size: usize,
+    /// Bytes at the start repeated from the previous chunk
+    overlap: usize,
+}
+
+/// A `file_chunks` row with everything needed to download and decode the chunk
+struct StoredChunk {
+    idx: i64,
+    url: String,
+    sha256: String,
+    compressed: bool,
+    encrypted: bool,
+    nonce_hex: Option<String>,
+    /// Leading bytes repeated from the previous chunk, dropped on decode
+    overlap: usize,
+}
+
+/// How listing commands print their rows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Formatter {
+    /// One aligned line per row
+    Human,
+    /// A JSON array of objects keyed by column name
+    Json,
+    /// CSV with a header row
+    Csv,
+}
+
+impl Formatter {
+    /// Prints `rows` as a table, JSON or CSV
+    pub fn print<T: Serialize + fmt::Display>(self, rows: &[T]) -> Result<()> {
+        match self {
+            Formatter::Human => {
+                for row in rows {
+                    println!("{row}");
+                }
+            }
+            Formatter::Json => println!("{}", serde_json::to_string_pretty(rows)?),
+            Formatter::Csv => {
+                let mut writer = csv::Writer::from_writer(std::io::stdout());
+                for row in rows {
+                    writer.serialize(row)?;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings that can come from a config file or the environment as well as the CLI.
+///
+/// Layers, lowest priority first: built-in defaults, `~/.config/octo-potato/config.toml`,
+/// `./octo-potato.toml`, `OCTO_*` environment variables, command-line flags.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Path of the SQLite database
+    pub db: PathBuf,
+    /// Webhook URLs, one or many
+    #[serde(deserialize_with = "one_or_many")]
+    pub webhook: Vec<String>,
+    /// Chunk size in bytes
+    pub chunk_size: usize,
+    /// Chunks uploaded at once
+    pub concurrency: usize,
+    /// Compress chunks with zstd
+    pub compress: bool,
+    /// Name of the entry in `workspaces` to use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<String>,
+    /// Named groups of webhooks for `--workspace`
+    pub workspaces: BTreeMap<String, Workspace>,
+    /// Key file for `--encrypt` and for exporting encrypted files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<PathBuf>,
+    /// `User-Agent` for every request
+    pub user_agent: String,
+    /// Extra `Name: value` headers for every request
+    pub extra_header: Vec<String>,
+    /// Proxy for every HTTP request; empty means none, not even the system one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// SQLCipher passphrase of the database, usually from `OCTO_DB_PASSPHRASE`
+    #[serde(skip_serializing)]
+    pub db_passphrase: Option<Passphrase>,
+}
+
+/// A database passphrase, kept out of `Debug` output such as `--verbose`'s
+/// config dump
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    /// The passphrase itself
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Self {
+        Self(passphrase)
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// A named database and its webhooks, kept under `[workspaces.<name>]`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Workspace {
+    db: PathBuf,
+    #[serde(default, deserialize_with = "one_or_many")]
+    webhook: Vec<String>,
+}
+
+/// Accept a single comma-separated string as well as a list, so that
+/// `OCTO_WEBHOOK=https://...` works without TOML array syntax.
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => s
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect(),
+        OneOrMany::Many(urls) => urls,
+    })
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            db: PathBuf::from(DEFAULT_DB),
+            webhook: Vec::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            concurrency: DEFAULT_CONCURRENCY,
+            compress: false,
+            workspace: None,
+            workspaces: BTreeMap::new(),
+            encryption_key: None,
+            user_agent: default_user_agent(),
+            extra_header: Vec::new(),
+            proxy: None,
+            db_passphrase: None,
+        }
+    }
+}
+
+/// The subset of `Config` given on the command line; unset fields leave lower layers alone.
+#[derive(Serialize)]
+pub struct CliConfig<'a> {
+    /// `--db`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<&'a PathBuf>,
+    /// `--webhook`
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub webhook: &'a [String],
+    /// `--chunk-size`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<usize>,
+    /// `--concurrency`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    /// `--compress`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub compress: bool,
+    /// `--workspace`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<&'a String>,
+    /// `--key-file`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<&'a PathBuf>,
+    /// `--user-agent`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<&'a String>,
+    /// `--extra-header`
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub extra_header: &'a [String],
+    /// `--proxy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<&'a String>,
+    /// `--encrypt-db`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db_passphrase: Option<&'a String>,
+}
+
+impl Config {
+    /// Layer the config files, `OCTO_*` variables and `overrides` over the defaults.
+    pub fn load(overrides: &CliConfig) -> Result<Self> {
+        let mut figment = Figment::from(Serialized::defaults(Config::default()));
+        if let Some(path) = user_config_path() {
+            figment = figment.merge(Toml::file(path));
+        }
+        let mut config: Config = figment
+            .merge(Toml::file("octo-potato.toml"))
+            .merge(Env::prefixed("OCTO_"))
+            .merge(Serialized::defaults(overrides))
+            .extract()?;
+        // the flag is range-checked by clap, but the config file and env aren't
+        if !(1..=MAX_CONCURRENCY).contains(&config.concurrency) {
+            bail!(
+                "concurrency must be between 1 and {MAX_CONCURRENCY}, got {}",
+                config.concurrency
+            );
+        }
+        if let Some(name) = &config.workspace {
+            let workspace = config
+                .workspaces
+                .get(name)
+                .with_context(|| format!("no workspace named '{name}'; see `workspace-list`"))?;
+            config.db = workspace.db.clone();
+            config.webhook = workspace.webhook.clone();
+        }
+        Ok(config)
+    }
+
+    /// Webhooks of the selected workspace, or the top-level `webhook` list
+    pub fn webhooks(&self) -> Result<&[String]> {
+        if self.webhook.is_empty() {
+            bail!(
+                "no webhook configured; pass --webhook, set OCTO_WEBHOOK or set `webhook` in octo-potato.toml"
+            );
+        }
+        Ok(&self.webhook)
+    }
+}
+
+/// Turn `name:value` strings into headers, splitting each on its first `:`.
+fn parse_extra_headers(headers: &[String]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("invalid extra header '{header}': expected name:value"))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .with_context(|| format!("invalid extra header name in '{header}'"))?;
+        let value = HeaderValue::from_str(value.trim())
+            .with_context(|| format!("invalid extra header value in '{header}'"))?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// `~/.config/octo-potato/config.toml`, where workspaces are added and removed
+fn user_config_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".config/octo-potato/config.toml"))
+}
+
+/// One line of `workspace-list`
+#[derive(Serialize)]
+pub struct WorkspaceRow {
+    name: String,
+    db: PathBuf,
+    webhooks: usize,
+    active: bool,
+}
+
+impl fmt::Display for WorkspaceRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} db={} webhooks={}",
+            if self.active { "*" } else { " " },
+            self.name,
+            self.db.display(),
+            self.webhooks
+        )
+    }
+}
+
+/// Configured workspaces, marking the one in use
+pub fn list_workspaces(config: &Config) -> Vec<WorkspaceRow> {
+    config
+        .workspaces
+        .iter()
+        .map(|(name, workspace)| WorkspaceRow {
+            name: name.clone(),
+            db: workspace.db.clone(),
+            webhooks: workspace.webhook.len(),
+            active: config.workspace.as_ref() == Some(name),
+        })
+        .collect()
+}
+
+/// Parse the user config file for editing, keeping its comments and layout.
+fn read_user_config() -> Result<(PathBuf, toml_edit::DocumentMut)> {
+    let path = user_config_path().context("HOME is not set; can't locate the config file")?;
+    let doc = match fs::read_to_string(&path) {
+        Ok(text) => text
+            .parse()
+            .with_context(|| format!("parsing {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml_edit::DocumentMut::new(),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    Ok((path, doc))
+}
+
+fn write_user_config(path: &Path, doc: &toml_edit::DocumentMut) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, doc.to_string()).with_context(|| format!("writing {}", path.display()))
+}
+
+/// Add `[workspaces.<name>]` to the user config file. Returns the file written.
+pub fn add_workspace(name: &str, db: &Path, webhook: &str) -> Result<PathBuf> {
+    let (path, mut doc) = read_user_config()?;
+    let workspaces = doc
+        .entry("workspaces")
+        .or_insert_with(|| {
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            toml_edit::Item::Table(table)
+        })
+        .as_table_mut()
+        .with_context(|| format!("`workspaces` in {} is not a table", path.display()))?;
+    if workspaces.contains_key(name) {
+        bail!(
+            "workspace '{name}' already exists in {}; remove it first",
+            path.display()
+        );
+    }
+    let mut workspace = toml_edit::Table::new();
+    workspace["db"] = toml_edit::value(db.to_string_lossy().as_ref());
+    workspace["webhook"] = toml_edit::value(toml_edit::Array::from_iter([webhook]));
+    workspaces.insert(name, toml_edit::Item::Table(workspace));
+    write_user_config(&path, &doc)?;
+    Ok(path)
+}
+
+/// Remove `[workspaces.<name>]` from the user config file. Returns the file written.
+pub fn remove_workspace(name: &str) -> Result<PathBuf> {
+    let (path, mut doc) = read_user_config()?;
+    let removed = doc
+        .get_mut("workspaces")
+        .and_then(|item| item.as_table_like_mut())
+        .and_then(|workspaces| workspaces.remove(name));
+    if removed.is_none() {
+        bail!("no workspace named '{name}' in {}", path.display());
+    }
+    write_user_config(&path, &doc)?;
+    Ok(path)
+}
+
+/// A `files` row as shown by `list` and `search`
+#[derive(Serialize)]
+pub struct FileRow {
+    id: i64,
+    filename: String,
+    filesize: i64,
+    chunk_size: i64,
+    chunk_count: i64,
+    created_at: String,
+    status: String,
+    mime_type: Option<String>,
+    description: Option<String>,
+    deleted_at: Option<String>,
+    /// Only filled in by `with_directories`; `/` for files at the root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    directory: Option<String>,
+}
+
+impl FileRow {
+    const COLUMNS: &'static str =
+        "id, filename, filesize, chunk_size, created_at, status, mime_type, description, deleted_at,
+         chunk_count";
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            filename: row.get(1)?,
+            filesize: row.get(2)?,
+            chunk_size: row.get(3)?,
+            created_at: row.get(4)?,
+            status: row.get(5)?,
+            mime_type: row.get(6)?,
+            description: row.get(7)?,
+            deleted_at: row.get(8)?,
+            chunk_count: row.get(9)?,
+            directory: None,
+        })
+    }
+}
+
+impl fmt::Display for FileRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut status = if self.status == "complete" {
+            String::new()
+        } else {
+            format!("[{}]", self.status)
+        };
+        if let Some(deleted_at) = &self.deleted_at {
+            status.push_str(&format!("[deleted {}]", deleted_at));
+        }
+        write!(
+            f,
+            "id={:<3} size={:<11} chunk_size={:<10} chunks={:<4} created_at={} file={} {}",
+            self.id,
+            format_size(self.filesize),
+            format_size(self.chunk_size),
+            self.chunk_count,
+            self.created_at,
+            self.filename,
+            status
+        )?;
+        if let Some(directory) = &self.directory {
+            write!(f, " dir={}", directory)?;
+        }
+        Ok(())
+    }
+}
+
+/// A tag as shown by `list-tags`
+#[derive(Serialize)]
+pub struct TagRow {
+    name: String,
+    files: i64,
+}
+
+impl fmt::Display for TagRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<20} {}", self.name, self.files)
+    }
+}
+
+/// A `file_chunks` row as shown by `list-chunks`
+#[derive(Serialize)]
+pub struct ChunkRow {
+    idx: i64,
+    message_id: String,
+    url: String,
+    sha256: String,
+}
+
+impl fmt::Display for ChunkRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sha256 = if self.sha256.is_empty() {
+            "-"
+        } else {
+            &self.sha256
+        };
+        write!(
+            f,
+            "idx={:<4} message_id={} sha256={} url={}",
+            self.idx, self.message_id, sha256, self.url
+        )
+    }
+}
+
+/// One version of a file as shown by `list-versions`
+#[derive(Serialize)]
+pub struct VersionRow {
+    version: i64,
+    id: i64,
+    filename: String,
+    filesize: i64,
+    created_at: String,
+    status: String,
+}
+
+impl fmt::Display for VersionRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "v{:<3} id={:<4} size={:<11} created_at={} status={} file={}",
+            self.version,
+            self.id,
+            format_size(self.filesize),
+            self.created_at,
+            self.status,
+            self.filename
+        )
+    }
+}
+
+/// Every version of the file `file_id` belongs to, oldest first: walk
+/// `parent_file_id` up to the first version, then back down to the newest.
+pub fn version_chain(conn: &Connection, file_id: i64) -> Result<Vec<VersionRow>> {
+    let mut stmt = conn.prepare(
+        "WITH RECURSIVE
+             up(id, parent) AS (
+                 SELECT id, parent_file_id FROM files WHERE id = ?1
+                 UNION SELECT f.id, f.parent_file_id FROM files f JOIN up ON f.id = up.parent
+             ),
+             down(id) AS (
+                 SELECT id FROM up WHERE parent IS NULL
+                 UNION SELECT f.id FROM files f JOIN down ON f.parent_file_id = down.id
+             )
+         SELECT f.version, f.id, f.filename, f.filesize, f.created_at, f.status
+         FROM files f JOIN down USING (id)
+         ORDER BY f.version, f.id",
+    )?;
+    let rows = stmt
+        .query_map(params![file_id], |row| {
+            Ok(VersionRow {
+                version: row.get(0)?,
+                id: row.get(1)?,
+                filename: row.get(2)?,
+                filesize: row.get(3)?,
+                created_at: row.get(4)?,
+                status: row.get(5)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if rows.is_empty() {
+        bail!("no file with id {file_id}");
+    }
+    Ok(rows)
+}
+
+/// The file id holding `version` of `file_id`'s chain, or the latest version
+/// when `version` is `None`.
+pub fn select_version(conn: &Connection, file_id: i64, version: Option<i64>) -> Result<i64> {
+    let chain = version_chain(conn, file_id)?;
+    let latest = chain.last().expect("chain is never empty");
+    let selected = match version {
+        Some(version) => chain
+            .iter()
+            .find(|row| row.version == version)
+            .with_context(|| {
+                format!(
+                    "file_id={file_id} has no version {version}; it has versions 1 to {}",
+                    latest.version
+                )
+            })?,
+        None => latest,
+    };
+    if selected.id != file_id {
+        info!(
+            file_id,
+            "exporting version {} (file_id={})", selected.version, selected.id
+        );
+    }
+    Ok(selected.id)
+}
+
+/// Ingest `path` as a new version after the latest one in `file_id`'s chain,
+/// in the same directory. Returns the new file id and its version number.
+pub async fn ingest_new_version(
+    conn: &mut Connection,
+    file_id: i64,
+    path: &Path,
+    webhooks: &[String],
+    options: &IngestOptions,
+) -> Result<(i64, i64)> {
+    let chain = version_chain(conn, file_id)?;
+    let latest = chain.last().expect("chain is never empty");
+    let directory_id: Option<i64> = conn.query_row(
+        "SELECT directory_id FROM files WHERE id = ?1",
+        params![latest.id],
+        |row| row.get(0),
+    )?;
+    let new_id = ingest_file(conn, path, webhooks, options).await?;
+    let version = latest.version + 1;
+    conn.execute(
+        "UPDATE files SET parent_file_id = ?1, version = ?2, directory_id = ?3 WHERE id = ?4",
+        params![latest.id, version, directory_id, new_id],
+    )?;
+    audit(
+        conn,
+        "new-version",
+        "file",
+        new_id,
+        &format!("version {} of file {}", version, latest.id),
+    )?;
+    Ok((new_id, version))
+}
+
+/// Point the versions after `file_id` at its parent instead, so the chain
+/// survives `file_id`'s row being deleted.
+fn unlink_version(conn: &Connection, file_id: i64) -> Result<()> {
+    conn.execute(
+        "UPDATE files SET parent_file_id = (SELECT parent_file_id FROM files WHERE id = ?1)
+         WHERE parent_file_id = ?1",
+        params![file_id],
+    )?;
+    Ok(())
+}
+
+/// A `directories` row as shown by `list-dirs`
+#[derive(Serialize)]
+pub struct DirRow {
+    /// Directory id
+    pub id: i64,
+    /// Directory name
+    pub name: String,
+}
+
+impl fmt::Display for DirRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} - {}", self.id, self.name)
+    }
+}
+
+/// Print `list`/`search` results, adding the MIME type and the start of the
+/// description to human output with `--verbose`.
+pub fn print_files(format: Formatter, verbose: bool, files: &[FileRow]) -> Result<()> {
+    if verbose && format == Formatter::Human {
+        for file in files {
+            let mut line = format!(
+                "{} mime_type={}",
+                file,
+                file.mime_type.as_deref().unwrap_or("unknown")
+            );
+            if let Some(description) = &file.description {
+                let mut short: String = description.chars().take(60).collect();
+                if short.len() < description.len() {
+                    short.push('…');
+                }
+                line.push_str(&format!(" description={:?}", short));
+            }
+            println!("{line}");
+        }
+        return Ok(());
+    }
+    format.print(files)
+}
+
+/// Which files `list` shows
+#[derive(clap::Args, Debug, Default)]
+pub struct ListFilter {
+    /// Only list files whose MIME type matches this glob, e.g. `video/*`
+    #[arg(long)]
+    mime_type: Option<String>,
+    /// Only list files added at or after this time (RFC 3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    since: Option<DateTime<Utc>>,
+    /// Only list files added before this time (RFC 3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    before: Option<DateTime<Utc>>,
+    #[command(flatten)]
+    size: SizeRange,
+    /// Also list soft-deleted files
+    #[arg(long)]
+    include_deleted: bool,
+}
+
+/// Bounds on `files.filesize`, both inclusive
+#[derive(clap::Args, Debug, Default)]
+pub struct SizeRange {
+    /// Only include files at least this large, e.g. `500KiB` or `1MB`
+    #[arg(long, value_parser = parse_size)]
+    size_min: Option<i64>,
+    /// Only include files at most this large, e.g. `2GiB`
+    #[arg(long, value_parser = parse_size)]
+    size_max: Option<i64>,
+}
+
+impl SizeRange {
+    fn validate(&self) -> Result<()> {
+        if let (Some(min), Some(max)) = (self.size_min, self.size_max) {
+            if min > max {
+                bail!("--size-min ({min}) is larger than --size-max ({max})");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a byte count with an optional unit: `B`, decimal `KB`/`MB`/`GB`/`TB`
+/// or binary `KiB`/`MiB`/`GiB`/`TiB`. Units are case-insensitive and the
+/// number may have a fractional part, e.g. `1.5GiB`.
+fn parse_size(s: &str) -> Result<i64> {
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        other => bail!("unknown size unit '{other}' in '{s}'"),
+    };
+    let bytes = if number.contains('.') {
+        let value: f64 = number
+            .parse()
+            .with_context(|| format!("invalid size: '{s}'"))?;
+        (value * multiplier as f64).round()
+    } else {
+        let value: u64 = number
+            .parse()
+            .with_context(|| format!("invalid size: '{s}'"))?;
+        value
+            .checked_mul(multiplier)
+            .with_context(|| format!("size too large: '{s}'"))? as f64
+    };
+    if bytes > i64::MAX as f64 {
+        bail!("size too large: '{s}'");
+    }
+    Ok(bytes as i64)
+}
+
+impl ListFilter {
+    /// The WHERE clause selecting the matching files, with its parameters.
+    fn sql(&self) -> (String, Vec<Box<dyn rusqlite::ToSql>>) {
+        // `created_at` is always written as UTC RFC 3339, so it sorts as text
+        let mut clauses = Vec::new();
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+        if !self.include_deleted {
+            clauses.push("deleted_at IS NULL");
+        }
+        if let Some(mime_type) = &self.mime_type {
+            clauses.push("mime_type GLOB ?");
+            values.push(Box::new(mime_type.clone()));
+        }
+        if let Some(since) = self.since {
+            clauses.push("created_at >= ?");
+            values.push(Box::new(since.to_rfc3339()));
+        }
+        if let Some(before) = self.before {
+            clauses.push("created_at < ?");
+            values.push(Box::new(before.to_rfc3339()));
+        }
+        if let Some(min) = self.size.size_min {
+            clauses.push("filesize >= ?");
+            values.push(Box::new(min));
+        }
+        if let Some(max) = self.size.size_max {
+            clauses.push("filesize <= ?");
+            values.push(Box::new(max));
+        }
+        if clauses.is_empty() {
+            (String::new(), values)
+        } else {
+            (format!("WHERE {}", clauses.join(" AND ")), values)
+        }
+    }
+
+    /// A description of the active filters for the listing header, or `None`
+    /// when every file is listed. Fails if the filters contradict each other.
+    pub fn describe(&self) -> Result<Option<String>> {
+        if let (Some(since), Some(before)) = (self.since, self.before) {
+            if since > before {
+                bail!("--since ({since}) is after --before ({before})");
+            }
+        }
+        self.size.validate()?;
+        let mut parts = Vec::new();
+        if let Some(mime_type) = &self.mime_type {
+            parts.push(format!("with MIME type {mime_type}"));
+        }
+        if let Some(since) = self.since {
+            parts.push(format!("added since {}", since.to_rfc3339()));
+        }
+        if let Some(before) = self.before {
+            parts.push(format!("added before {}", before.to_rfc3339()));
+        }
+        if let Some(min) = self.size.size_min {
+            parts.push(format!("of at least {}", format_size(min)));
+        }
+        if let Some(max) = self.size.size_max {
+            parts.push(format!("of at most {}", format_size(max)));
+        }
+        if self.include_deleted {
+            parts.push("including soft-deleted".to_string());
+        }
+        Ok((!parts.is_empty()).then(|| parts.join(", ")))
+    }
+}
+
+/// How `list` orders and pages its rows
+#[derive(clap::Args, Debug, Default)]
+pub struct ListPage {
+    /// Column to sort by
+    #[arg(long, value_enum, default_value_t = SortKey::Id)]
+    sort: SortKey,
+    /// Sort direction
+    #[arg(long, value_enum, default_value_t = SortOrder::Asc)]
+    order: SortOrder,
+    /// Show at most this many files
+    #[arg(long)]
+    limit: Option<u32>,
+    /// Skip this many files before showing any
+    #[arg(long, default_value_t = 0)]
+    offset: u32,
+}
+
+impl ListPage {
+    /// Whether no sort, order or paging flag was given
+    pub fn is_default(&self) -> bool {
+        self.sort == SortKey::Id
+            && self.order == SortOrder::Asc
+            && self.limit.is_none()
+            && self.offset == 0
+    }
+
+    /// The ORDER BY / LIMIT tail of the query. Only fixed column names reach
+    /// the SQL; ties fall back to `id` so pages don't overlap.
+    fn sql(&self) -> String {
+        let direction = self.order.sql();
+        let mut sql = match self.sort {
+            SortKey::Id => format!("ORDER BY id {direction}"),
+            key => format!("ORDER BY {} {direction}, id", key.column()),
+        };
+        if self.limit.is_some() || self.offset > 0 {
+            // SQLite needs a LIMIT before OFFSET; -1 means no limit
+            let limit = self.limit.map_or(-1, i64::from);
+            sql.push_str(&format!(" LIMIT {limit} OFFSET {}", self.offset));
+        }
+        sql
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum SortKey {
+    #[default]
+    Id,
+    Name,
+    Size,
+    CreatedAt,
+    ChunkSize,
+}
+
+impl SortKey {
+    fn column(self) -> &'static str {
+        match self {
+            SortKey::Id => "id",
+            SortKey::Name => "filename",
+            SortKey::Size => "filesize",
+            SortKey::CreatedAt => "created_at",
+            SortKey::ChunkSize => "chunk_size",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// e.g. "Showing 1–50 of 342 files with MIME type video/*, sorted by size DESC"
+pub fn list_header(description: Option<&str>, page: &ListPage, shown: usize, total: i64) -> String {
+    let range = if shown == 0 {
+        "0".to_string()
+    } else {
+        let start = u64::from(page.offset) + 1;
+        format!("{}\u{2013}{}", start, start + shown as u64 - 1)
+    };
+    let mut header = format!("Showing {} of {} files", range, total);
+    if let Some(description) = description {
+        header.push(' ');
+        header.push_str(description);
+    }
+    let key = page.sort.to_possible_value().expect("no skipped variants");
+    header.push_str(&format!(
+        ", sorted by {} {}",
+        key.get_name(),
+        page.order.sql()
+    ));
+    header
+}
+
+/// Parse an RFC 3339 timestamp, or a bare date taken as midnight UTC.
+fn parse_date(s: &str) -> std::result::Result<DateTime<Utc>, String> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc())
+        .map_err(|_| format!("expected an RFC 3339 timestamp or YYYY-MM-DD, got '{s}'"))
+}
+
+/// Files matching `filter`, sorted and paged by `page`
+pub fn list_files(
+    conn: &mut Connection,
+    filter: &ListFilter,
+    page: &ListPage,
+) -> Result<Vec<FileRow>> {
+    filter.describe()?;
+    let (where_clause, values) = filter.sql();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM files {} {}",
+        FileRow::COLUMNS,
+        where_clause,
+        page.sql()
+    ))?;
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(values), FileRow::from_row)?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Fill in the directory name of each file for `list --with-dir`.
+pub fn with_directories(conn: &Connection, files: &mut [FileRow]) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT d.name FROM files f LEFT JOIN directories d ON d.id = f.directory_id
+         WHERE f.id = ?1",
+    )?;
+    for file in files {
+        let name: Option<String> = stmt.query_row(params![file.id], |row| row.get(0))?;
+        file.directory = Some(name.unwrap_or_else(|| "/".to_string()));
+    }
+    Ok(())
+}
+
+/// How many files match `filter`, ignoring any paging.
+pub fn count_files(conn: &Connection, filter: &ListFilter) -> Result<i64> {
+    let (where_clause, values) = filter.sql();
+    let count = conn.query_row(
+        &format!("SELECT COUNT(*) FROM files {}", where_clause),
+        rusqlite::params_from_iter(values),
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
+fn validate_tag(tag: &str) -> Result<()> {
+    if tag.trim().is_empty() {
+        bail!("tag must not be empty");
+    }
+    Ok(())
+}
+
+/// Adds `tags` to a file, creating tags that don't exist yet
+pub fn tag_file(conn: &mut Connection, file_id: i64, tags: &[String]) -> Result<()> {
+    let tx = conn.transaction()?;
+    let exists: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM files WHERE id = ?1)",
+        params![file_id],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        bail!("no file with id {file_id}");
+    }
+    for tag in tags {
+        validate_tag(tag)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO tags (name) VALUES (?1)",
+            params![tag],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO file_tags (file_id, tag_id)
+             SELECT ?1, id FROM tags WHERE name = ?2",
+            params![file_id, tag],
+        )?;
+    }
+    audit(
+        &tx,
+        "tag",
+        "file",
+        file_id,
+        &format!("tagged {}", tags.join(", ")),
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Remove `tags` from a file, returning how many it actually had.
+pub fn untag_file(conn: &Connection, file_id: i64, tags: &[String]) -> Result<usize> {
+    let placeholders = vec!["?"; tags.len()].join(", ");
+    let removed = conn.execute(
+        &format!(
+            "DELETE FROM file_tags WHERE file_id = ?
+             AND tag_id IN (SELECT id FROM tags WHERE name IN ({placeholders}))"
+        ),
+        rusqlite::params_from_iter(
+            std::iter::once(&file_id as &dyn rusqlite::ToSql)
+                .chain(tags.iter().map(|t| t as &dyn rusqlite::ToSql)),
+        ),
+    )?;
+    if removed > 0 {
+        audit(
+            conn,
+            "untag",
+            "file",
+            file_id,
+            &format!("untagged {}", tags.join(", ")),
+        )?;
+    }
+    Ok(removed)
+}
+
+/// Chunks of a file in order
+pub fn list_chunks(conn: &Connection, file_id: i64) -> Result<Vec<ChunkRow>> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM files WHERE id = ?1)",
+        params![file_id],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        bail!("no file with id {file_id}");
+    }
+    let mut stmt = conn.prepare(
+        "SELECT idx, message_id, url, sha256 FROM file_chunks WHERE file_id = ?1 ORDER BY idx",
+    )?;
+    let rows = stmt
+        .query_map(params![file_id], |row| {
+            Ok(ChunkRow {
+                idx: row.get(0)?,
+                message_id: row.get(1)?,
+                url: row.get(2)?,
+                sha256: row.get(3)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// What `export-manifest` writes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ManifestFormat {
+    /// An array of `{idx, url, sha256}` objects
+    Json,
+    /// `idx,url,sha256` with a header row
+    Csv,
+    /// An HLS playlist with one segment per chunk
+    M3u8,
+}
+
+/// One chunk in an `export-manifest` listing
+#[derive(Serialize)]
+struct ManifestEntry {
+    idx: i64,
+    url: String,
+    sha256: String,
+}
+
+/// Write the stored URL of each chunk of a file to `out` without downloading
+/// anything. The URLs point at chunks as uploaded, so compressed or encrypted
+/// files still need `export` to decode them. Returns how many chunks were listed.
+pub fn export_manifest(
+    conn: &Connection,
+    file_id: i64,
+    out: &Path,
+    format: ManifestFormat,
+) -> Result<usize> {
+    let entries: Vec<ManifestEntry> = list_chunks(conn, file_id)?
+        .into_iter()
+        .map(|chunk| ManifestEntry {
+            idx: chunk.idx,
+            url: chunk.url,
+            sha256: chunk.sha256,
+        })
+        .collect();
+    let file =
+        fs::File::create(out).with_context(|| format!("failed to create {}", out.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    match format {
+        ManifestFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &entries)?;
+            writeln!(writer)?;
+        }
+        ManifestFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(&mut writer);
+            for entry in &entries {
+                csv.serialize(entry)?;
+            }
+            csv.flush()?;
+        }
+        ManifestFormat::M3u8 => {
+            // Chunks are cut by size, not time, so every segment gets the same
+            // nominal duration
+            writeln!(writer, "#EXTM3U")?;
+            writeln!(writer, "#EXT-X-VERSION:3")?;
+            writeln!(writer, "#EXT-X-TARGETDURATION:10")?;
+            writeln!(writer, "#EXT-X-MEDIA-SEQUENCE:0")?;
+            for entry in &entries {
+                writeln!(writer, "#EXTINF:10.0,")?;
+                writeln!(writer, "{}", entry.url)?;
+            }
+            writeln!(writer, "#EXT-X-ENDLIST")?;
+        }
+    }
+    writer.flush()?;
+    Ok(entries.len())
+}
+
+/// Every tag with the number of files carrying it
+pub fn list_tags(conn: &Connection) -> Result<Vec<TagRow>> {
+    let mut stmt = conn.prepare(
+        "SELECT t.name, COUNT(ft.file_id) FROM tags t
+         LEFT JOIN file_tags ft ON ft.tag_id = t.id
+         GROUP BY t.id ORDER BY t.name",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(TagRow {
+                name: row.get(0)?,
+                files: row.get(1)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Files carrying any of `tags`, or every one of them with `all`.
+pub fn search_by_tag(conn: &Connection, tags: &[String], all: bool) -> Result<Vec<FileRow>> {
+    let tags: Vec<&String> = tags
+        .iter()
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let needed = if all { tags.len() } else { 1 };
+    let placeholders = vec!["?"; tags.len()].join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM files WHERE id IN (
+             SELECT ft.file_id FROM file_tags ft JOIN tags t ON t.id = ft.tag_id
+             WHERE t.name IN ({placeholders})
+             GROUP BY ft.file_id HAVING COUNT(*) >= {needed}
+         )
+         ORDER BY id",
+        FileRow::COLUMNS
+    ))?;
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(&tags), FileRow::from_row)?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Files whose name matches `pattern`, as a glob or (with `regex`) a regular
+/// expression that must match the whole name. With `dir_id`, only files in that
+/// directory or any directory below it are considered.
+pub fn search_files(
+    conn: &Connection,
+    pattern: &str,
+    regex: bool,
+    dir_id: Option<i64>,
+    mime_type: Option<&str>,
+    size: &SizeRange,
+) -> Result<Vec<FileRow>> {
+    size.validate()?;
+    let matcher: Box<dyn Fn(&str) -> bool> = if regex {
+        let re = regex::Regex::new(&format!("^(?:{pattern})$"))
+            .with_context(|| format!("invalid regex: {pattern}"))?;
+        Box::new(move |name| re.is_match(name))
+    } else {
+        let glob =
+            glob::Pattern::new(pattern).with_context(|| format!("invalid glob: {pattern}"))?;
+        Box::new(move |name| glob.matches(name))
+    };
+
+    let rows = match dir_id {
+        Some(dir_id) => {
+            let mut stmt = conn.prepare(&format!(
+                "WITH RECURSIVE subtree(id) AS (
+                    SELECT ?1
+                    UNION
+                    SELECT d.id FROM directories d JOIN subtree s ON d.parent_id = s.id
+                 )
+                 SELECT {} FROM files
+                 WHERE directory_id IN (SELECT id FROM subtree)
+                   AND deleted_at IS NULL
+                   AND (?2 IS NULL OR mime_type GLOB ?2)
+                   AND (?3 IS NULL OR filesize >= ?3)
+                   AND (?4 IS NULL OR filesize <= ?4)
+                 ORDER BY id",
+                FileRow::COLUMNS
+            ))?;
+            let rows = stmt
+                .query_map(
+                    params![dir_id, mime_type, size.size_min, size.size_max],
+                    FileRow::from_row,
+                )?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            rows
+        }
+        None => {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM files
+                 WHERE deleted_at IS NULL
+                   AND (?1 IS NULL OR mime_type GLOB ?1)
+                   AND (?2 IS NULL OR filesize >= ?2)
+                   AND (?3 IS NULL OR filesize <= ?3)
+                 ORDER BY id",
+                FileRow::COLUMNS
+            ))?;
+            let rows = stmt
+                .query_map(
+                    params![mime_type, size.size_min, size.size_max],
+                    FileRow::from_row,
+                )?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            rows
+        }
+    };
+
+    Ok(rows.into_iter().filter(|f| matcher(&f.filename)).collect())
+}
+
+/// Downloads a file's chunks and reassembles them into `out_path`, or stdout
+#[allow(clippy::too_many_arguments)]
+pub async fn export_file(
+    conn: &mut Connection,
+    file_id: i64,
+    proxy_base: &str,
+    out_path: Option<PathBuf>,
+    check: ChunkCheck,
+    retries: u32,
+    output_format: OutputFormat,
+    transfer: &TransferOptions,
+    cipher: Option<ChunkCipher>,
+    integrity: bool,
+) -> Result<FileIntegrity> {
+    let algo = file_hash_algo(conn, file_id)?;
+    // only a reassembled file can be compared with the original's hash
+    let mut file_hash = (integrity && output_format == OutputFormat::File).then(|| algo.hasher());
+
+    // prepare output writer; `None` means stdout
+    let mut out_writer = output_format.writer(out_path.as_deref())?;
+
+    let res = stream_chunks(
+        conn,
+        file_id,
+        proxy_base,
+        out_writer.as_mut(),
+        check,
+        retries,
+        transfer,
+        cipher.as_ref(),
+        file_hash.as_mut(),
+    )
+    .await;
+    let res = res.and_then(|mismatched| out_writer.finish().map(|()| mismatched));
+
+    let mismatched = match res {
+        Ok(mismatched) => mismatched,
+        Err(e) => {
+            // don't leave a partial or corrupt reconstruction behind; the
+            // chunks already in a directory are complete files of their own
+            drop(out_writer);
+            if let (Some(path), false) = (&out_path, output_format == OutputFormat::Dir) {
+                fs::remove_file(path)?;
+            }
+            return Err(e);
+        }
+    };
+
+    if !mismatched.is_empty() {
+        let indices: Vec<String> = mismatched.iter().map(|idx| idx.to_string()).collect();
+        bail!(
+            "{} chunk(s) failed the integrity check and were left out of the output: {}",
+            mismatched.len(),
+            indices.join(", ")
+        );
+    }
+    match file_hash {
+        Some(hasher) => check_file_hash(conn, file_id, hasher),
+        None => Ok(FileIntegrity::NotChecked),
+    }
+}
+
+/// Compare what was exported, hashed into `hasher`, with the hash recorded at ingest.
+fn check_file_hash(conn: &Connection, file_id: i64, hasher: Hasher) -> Result<FileIntegrity> {
+    let stored: Option<String> = conn.query_row(
+        "SELECT sha256 FROM files WHERE id = ?1",
+        params![file_id],
+        |row| row.get(0),
+    )?;
+    let Some(stored) = stored else {
+        return Ok(FileIntegrity::NoStoredHash);
+    };
+    let calc = hasher.finalize();
+    if calc != stored {
+        bail!(
+            "File integrity: MISMATCH (stored {} {stored}, exported {calc})",
+            file_hash_algo(conn, file_id)?.label()
+        );
+    }
+    Ok(FileIntegrity::Ok)
+}
+
+/// Download every chunk of a file and hand them to `out_writer` in index
+/// order, also feeding `file_hash` if given. Keeps `concurrency` downloads in
+/// flight, so memory stays proportional to a few chunks. Returns the chunks
+/// left out by `ChunkCheck::SkipMismatched`.
+#[allow(clippy::too_many_arguments)]
+async fn stream_chunks(
+    conn: &Connection,
+    file_id: i64,
+    proxy_base: &str,
+    out_writer: &mut dyn ChunkWriter,
+    check: ChunkCheck,
+    retries: u32,
+    transfer: &TransferOptions,
+    cipher: Option<&ChunkCipher>,
+    mut file_hash: Option<&mut Hasher>,
+) -> Result<Vec<i64>> {
+    let filesize: i64 = conn
+        .query_row(
+            "SELECT filesize FROM files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    let algo = file_hash_algo(conn, file_id)?;
+    let chunks = load_chunks(conn, file_id)?;
+
+    let pb = progress_bar(
+        filesize as u64,
+        "{bytes}/{total_bytes} downloaded {wide_bar} {elapsed_precise} ETA {eta} ({bytes_per_sec})",
+        &transfer.progress,
+    );
+
+    // Keep a window of `concurrency` downloads in flight and write them out in index order
+    let client = transfer.pacing.client()?;
+    let mut chunks = chunks.into_iter();
+    let mut in_flight = VecDeque::new();
+    let mut mismatched = Vec::new();
+    let res = async {
+        loop {
+            while in_flight.len() < transfer.concurrency {
+                let Some(chunk) = chunks.next() else {
+                    break;
+                };
+                let client = client.clone();
+                let proxy_base = proxy_base.to_string();
+                let url = chunk.url.clone();
+                let idx = chunk.idx;
+                let handle = tokio::spawn(async move {
+                    download_chunk_with_retry(&client, &proxy_base, &url, idx, retries).await
+                });
+                in_flight.push_back((chunk, handle));
+            }
+            let Some((chunk, handle)) = in_flight.pop_front() else {
+                break;
+            };
+
+            let data = decode_chunk(&handle.await??, &chunk, cipher)?;
+            pb.inc(data.len() as u64);
+            if check != ChunkCheck::Off {
+                let idx = chunk.idx;
+                let stored = &chunk.sha256;
+                if stored.is_empty() {
+                    warn!(chunk = idx, "no stored hash, cannot verify");
+                } else {
+                    let calc = algo.digest(&data);
+                    if &calc != stored {
+                        error!(chunk = idx, stored, calc, "hash mismatch");
+                        if check == ChunkCheck::AbortOnMismatch {
+                            bail!("export aborted: chunk {idx} failed verification");
+                        }
+                        mismatched.push(idx);
+                        continue;
+                    }
+                }
+            }
+            out_writer.write_chunk(chunk.idx, &data, &chunk.sha256)?;
+            if let Some(hasher) = &mut file_hash {
+                hasher.update(&data);
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+        for (_, handle) in in_flight {
+            handle.abort();
+        }
+        return Err(e);
+    }
+    pb.finish();
+    Ok(mismatched)
+}
+
+/// Outcome of comparing an exported file with the hash recorded at ingest;
+/// a mismatch is an error instead
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileIntegrity {
+    /// The output hashes to the stored value
+    Ok,
+    /// Ingested before whole-file hashes were recorded
+    NoStoredHash,
+    /// `--no-integrity`, or the output isn't the reassembled file
+    NotChecked,
+}
+
+impl FileIntegrity {
+    /// The line `export` prints, if any.
+    pub fn message(&self) -> Option<&'static str> {
+        match self {
+            FileIntegrity::Ok => Some("File integrity: OK"),
+            FileIntegrity::NoStoredHash => Some("File integrity: unknown (no stored hash)"),
+            FileIntegrity::NotChecked => None,
+        }
+    }
+}
+
+/// `preview`: the first `lines` lines of `file_id`, from its first chunk
+/// alone, decoded as UTF-8 with invalid bytes replaced. Files that look
+/// binary, by their MIME type or a NUL byte in the first 512 bytes, are
+/// refused unless `force` is set.
+pub async fn preview_file(
+    conn: &Connection,
+    file_id: i64,
+    proxy_base: &str,
+    lines: usize,
+    force: bool,
+    pacing: &UploadConfig,
+    cipher: Option<&ChunkCipher>,
+) -> Result<String> {
+    let mime_type: Option<String> = conn
+        .query_row(
+            "SELECT mime_type FROM files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )
+        .optional()?
+        .with_context(|| format!("no file with id {file_id}"))?;
+    let chunk = load_chunks(conn, file_id)?
+        .into_iter()
+        .find(|chunk| chunk.idx == 0)
+        .with_context(|| format!("file_id={file_id} has no chunk 0; run `resume` first"))?;
+    if chunk.encrypted && cipher.is_none() {
+        bail!("file_id={file_id} is encrypted; pass --key-file");
+    }
+
+    let client = pacing.client()?;
+    let raw = download_chunk_with_retry(
+        &client,
+        proxy_base,
+        &chunk.url,
+        chunk.idx,
+        DEFAULT_DOWNLOAD_RETRIES,
+    )
+    .await?;
+    let data = decode_chunk(&raw, &chunk, cipher)?;
+    if let (false, Some(why)) = (force, binary_evidence(mime_type.as_deref(), &data)) {
+        bail!(
+            "file_id={file_id} looks like a binary file ({why}); pass --force to preview it anyway"
+        );
+    }
+    let text = String::from_utf8_lossy(&data);
+    Ok(text.lines().take(lines).collect::<Vec<_>>().join("\n"))
+}
+
+/// Why a file looks binary, if it does: a MIME type `ingest` detected that
+/// isn't text, or else a NUL byte within the first 512 bytes.
+fn binary_evidence(mime_type: Option<&str>, head: &[u8]) -> Option<String> {
+    match mime_type {
+        // `infer` only recognises a few text formats and calls plain text
+        // application/octet-stream, so that says nothing either way
+        Some(mime) if mime != "application/octet-stream" => {
+            let text = mime.starts_with("text/")
+                || matches!(
+                    mime,
+                    "application/json" | "application/xml" | "application/x-sh"
+                );
+            (!text).then(|| format!("MIME type {mime}"))
+        }
+        _ => head
+            .iter()
+            .take(512)
+            .any(|&b| b == 0)
+            .then(|| "NUL bytes in its first 512 bytes".to_string()),
+    }
+}
+
+/// Download only the chunks of `file_id` that overlap `range` and write the
+/// bytes in it to `out_path`, trimming the first and last chunk.
+#[allow(clippy::too_many_arguments)]
+pub async fn export_range(
+    conn: &Connection,
+    file_id: i64,
+    range: std::ops::Range<u64>,
+    out_path: &Path,
+    proxy_base: &str,
+    retries: u32,
+    transfer: &TransferOptions,
+    cipher: Option<ChunkCipher>,
+) -> Result<()> {
+    let (filesize, chunk_size): (i64, i64) = conn
+        .query_row(
+            "SELECT filesize, chunk_size FROM files WHERE id = ?1",
+            params![file_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    let (filesize, chunk_size) = (filesize as u64, chunk_size as u64);
+    if range.start >= range.end {
+        bail!(
+            "--start ({}) must be less than --end ({})",
+            range.start,
+            range.end
+        );
+    }
+    if range.end > filesize {
+        bail!(
+            "--end ({}) is past the end of file_id={} ({} bytes)",
+            range.end,
+            file_id,
+            filesize
+        );
+    }
+
+    let first = (range.start / chunk_size) as i64;
+    let last = ((range.end - 1) / chunk_size) as i64;
+    let chunks: Vec<StoredChunk> = load_chunks(conn, file_id)?
+        .into_iter()
+        .filter(|chunk| (first..=last).contains(&chunk.idx))
+        .collect();
+    if chunks.len() as i64 != last - first + 1 {
+        bail!("file_id={file_id} is missing some of chunks {first}..={last}; run `resume` first");
+    }
+
+    let pb = progress_bar(
+        range.end - range.start,
+        "{bytes}/{total_bytes} downloaded {wide_bar} {elapsed_precise} ETA {eta} ({bytes_per_sec})",
+        &transfer.progress,
+    );
+    let mut out = std::io::BufWriter::new(
+        File::create(out_path).with_context(|| format!("creating {}", out_path.display()))?,
+    );
+    let client = transfer.pacing.client()?;
+    let mut chunks = chunks.into_iter();
+    let mut in_flight = VecDeque::new();
+    let mut written = 0;
+    let res = async {
+        loop {
+            while in_flight.len() < transfer.concurrency {
+                let Some(chunk) = chunks.next() else {
+                    break;
+                };
+                let client = client.clone();
+                let proxy_base = proxy_base.to_string();
+                let url = chunk.url.clone();
+                let idx = chunk.idx;
+                let handle = tokio::spawn(async move {
+                    download_chunk_with_retry(&client, &proxy_base, &url, idx, retries).await
+                });
+                in_flight.push_back((chunk, handle));
+            }
+            let Some((chunk, handle)) = in_flight.pop_front() else {
+                break;
+            };
+
+            let data = decode_chunk(&handle.await??, &chunk, cipher.as_ref())?;
+            // where this chunk's bytes sit in the file, clipped to the range
+            let offset = chunk.idx as u64 * chunk_size;
+            let from = (range.start.max(offset) - offset).min(data.len() as u64);
+            let to = (range.end.min(offset + data.len() as u64) - offset).max(from);
+            let piece = &data[from as usize..to as usize];
+            out.write_all(piece)?;
+            pb.inc(piece.len() as u64);
+            written += piece.len() as u64;
+        }
+        if written != range.end - range.start {
+            bail!(
+                "chunks of file_id={file_id} held only {written} of the {} requested bytes",
+                range.end - range.start
+            );
+        }
+        out.flush()?;
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Err(e) = res {
+        drop(out);
+        for (_, handle) in in_flight {
+            handle.abort();
+        }
+        fs::remove_file(out_path)?;
+        return Err(e);
+    }
+    pb.finish();
+    Ok(())
+}
+
+/// Where `export --use-original-path` writes `file_id` under `dir`: the path
+/// it was ingested from, less any root, prefix or `..` components.
+pub fn original_export_path(conn: &Connection, file_id: i64, dir: &Path) -> Result<PathBuf> {
+    let original: Option<String> = conn
+        .query_row(
+            "SELECT original_path FROM files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    let original =
+        original.with_context(|| format!("file_id={file_id} has no recorded original path"))?;
+    let relative: PathBuf = Path::new(&original)
+        .components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect();
+    if relative.as_os_str().is_empty() {
+        bail!("file_id={file_id} has an unusable original path: {original}");
+    }
+    Ok(dir.join(relative))
+}
+
+/// How `export` checks downloaded chunks against their stored SHA-256
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkCheck {
+    /// Chunks aren't checked
+    Off,
+    /// `--verify`: stop at the first bad chunk and remove the output
+    AbortOnMismatch,
+    /// `--integrity-check`: leave bad chunks out and report them all at the end
+    SkipMismatched,
+}
+
+impl ChunkCheck {
+    /// `AbortOnMismatch` for `--verify`, otherwise `Off`
+    pub fn from_verify(verify: bool) -> Self {
+        if verify {
+            ChunkCheck::AbortOnMismatch
+        } else {
+            ChunkCheck::Off
+        }
+    }
+}
+
+/// What `export` writes its chunks into
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The reassembled file
+    File,
+    /// A SQLite database with a `chunks(idx, data, sha256)` table
+    Sqlite,
+    /// A tar archive of `{idx}.chunk` entries
+    Tar,
+    /// A directory with one `{idx}.chunk` file per chunk, created if missing
+    Dir,
+}
+
+impl OutputFormat {
+    /// A writer for `path`, or for stdout when `path` is `None`.
+    fn writer(self, path: Option<&Path>) -> Result<Box<dyn ChunkWriter>> {
+        let out = || -> Result<Box<dyn Write>> {
+            Ok(match path {
+                Some(path) => Box::new(std::io::BufWriter::new(File::create(path)?)),
+                None => Box::new(std::io::stdout()),
+            })
+        };
+        Ok(match self {
+            OutputFormat::File => Box::new(FileChunkWriter(out()?)),
+            OutputFormat::Tar => Box::new(TarChunkWriter(tar::Builder::new(out()?))),
+            OutputFormat::Sqlite => {
+                let path = path.context("--output-format sqlite can't write to stdout")?;
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                let conn = Connection::open(path)?;
+                conn.execute_batch(
+                    "CREATE TABLE chunks (idx INTEGER PRIMARY KEY, data BLOB NOT NULL, sha256 TEXT);
+                     BEGIN;",
+                )?;
+                Box::new(SqliteChunkWriter(conn))
+            }
+            OutputFormat::Dir => {
+                let dir = path.context("--out-dir can't write to stdout")?;
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {}", dir.display()))?;
+                Box::new(DirChunkWriter(dir.to_path_buf()))
+            }
+        })
+    }
+}
+
+/// Destination for decoded chunks, handed over in index order.
+trait ChunkWriter {
+    fn write_chunk(&mut self, idx: i64, data: &[u8], sha256: &str) -> Result<()>;
+    /// Flush everything written so far; called once after the last chunk.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Chunks concatenated into the current entry of a zip archive
+struct ZipChunkWriter<'a, W: Write + std::io::Seek>(&'a mut zip::ZipWriter<W>);
+
+impl<W: Write + std::io::Seek> ChunkWriter for ZipChunkWriter<'_, W> {
+    fn write_chunk(&mut self, _idx: i64, data: &[u8], _sha256: &str) -> Result<()> {
+        Ok(self.0.write_all(data)?)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Chunks concatenated back into the original file
+struct FileChunkWriter(Box<dyn Write>);
+
+impl ChunkWriter for FileChunkWriter {
+    fn write_chunk(&mut self, _idx: i64, data: &[u8], _sha256: &str) -> Result<()> {
+        Ok(self.0.write_all(data)?)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.0.flush()?)
+    }
+}
+
+/// One BLOB row per chunk
+struct SqliteChunkWriter(Connection);
+
+impl ChunkWriter for SqliteChunkWriter {
+    fn write_chunk(&mut self, idx: i64, data: &[u8], sha256: &str) -> Result<()> {
+        let sha256 = (!sha256.is_empty()).then_some(sha256);
+        self.0.execute(
+            "INSERT INTO chunks (idx, data, sha256) VALUES (?1, ?2, ?3)",
+            params![idx, data, sha256],
+        )?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.0.execute_batch("COMMIT")?)
+    }
+}
+
+/// One `{idx}.chunk` tar entry per chunk
+struct TarChunkWriter(tar::Builder<Box<dyn Write>>);
+
+impl ChunkWriter for TarChunkWriter {
+    fn write_chunk(&mut self, idx: i64, data: &[u8], _sha256: &str) -> Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        header.set_cksum();
+        self.0
+            .append_data(&mut header, format!("{idx}.chunk"), data)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.0.finish()?;
+        Ok(self.0.get_mut().flush()?)
+    }
+}
+
+/// One `{idx}.chunk` file per chunk
+struct DirChunkWriter(PathBuf);
+
+impl ChunkWriter for DirChunkWriter {
+    fn write_chunk(&mut self, idx: i64, data: &[u8], _sha256: &str) -> Result<()> {
+        let path = self.0.join(format!("{idx}.chunk"));
+        fs::write(&path, data).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// `filename`, or `{stem}_{file_id}.{ext}` if `used` already has it.
+fn unique_name(used: &mut HashSet<String>, filename: &str, file_id: i64) -> String {
+    let mut name = filename.to_string();
+    if !used.insert(name.clone()) {
+        let path = Path::new(filename);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        name = match path.extension() {
+            Some(ext) => format!("{}_{}.{}", stem, file_id, ext.to_string_lossy()),
+            None => format!("{}_{}", stem, file_id),
+        };
+        used.insert(name.clone());
+    }
+    name
+}
+
+/// Stream files from Discord into a zip archive at `out`, one entry per file
+/// named by its filename (repeats get `_{file_id}` appended). Chunks go
+/// straight into the archive, so memory stays proportional to a chunk rather
+/// than a file. Returns how many files were added.
+#[allow(clippy::too_many_arguments)]
+pub async fn zip_files(
+    conn: &mut Connection,
+    file_ids: &[i64],
+    out: &Path,
+    proxy_base: &str,
+    verify: bool,
+    transfer: &TransferOptions,
+    cipher: Option<ChunkCipher>,
+) -> Result<usize> {
+    // catch missing and unfinished files before writing anything
+    let mut files = Vec::new();
+    for &file_id in file_ids {
+        let (filename, filesize, status): (String, i64, String) = conn
+            .query_row(
+                "SELECT filename, filesize, status FROM files WHERE id = ?1",
+                params![file_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .with_context(|| format!("no file with id {file_id}"))?;
+        if status != "complete" {
+            bail!("file_id={file_id} is {status}; run `resume` first");
+        }
+        files.push((file_id, filename, filesize));
+    }
+
+    let file = File::create(out).with_context(|| format!("failed to create {}", out.display()))?;
+    let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+    let res = async {
+        let mut used = HashSet::new();
+        for (file_id, filename, filesize) in &files {
+            let name = unique_name(&mut used, filename, *file_id);
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .large_file(*filesize >= u32::MAX as i64);
+            zip.start_file(name, options)?;
+            let mut hasher = file_hash_algo(conn, *file_id)?.hasher();
+            // `--verify` aborts on the first bad chunk, so none are ever skipped
+            stream_chunks(
+                conn,
+                *file_id,
+                proxy_base,
+                &mut ZipChunkWriter(&mut zip),
+                ChunkCheck::from_verify(verify),
+                DEFAULT_DOWNLOAD_RETRIES,
+                transfer,
+                cipher.as_ref(),
+                Some(&mut hasher),
+            )
+            .await
+            .with_context(|| format!("adding file_id={file_id} '{filename}'"))?;
+            check_file_hash(conn, *file_id, hasher)
+                .with_context(|| format!("adding file_id={file_id} '{filename}'"))?;
+        }
+        zip.finish()?.flush()?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+        // a truncated archive is no use to anyone
+        fs::remove_file(out)?;
+        return Err(e);
+    }
+    Ok(files.len())
+}
+
+/// Export the files of a directory (and with `recursive`, its subdirectories)
+/// into `out_dir`, returning how many were written. A name used by more than
+/// one file gets `_{file_id}` appended to all but the first.
+#[allow(clippy::too_many_arguments)]
+pub async fn export_directory(
+    conn: &mut Connection,
+    dir_id: i64,
+    out_dir: &Path,
+    recursive: bool,
+    proxy_base: &str,
+    verify: bool,
+    transfer: &TransferOptions,
+    cipher: Option<ChunkCipher>,
+) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "WITH RECURSIVE subtree(id, depth) AS (
+            SELECT ?1, 0
+            UNION
+            SELECT d.id, s.depth + 1 FROM directories d JOIN subtree s ON d.parent_id = s.id
+            WHERE ?2
+         )
+         SELECT id, filename, status FROM files
+         WHERE directory_id IN (SELECT id FROM subtree) ORDER BY id",
+    )?;
+    let files = stmt
+        .query_map(params![dir_id, recursive], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    drop(stmt);
+
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("failed to create {}", out_dir.display()))?;
+
+    let overall = progress_bar(
+        files.len() as u64,
+        "[file {pos} / {len}] {wide_bar} {elapsed_precise} {msg}",
+        &transfer.progress,
+    );
+    let mut used = HashSet::new();
+    let mut failures = Vec::new();
+    let mut exported = 0;
+    for (file_id, filename, status) in &files {
+        overall.set_message(filename.clone());
+        let name = unique_name(&mut used, filename, *file_id);
+
+        let res = if status != "complete" {
+            Err(anyhow!("file is {status}; run `resume` first"))
+        } else {
+            export_file(
+                conn,
+                *file_id,
+                proxy_base,
+                Some(out_dir.join(&name)),
+                ChunkCheck::from_verify(verify),
+                DEFAULT_DOWNLOAD_RETRIES,
+                OutputFormat::File,
+                transfer,
+                cipher.clone(),
+                true,
+            )
+            .await
+        };
+        match res {
+            Ok(_) => exported += 1,
+            Err(e) => failures.push((file_id, filename, e)),
+        }
+        overall.inc(1);
+    }
+    overall.finish_with_message("done");
+
+    if !failures.is_empty() {
+        for (file_id, filename, e) in &failures {
+            error!(file_id, filename, "failed to export: {:#}", e);
+        }
+        bail!("exported {} file(s), {} failed", exported, failures.len());
+    }
+    Ok(exported)
+}
+
+/// `download_chunk`, retried up to `retries` times with exponential backoff.
+async fn download_chunk_with_retry(
+    client: &Client,
+    proxy_base: &str,
+    url: &str,
+    idx: i64,
+    retries: u32,
+) -> Result<bytes::Bytes> {
+    let mut attempts = 0;
+    loop {
+        match download_chunk(client, proxy_base, url, idx).await {
+            Ok(data) => return Ok(data),
+            Err(e) if attempts < retries => {
+                attempts += 1;
+                let delay = 2u64.pow(attempts);
+                warn!(
+                    chunk = idx,
+                    attempt = attempts,
+                    retries,
+                    "download failed: {}. Retrying in {}s",
+                    e,
+                    delay
+                );
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "chunk {idx} failed to download after {} attempt(s)",
+                    attempts + 1
+                )))
+            }
+        }
+    }
+}
+
+async fn download_chunk(
+    client: &Client,
+    proxy_base: &str,
+    url: &str,
+    idx: i64,
+) -> Result<bytes::Bytes> {
+    if let Some(path) = url.strip_prefix(LOCAL_URL_PREFIX) {
+        let data = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("chunk {idx}: failed to read {path}"))?;
+        return Ok(data.into());
+    }
+    // wrap original discord cdn url with proxy
+    let proxied_url = format!("{proxy_base}/?{url}");
+
+    debug!(chunk = idx, url = proxied_url, "downloading chunk");
+    let resp = client.get(&proxied_url).send().await?.error_for_status()?;
+    Ok(resp.bytes().await?)
+}
+
+/// Splits a file into chunks, uploads them and records the file; returns its id
+pub async fn ingest_file(
+    conn: &mut Connection,
+    path: &Path,
+    webhooks: &[String],
+    options: &IngestOptions,
+) -> Result<i64> {
+    options.check_overlap()?;
+    let filename = path
+        .file_name()
+        .with_context(|| format!("{} has no file name", path.display()))?
+        .to_string_lossy()
+        .to_string();
+    validate_webhooks(&options.transfer.pacing.client()?, webhooks).await?;
+    let mime_type = detect_mime_type(path).await?;
+    let mut f = tokio::fs::File::open(path).await?;
+    let filesize = f.metadata().await?.len() as i64;
+
+    conn.execute(
+        "INSERT INTO files (filename, filesize, chunk_size, created_at, mime_type, original_path,
+                            compressed, encrypted, chunk_overlap)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            filename,
+            filesize,
+            options.chunk_size as i64,
+            Utc::now().to_rfc3339(),
+            mime_type,
+            path.to_string_lossy(),
+            options.encoding.compress_level.is_some(),
+            options.encoding.cipher.is_some(),
+            options.encoding.overlap as i64,
+        ],
+    )?;
+    let file_id = conn.last_insert_rowid();
+    let started = Instant::now();
+
+    let uploaded = upload_chunks(
+        conn,
+        Some(file_id),
+        &mut f,
+        Some(filesize as u64),
+        options.chunk_size,
+        webhooks,
+        &options.transfer,
+        &options.encoding,
+        &HashSet::new(),
+    )
+    .await?;
+    if !uploaded.failed.is_empty() && !uploaded.cancelled {
+        uploaded
+            .roll_back(conn, file_id, webhooks, &options.transfer.pacing)
+            .await?;
+    }
+    set_ingest_duration(conn, file_id, started.elapsed())?;
+    set_file_hash(conn, file_id, &uploaded.sha256, options.encoding.hash_algo)?;
+    update_file_status(conn, file_id)?;
+    uploaded.check_cancelled(conn, file_id)?;
+    audit(
+        conn,
+        "ingest",
+        "file",
+        file_id,
+        &format!("ingested '{}' ({} bytes)", filename, filesize),
+    )?;
+    uploaded.check_failures(file_id)?;
+
+    Ok(file_id)
+}
+
+/// A line of `ingest --upload-only` output, which `import-urls` reads back.
+/// Only `idx`, `message_id` and `url` are required there; the rest default
+/// to an uncompressed, unencrypted chunk with no stored hash.
+#[derive(Serialize, Deserialize)]
+struct ChunkLine {
+    idx: i64,
+    message_id: String,
+    url: String,
+    #[serde(default)]
+    sha256: String,
+    #[serde(default)]
+    compressed: bool,
+    #[serde(default)]
+    nonce_hex: Option<String>,
+    #[serde(default)]
+    webhook_idx: i64,
+    #[serde(default)]
+    stored_size: Option<i64>,
+    #[serde(default)]
+    overlap: i64,
+}
+
+/// `ingest --upload-only`: split and upload `path` like `ingest_file`, but
+/// record nothing in `files` or `file_chunks`; each uploaded chunk is written
+/// to `out` as a JSON line with its URL and everything needed to decode it,
+/// for `import-urls`. Nothing goes to `storage/` either. Chunks that failed are listed in the error,
+/// after the ones that made it have been written. Returns how many were
+/// uploaded.
+pub async fn upload_only_file(
+    conn: &Connection,
+    path: &Path,
+    webhooks: &[String],
+    options: &IngestOptions,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    options.check_overlap()?;
+    validate_webhooks(&options.transfer.pacing.client()?, webhooks).await?;
+    let mut f = tokio::fs::File::open(path).await?;
+    let filesize = f.metadata().await?.len();
+    let transfer = TransferOptions {
+        local_storage: false,
+        ..options.transfer.clone()
+    };
+    let uploaded = upload_chunks(
+        conn,
+        None,
+        &mut f,
+        Some(filesize),
+        options.chunk_size,
+        webhooks,
+        &transfer,
+        &options.encoding,
+        &HashSet::new(),
+    )
+    .await?;
+    for chunk in &uploaded.chunks {
+        let line = ChunkLine {
+            idx: chunk.idx as i64,
+            message_id: chunk.message_id.clone(),
+            url: chunk.url.clone(),
+            sha256: chunk.sha256.clone(),
+            compressed: options.encoding.compress_level.is_some(),
+            nonce_hex: chunk.nonce_hex.clone(),
+            webhook_idx: chunk.webhook_idx as i64,
+            stored_size: Some(chunk.stored_size as i64),
+            overlap: chunk.overlap as i64,
+        };
+        writeln!(out, "{}", serde_json::to_string(&line)?)?;
+    }
+    out.flush()?;
+    if let Some(e) = &uploaded.stopped {
+        bail!(
+            "upload of {} stopped after {} chunk(s): {e:#}",
+            path.display(),
+            uploaded.chunks.len()
+        );
+    }
+    if !uploaded.failed.is_empty() {
+        let details: Vec<String> = uploaded
+            .failed
+            .iter()
+            .map(|(idx, e)| format!("  chunk {idx}: {e:#}"))
+            .collect();
+        bail!(
+            "{} chunk(s) of {} failed to upload:\n{}",
+            uploaded.failed.len(),
+            path.display(),
+            details.join("\n")
+        );
+    }
+    Ok(uploaded.chunks.len())
+}
+
+/// Ingest whatever arrives on stdin as a file called `name`. Chunks are
+/// uploaded as they fill up and the size is recorded once the stream ends.
+/// If any chunk fails, or the ingest is cancelled, it is rolled back.
+pub async fn ingest_stdin(
+    conn: &mut Connection,
+    name: &str,
+    webhooks: &[String],
+    options: &IngestOptions,
+) -> Result<i64> {
+    validate_name(name)?;
+    options.check_overlap()?;
+    validate_webhooks(&options.transfer.pacing.client()?, webhooks).await?;
+    conn.execute(
+        "INSERT INTO files (filename, filesize, chunk_size, created_at,
+                            compressed, encrypted, chunk_overlap)
+         VALUES (?1, 0, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            name,
+            options.chunk_size as i64,
+            Utc::now().to_rfc3339(),
+            options.encoding.compress_level.is_some(),
+            options.encoding.cipher.is_some(),
+            options.encoding.overlap as i64,
+        ],
+    )?;
+    let file_id = conn.last_insert_rowid();
+    let started = Instant::now();
+
+    let uploaded = upload_chunks(
+        conn,
+        Some(file_id),
+        &mut tokio::io::stdin(),
+        None,
+        options.chunk_size,
+        webhooks,
+        &options.transfer,
+        &options.encoding,
+        &HashSet::new(),
+    )
+    .await?;
+    // stdin can't be read a second time, so `resume` could never finish it
+    if !uploaded.failed.is_empty() || uploaded.stopped.is_some() || uploaded.cancelled {
+        uploaded
+            .roll_back(conn, file_id, webhooks, &options.transfer.pacing)
+            .await?;
+    }
+    conn.execute(
+        "UPDATE files SET filesize = ?1 WHERE id = ?2",
+        params![uploaded.size as i64, file_id],
+    )?;
+    set_ingest_duration(conn, file_id, started.elapsed())?;
+    set_file_hash(conn, file_id, &uploaded.sha256, options.encoding.hash_algo)?;
+    update_file_status(conn, file_id)?;
+    audit(
+        conn,
+        "ingest",
+        "file",
+        file_id,
+        &format!("ingested '{}' ({} bytes) from stdin", name, uploaded.size),
+    )?;
+
+    Ok(file_id)
+}
+
+/// Upload the chunks of an interrupted ingest that never made it into `file_chunks`.
+/// Returns how many chunks were uploaded this time.
+pub async fn resume_ingest(
+    conn: &mut Connection,
+    file_id: i64,
+    path: &Path,
+    webhooks: &[String],
+    transfer: &TransferOptions,
+    cipher: Option<ChunkCipher>,
+) -> Result<usize> {
+    let (filesize, chunk_size, compressed, encrypted, overlap): (
+        i64,
+        i64,
+        Option<bool>,
+        Option<bool>,
+        Option<i64>,
+    ) = conn
+        .query_row(
+            "SELECT filesize, chunk_size, compressed, encrypted, chunk_overlap FROM files
+             WHERE id = ?1",
+            params![file_id],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            },
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    // guessing would risk uploading the rest of an encrypted file in the clear
+    let (Some(compressed), Some(encrypted), Some(overlap)) = (compressed, encrypted, overlap)
+    else {
+        bail!(
+            "file_id={file_id} was ingested before its compression and encryption were recorded \
+             and has no chunks to tell from; ingest it again instead"
+        );
+    };
+
+    let mut f = tokio::fs::File::open(path).await?;
+    let actual = f.metadata().await?.len() as i64;
+    if actual != filesize {
+        bail!(
+            "{} is {} bytes but file_id={} was ingested from {} bytes",
+            path.display(),
+            actual,
+            file_id,
+            filesize
+        );
+    }
+
+    // Carry on with the compression/encryption the file was ingested with
+    let done: HashSet<i64> = load_chunks(conn, file_id)?.iter().map(|c| c.idx).collect();
+    let encoding = ChunkEncoding {
+        compress_level: compressed.then_some(DEFAULT_COMPRESS_LEVEL),
+        cipher: if encrypted {
+            Some(cipher.context("file_id is encrypted; pass --key-file")?)
+        } else {
+            None
+        },
+        hash_algo: file_hash_algo(conn, file_id)?,
+        overlap: overlap as usize,
+    };
+
+    let results = upload_chunks(
+        conn,
+        Some(file_id),
+        &mut f,
+        Some(filesize as u64),
+        chunk_size as usize,
+        webhooks,
+        transfer,
+        &encoding,
+        &done,
+    )
+    .await?;
+    let uploaded = results.chunks.len();
+    set_file_hash(conn, file_id, &results.sha256, encoding.hash_algo)?;
+    update_file_status(conn, file_id)?;
+    results.check_cancelled(conn, file_id)?;
+    audit(
+        conn,
+        "resume",
+        "file",
+        file_id,
+        &format!("uploaded {} missing chunk(s)", uploaded),
+    )?;
+    results.check_failures(file_id)?;
+
+    Ok(uploaded)
+}
+
+fn set_ingest_duration(conn: &Connection, file_id: i64, duration: Duration) -> Result<()> {
+    conn.execute(
+        "UPDATE files SET ingest_duration_ms = ?1 WHERE id = ?2",
+        params![duration.as_millis() as i64, file_id],
+    )?;
+    Ok(())
+}
+
+fn set_file_hash(conn: &Connection, file_id: i64, hash: &str, algo: HashAlgo) -> Result<()> {
+    conn.execute(
+        "UPDATE files SET sha256 = ?1, hash_algo = ?2 WHERE id = ?3",
+        params![hash, algo.as_str(), file_id],
+    )?;
+    Ok(())
+}
+
+/// Sniff the MIME type from the first 512 bytes of `path`.
+async fn detect_mime_type(path: &Path) -> Result<&'static str> {
+    let mut f = tokio::fs::File::open(path).await?;
+    let mut head = [0u8; 512];
+    let n = read_chunk(&mut f, &mut head).await?;
+    Ok(infer::get(&head[..n])
+        .map(|kind| kind.mime_type())
+        .unwrap_or("application/octet-stream"))
+}
+
+/// The `--auto-chunk` size for `path`: about `AUTO_CHUNK_TARGET` chunks, kept
+/// between `AUTO_CHUNK_MIN` and `AUTO_CHUNK_MAX`.
+pub fn auto_chunk_size(path: &Path) -> Result<usize> {
+    let filesize = fs::metadata(path)
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .len();
+    let chunk_size = (filesize / AUTO_CHUNK_TARGET).clamp(AUTO_CHUNK_MIN, AUTO_CHUNK_MAX);
+    // shown at the default level, so the size picked is never a surprise
+    warn!(
+        "Auto chunk size: {} ({} chunk(s))",
+        format_size(chunk_size as i64),
+        filesize.div_ceil(chunk_size).max(1)
+    );
+    if filesize <= chunk_size {
+        warn!("'{}' fits in a single chunk", path.display());
+    }
+    Ok(chunk_size as usize)
+}
+
+/// Print the chunk boundaries and hashes `ingest_file` would upload, without
+/// touching the database, the network or `storage/`.
+pub async fn dry_run_ingest(path: &Path, chunk_size: usize) -> Result<()> {
+    if chunk_size == 0 {
+        bail!("chunk size must be greater than 0");
+    }
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let filesize = f.metadata().await?.len();
+
+    let mut buf = vec![0u8; chunk_size];
+    let mut offset = 0u64;
+    let mut idx = 0;
+    loop {
+        let n = read_chunk(&mut f, &mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let sha256 = hex::encode(Sha256::digest(&buf[..n]));
+        println!(
+            "Chunk {}: bytes {}..{} ({}) sha256={}",
+            idx,
+            offset,
+            offset + n as u64,
+            format_size(n as i64),
+            sha256
+        );
+        offset += n as u64;
+        idx += 1;
+    }
+    println!(
+        "Dry run: '{}' ({}) would be uploaded as {} chunk(s) of up to {}, one Discord message each",
+        path.display(),
+        format_size(filesize as i64),
+        idx,
+        format_size(chunk_size as i64)
+    );
+    Ok(())
+}
+
+/// Read the paths listed in `manifest` (or stdin for `-`), skipping blank
+/// lines and `#` comments.
+fn read_manifest(manifest: &Path) -> Result<Vec<PathBuf>> {
+    let text = if manifest == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        fs::read_to_string(manifest)
+            .with_context(|| format!("failed to read {}", manifest.display()))?
+    };
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Ingest each file from `manifest` in turn so webhook rate limits are shared
+/// sensibly, then print a summary. Failures don't stop the batch but make the
+/// whole command fail at the end.
+pub async fn batch_ingest(
+    conn: &mut Connection,
+    manifest: &Path,
+    dir_id: Option<i64>,
+    webhooks: &[String],
+    options: &IngestOptions,
+) -> Result<()> {
+    let paths = read_manifest(manifest)?;
+    let mut results = Vec::with_capacity(paths.len());
+    for path in paths {
+        let res = async {
+            let file_id = ingest_file(conn, &path, webhooks, options).await?;
+            if dir_id.is_some() {
+                move_file_to_directory(conn, file_id, dir_id)?;
+            }
+            anyhow::Ok(file_id)
+        }
+        .await;
+        results.push((path, res));
+    }
+
+    println!("{:<40} {:>8}  result", "path", "file_id");
+    let mut failed = 0;
+    for (path, res) in &results {
+        match res {
+            Ok(file_id) => println!("{:<40} {:>8}  ok", path.display(), file_id),
+            Err(e) => {
+                failed += 1;
+                println!("{:<40} {:>8}  error: {:#}", path.display(), "-", e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} file(s) failed to ingest", failed, results.len());
+    }
+    Ok(())
+}
+
+/// Ingest the files under `dir` that match `pattern`, returning how many were
+/// ingested. With `create_subdirs`, files land in database directories that
+/// mirror their path relative to `dir`; files directly in `dir` stay at the root.
+pub async fn ingest_dir(
+    conn: &mut Connection,
+    dir: &Path,
+    recursive: bool,
+    pattern: Option<&str>,
+    create_subdirs: bool,
+    webhooks: &[String],
+    options: &IngestOptions,
+) -> Result<usize> {
+    let pattern = pattern
+        .map(|p| glob::Pattern::new(p).with_context(|| format!("invalid glob: {p}")))
+        .transpose()?;
+
+    let mut files = Vec::new();
+    let walker = walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(if recursive { usize::MAX } else { 1 })
+        .sort_by_file_name();
+    for entry in walker {
+        let entry = entry?;
+        if entry.path_is_symlink() {
+            warn!("Skipping symbolic link {}", entry.path().display());
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy();
+        if pattern.as_ref().is_some_and(|p| !p.matches(&name)) {
+            continue;
+        }
+        files.push(entry.into_path());
+    }
+
+    let overall = progress_bar(
+        files.len() as u64,
+        "[file {pos} / {len}] {wide_bar} {elapsed_precise} {msg}",
+        &options.transfer.progress,
+    );
+    let mut dir_ids: HashMap<PathBuf, i64> = HashMap::new();
+    let mut failures = Vec::new();
+    let mut ingested = 0;
+    for path in &files {
+        overall.set_message(path.display().to_string());
+        let res = async {
+            let parent = path.parent().unwrap_or(dir).strip_prefix(dir)?;
+            let dir_id = if create_subdirs {
+                mirror_directory(conn, &mut dir_ids, parent)?
+            } else {
+                None
+            };
+            let file_id = ingest_file(conn, path, webhooks, options).await?;
+            if dir_id.is_some() {
+                move_file_to_directory(conn, file_id, dir_id)?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        match res {
+            Ok(()) => ingested += 1,
+            Err(e) => failures.push((path, e)),
+        }
+        overall.inc(1);
+    }
+    overall.finish_with_message("done");
+
+    if !failures.is_empty() {
+        for (path, e) in &failures {
+            error!("Failed to ingest '{}': {:#}", path.display(), e);
+        }
+        bail!(
+            "{} of {} file(s) failed to ingest",
+            failures.len(),
+            files.len()
+        );
+    }
+    Ok(ingested)
+}
+
+/// The database directory mirroring the relative path `rel`, creating it and
+/// any missing parents. The empty path is the root and maps to `None`.
+fn mirror_directory(
+    conn: &mut Connection,
+    dir_ids: &mut HashMap<PathBuf, i64>,
+    rel: &Path,
+) -> Result<Option<i64>> {
+    let mut parent_id = None;
+    let mut current = PathBuf::new();
+    for component in rel.components() {
+        current.push(component);
+        let id = match dir_ids.get(&current) {
+            Some(id) => *id,
+            None => {
+                let name = component.as_os_str().to_string_lossy();
+                let existing: Option<i64> = conn
+                    .query_row(
+                        "SELECT id FROM directories WHERE name = ?1 AND parent_id IS ?2",
+                        params![name, parent_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let id = match existing {
+                    Some(id) => id,
+                    None => create_directory(conn, &name, parent_id)?,
+                };
+                dir_ids.insert(current.clone(), id);
+                id
+            }
+        };
+        parent_id = Some(id);
+    }
+    Ok(parent_id)
+}
+
+/// What `upload_chunks` read and uploaded
+#[derive(Default)]
+struct UploadedFile {
+    chunks: Vec<UploadedChunk>,
+    /// Chunks that couldn't be uploaded, by index
+    failed: Vec<(usize, anyhow::Error)>,
+    /// Why reading stopped before the end of the input, e.g. a used-up quota
+    stopped: Option<anyhow::Error>,
+    /// Stopped because `cancel` created the sentinel file
+    cancelled: bool,
+    /// Hash of everything read, skipped chunks included, in the encoding's algorithm
+    sha256: String,
+    /// Bytes read
+    size: u64,
+}
+
+/// The sizes of the chunks in `skip` that a `filesize`-byte file actually
+/// has: indices past its end are left out, and the last chunk may be short.
+fn skipped_chunk_sizes(skip: &HashSet<i64>, filesize: u64, chunk_size: usize) -> Vec<u64> {
+    let chunk_size = chunk_size as u64;
+    skip.iter()
+        .filter_map(|&idx| u64::try_from(idx).ok())
+        .filter_map(|idx| filesize.checked_sub(idx.checked_mul(chunk_size)?))
+        .filter(|&left| left > 0)
+        .map(|left| left.min(chunk_size))
+        .collect()
+}
+
+/// Read `f` chunk by chunk and upload every chunk whose index isn't in `skip`.
+/// Each chunk's `file_chunks` row is inserted as soon as its upload finishes,
+/// so an ingest that is killed part-way keeps what it uploaded for `resume`;
+/// with `file_id` `None` (`--upload-only`) nothing is recorded and the
+/// upload can't be cancelled. `filesize` only sizes the progress bar; pass
+/// `None` for streams of unknown length.
+#[allow(clippy::too_many_arguments)]
+async fn upload_chunks<R: AsyncRead + Unpin>(
+    conn: &Connection,
+    file_id: Option<i64>,
+    f: &mut R,
+    filesize: Option<u64>,
+    chunk_size: usize,
+    webhooks: &[String],
+    transfer: &TransferOptions,
+    encoding: &ChunkEncoding,
+    skip: &HashSet<i64>,
+) -> Result<UploadedFile> {
+    // Prepare storage directory
+    let dir = file_id.filter(|_| transfer.local_storage).map(storage_dir);
+    if let Some(dir) = &dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let client = transfer.pacing.client()?;
+    let sentinel = file_id.map(|file_id| cancel_sentinel(conn, file_id));
+
+    // Each upload task holds a permit, so at most `concurrency` chunks are in memory at once
+    let semaphore = Arc::new(Semaphore::new(transfer.concurrency));
+    let mut tasks: JoinSet<std::result::Result<UploadedChunk, (usize, anyhow::Error)>> =
+        JoinSet::new();
+
+    let skipped = filesize
+        .map(|filesize| skipped_chunk_sizes(skip, filesize, chunk_size))
+        .unwrap_or_default();
+    let pb = match filesize {
+        Some(filesize) => progress_bar(
+            filesize.div_ceil(chunk_size as u64) - skipped.len() as u64,
+            "[chunk {pos} / {len}] uploading… {wide_bar} {elapsed_precise} ETA {eta} ({per_sec})",
+            &transfer.progress,
+        ),
+        None => progress_bar(
+            0,
+            "[chunk {pos}] uploading… {elapsed_precise} ({per_sec})",
+            &transfer.progress,
+        ),
+    };
+    let meter = ByteMeter::start(
+        filesize.map(|filesize| filesize - skipped.iter().sum::<u64>()),
+        &transfer.progress,
+    );
+
+    // Read and process file in chunks without loading everything into memory:
+    // the next chunk is only read once an upload slot is free, so at most
+    // `concurrency` chunks are held at a time
+    let mut buffer = vec![0u8; chunk_size];
+    let mut idx = 0;
+    let mut uploaded = UploadedFile::default();
+    let mut file_hash = encoding.hash_algo.hasher();
+    // The end of the previous chunk, which the next one starts with
+    let mut tail = Vec::new();
+
+    // Errors from here on stop reading through `uploaded.stop` instead of
+    // returning, which would drop the uploads still running
+    loop {
+        let permit = Arc::clone(&semaphore)
+            .acquire_owned()
+            .await
+            .expect("the upload semaphore is never closed");
+        while let Some(res) = tasks.try_join_next() {
+            uploaded.finish_task(conn, file_id, webhooks, encoding, res);
+        }
+        if uploaded.stopped.is_some() {
+            break;
+        }
+        let n = match read_chunk(f, &mut buffer).await {
+            Ok(n) => n,
+            Err(e) => {
+                uploaded.stop(e.context(format!("reading chunk {idx}")));
+                break;
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        file_hash.update(&buffer[..n]);
+        uploaded.size += n as u64;
+        let prefix = std::mem::replace(&mut tail, buffer[n - encoding.overlap.min(n)..n].to_vec());
+        if skip.contains(&(idx as i64)) {
+            idx += 1;
+            continue;
+        }
+        if let Some(sentinel) = sentinel.as_ref().filter(|sentinel| sentinel.exists()) {
+            if let Err(e) = fs::remove_file(sentinel) {
+                warn!("failed to remove {}: {}", sentinel.display(), e);
+            }
+            warn!(file_id, "cancelled, waiting for chunks already uploading");
+            uploaded.cancelled = true;
+            break;
+        }
+        if let Err(e) = reserve_quota(conn, &webhooks[idx % webhooks.len()]) {
+            uploaded.stop(e);
+            break;
+        }
+
+        let overlap = prefix.len();
+        let data = [prefix, buffer[..n].to_vec()].concat();
+        let client = client.clone();
+        let webhooks = webhooks.to_vec();
+        let encoding = encoding.clone();
+        let pb = pb.clone();
+        let max_retries = transfer.max_retries;
+        let pacing = transfer.pacing.clone();
+        let sent = meter.counter();
+        let chunk_path = dir.as_ref().map(|dir| dir.join(format!("{}.chunk", idx)));
+
+        tasks.spawn(async move {
+            let _permit = permit;
+            let uploaded = async {
+                let sha256 = encoding.hash_algo.digest(&data[overlap..]);
+                let (data, nonce_hex) =
+                    encode_chunk(data, &encoding).context("failed to encode chunk")?;
+
+                // Write chunk to disk
+                if let Some(chunk_path) = &chunk_path {
+                    tokio::fs::write(chunk_path, &data)
+                        .await
+                        .with_context(|| format!("failed to write {}", chunk_path.display()))?;
+                }
+                let stored_size = data.len();
+
+                // Upload with retry logic
+                let (webhook_idx, (message_id, url)) = upload_chunk_with_retry(
+                    &client,
+                    &webhooks,
+                    data.into(),
+                    idx,
+                    max_retries,
+                    &pacing,
+                    &sent,
+                )
+                .await?;
+                anyhow::Ok(UploadedChunk {
+                    idx,
+                    message_id,
+                    url,
+                    sha256,
+                    nonce_hex,
+                    webhook_idx,
+                    stored_size,
+                    overlap,
+                })
+            }
+            .await;
+
+            pb.inc(1);
+            uploaded.map_err(|e| (idx, e))
+        });
+        idx += 1;
+    }
+
+    // Wait for the remaining uploads to finish
+    while let Some(res) = tasks.join_next().await {
+        uploaded.finish_task(conn, file_id, webhooks, encoding, res);
+    }
+    pb.finish();
+    meter.finish();
+
+    uploaded.chunks.sort_by_key(|chunk| chunk.idx);
+    uploaded.failed.sort_by_key(|(idx, _)| *idx);
+    uploaded.sha256 = file_hash.finalize();
+    Ok(uploaded)
+}
+
+/// What an upload task ends with: the chunk, or its index and why it failed
+type UploadTaskResult = std::result::Result<
+    std::result::Result<UploadedChunk, (usize, anyhow::Error)>,
+    tokio::task::JoinError,
+>;
+
+impl UploadedFile {
+    /// Book an upload task that has finished: settle the quota reserved for
+    /// its chunk and, unless `file_id` is `None`, insert its `file_chunks`
+    /// row. A bookkeeping error stops the upload instead of being returned.
+    fn finish_task(
+        &mut self,
+        conn: &Connection,
+        file_id: Option<i64>,
+        webhooks: &[String],
+        encoding: &ChunkEncoding,
+        res: UploadTaskResult,
+    ) {
+        let booked = match res {
+            Ok(Ok(chunk)) => {
+                let booked = settle_quota(conn, webhooks, chunk.idx, Some(chunk.webhook_idx))
+                    .and_then(|()| match file_id {
+                        Some(file_id) => record_chunk(conn, file_id, &chunk, encoding),
+                        None => Ok(()),
+                    });
+                if booked.is_err() {
+                    warn!(
+                        chunk = chunk.idx,
+                        message_id = chunk.message_id,
+                        "chunk was uploaded but could not be recorded"
+                    );
+                }
+                self.chunks.push(chunk);
+                booked
+            }
+            Ok(Err(failure)) => {
+                let booked = settle_quota(conn, webhooks, failure.0, None);
+                self.failed.push(failure);
+                booked
+            }
+            Err(e) => Err(anyhow::Error::new(e).context("upload task failed")),
+        };
+        if let Err(e) = booked {
+            self.stop(e);
+        }
+    }
+
+    /// Stop reading more chunks. Only the first reason is kept; any later
+    /// ones are logged.
+    fn stop(&mut self, e: anyhow::Error) {
+        if self.stopped.is_some() {
+            warn!("{:#}", e);
+        } else {
+            self.stopped = Some(e);
+        }
+    }
+
+    /// After `cancel`: drop the `files` row if none of its chunks made it to
+    /// Discord, otherwise mark it `cancelled` so `resume` can finish it later.
+    /// Either way the ingest ends with an error saying which happened.
+    fn check_cancelled(&self, conn: &Connection, file_id: i64) -> Result<()> {
+        if !self.cancelled {
+            return Ok(());
+        }
+        let chunks: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM file_chunks WHERE file_id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )?;
+        if chunks == 0 {
+            unlink_version(conn, file_id)?;
+            conn.execute("DELETE FROM files WHERE id = ?1", params![file_id])?;
+            let dir = storage_dir(file_id);
+            if dir.exists() {
+                fs::remove_dir_all(&dir)?;
+            }
+            bail!("ingest of file_id={file_id} cancelled before any chunk was uploaded; nothing was kept");
+        }
+        conn.execute(
+            "UPDATE files SET status = 'cancelled' WHERE id = ?1",
+            params![file_id],
+        )?;
+        audit(
+            conn,
+            "cancel",
+            "file",
+            file_id,
+            &format!("cancelled with {} chunk(s) uploaded", chunks),
+        )?;
+        bail!("ingest of file_id={file_id} cancelled after {chunks} chunk(s); run `resume` to finish it");
+    }
+
+    /// After chunks failed for good, or an ingest from stdin didn't finish:
+    /// delete the ones that did reach Discord and remove the file's rows in
+    /// one transaction, so a failed ingest leaves nothing half-stored behind.
+    /// Ends with an error listing the chunks that couldn't be uploaded.
+    async fn roll_back(
+        &self,
+        conn: &mut Connection,
+        file_id: i64,
+        webhooks: &[String],
+        pacing: &UploadConfig,
+    ) -> Result<()> {
+        let client = pacing.client()?;
+        for chunk in &self.chunks {
+            let webhook = &webhooks[chunk.webhook_idx];
+            let deleted = async {
+                let parsed = Webhook::parse(webhook)?;
+                delete_message(&client, &parsed, &chunk.message_id, pacing).await
+            }
+            .await;
+            match deleted {
+                Ok(()) => release_quota(conn, webhook)?,
+                Err(e) => warn!(
+                    file_id,
+                    chunk = chunk.idx,
+                    message_id = chunk.message_id,
+                    "failed to delete uploaded chunk, it stays on Discord: {:#}",
+                    e
+                ),
+            }
+        }
+
+        let reason = if !self.failed.is_empty() {
+            format!("{} chunk(s) failed to upload", self.failed.len())
+        } else if self.cancelled {
+            "the ingest was cancelled".to_string()
+        } else {
+            "the upload stopped early".to_string()
+        };
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM file_chunks WHERE file_id = ?1",
+            params![file_id],
+        )?;
+        unlink_version(&tx, file_id)?;
+        tx.execute("DELETE FROM files WHERE id = ?1", params![file_id])?;
+        audit(
+            &tx,
+            "rollback",
+            "file",
+            file_id,
+            &format!("{reason}; removed {} uploaded chunk(s)", self.chunks.len()),
+        )?;
+        tx.commit()?;
+        let dir = storage_dir(file_id);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+
+        let mut details: Vec<String> = self
+            .failed
+            .iter()
+            .map(|(idx, e)| format!("  chunk {idx}: {e:#}"))
+            .collect();
+        if let Some(e) = &self.stopped {
+            details.push(format!("  stopped early: {e:#}"));
+        }
+        bail!(
+            "{reason}, so the ingest was rolled back and nothing was kept:\n{}",
+            details.join("\n")
+        );
+    }
+
+    /// An error listing every chunk that failed to upload, if any did. Call
+    /// it once the chunks that did make it are recorded, so `resume` can
+    /// pick up the rest.
+    fn check_failures(&self, file_id: i64) -> Result<()> {
+        if let Some(e) = &self.stopped {
+            bail!("upload of file_id={file_id} stopped early; run `resume` once fixed: {e:#}");
+        }
+        if self.failed.is_empty() {
+            return Ok(());
+        }
+        let details: Vec<String> = self
+            .failed
+            .iter()
+            .map(|(idx, e)| format!("  chunk {idx}: {e:#}"))
+            .collect();
+        bail!(
+            "{} chunk(s) of file_id={} failed to upload; run `resume` to retry them:\n{}",
+            self.failed.len(),
+            file_id,
+            details.join("\n")
+        );
+    }
+}
+
+/// Insert the `file_chunks` row of an uploaded chunk, together with the
+/// `chunk_count` it adds to.
+fn record_chunk(
+    conn: &Connection,
+    file_id: i64,
+    chunk: &UploadedChunk,
+    encoding: &ChunkEncoding,
+) -> Result<()> {
+    with_busy_retry(|| {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO file_chunks
+                (file_id, idx, message_id, url, sha256, compressed, encrypted, nonce_hex,
+                 webhook_idx, stored_size, overlap)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                file_id,
+                chunk.idx as i64,
+                chunk.message_id,
+                chunk.url,
+                chunk.sha256,
+                encoding.compress_level.is_some(),
+                chunk.nonce_hex.is_some(),
+                chunk.nonce_hex,
+                chunk.webhook_idx as i64,
+                chunk.stored_size as i64,
+                chunk.overlap as i64,
+            ],
+        )?;
+        add_chunk_count(&tx, file_id, 1)?;
+        tx.commit()
+    })?;
+    Ok(())
+}
+
+/// Add `n` newly inserted `file_chunks` rows to the `chunk_count` of
+/// `file_id`; call it in the same transaction as the inserts.
+fn add_chunk_count(conn: &Connection, file_id: i64, n: usize) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE files SET chunk_count = chunk_count + ?1 WHERE id = ?2",
+        params![n as i64, file_id],
+    )?;
+    Ok(())
+}
+
+/// Set the `chunk_count` of `file_id` from its `file_chunks` rows, for
+/// writers that may replace rows instead of adding them.
+fn recount_chunks(conn: &Connection, file_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE files SET chunk_count =
+             (SELECT COUNT(*) FROM file_chunks WHERE file_id = ?1)
+         WHERE id = ?1",
+        params![file_id],
+    )?;
+    Ok(())
+}
+
+/// `repair-chunk-count`: recompute every file's `chunk_count` from
+/// `file_chunks`, e.g. after editing the database by hand, and update its
+/// status to match. Returns `(file_id, stored, actual)` for each file that
+/// was off.
+pub fn repair_chunk_count(conn: &mut Connection) -> Result<Vec<(i64, i64, i64)>> {
+    let tx = conn.transaction()?;
+    let wrong = tx
+        .prepare(
+            "SELECT id, chunk_count, actual FROM (
+                 SELECT id, chunk_count,
+                        (SELECT COUNT(*) FROM file_chunks WHERE file_id = files.id) AS actual
+                 FROM files
+             )
+             WHERE chunk_count != actual
+             ORDER BY id",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    for (file_id, _, _) in &wrong {
+        recount_chunks(&tx, *file_id)?;
+        update_file_status(&tx, *file_id)?;
+    }
+    tx.commit()?;
+    Ok(wrong)
+}
+
+/// What `check-orphans` found
+#[derive(Debug, Default)]
+pub struct OrphanReport {
+    /// `(file_id, rows)`: `file_chunks` rows whose file no longer exists
+    pub chunks: Vec<(i64, i64)>,
+    /// `(file_id, tag_id)`: `file_tags` rows whose file or tag no longer exists
+    pub file_tags: Vec<(i64, i64)>,
+    /// `(id, parent_id)`: directories whose parent no longer exists
+    pub directories: Vec<(i64, i64)>,
+}
+
+impl OrphanReport {
+    /// Whether nothing was found
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.file_tags.is_empty() && self.directories.is_empty()
+    }
+}
+
+/// `check-orphans`: find rows left pointing at a file, tag or directory that
+/// was deleted by hand. With `repair`, the orphaned `file_chunks` and
+/// `file_tags` rows are deleted and the orphaned directories are moved to the
+/// root, with ` ({id})` added to the name if the root already has one by
+/// that name.
+pub fn check_orphans(conn: &mut Connection, repair: bool) -> Result<OrphanReport> {
+    let tx = conn.transaction()?;
+    let report = OrphanReport {
+        chunks: tx
+            .prepare(
+                "SELECT file_id, COUNT(*) FROM file_chunks
+                 WHERE file_id NOT IN (SELECT id FROM files)
+                 GROUP BY file_id ORDER BY file_id",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?,
+        file_tags: tx
+            .prepare(
+                "SELECT file_id, tag_id FROM file_tags
+                 WHERE file_id NOT IN (SELECT id FROM files)
+                    OR tag_id NOT IN (SELECT id FROM tags)
+                 ORDER BY file_id, tag_id",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?,
+        directories: tx
+            .prepare(
+                "SELECT id, parent_id FROM directories
+                 WHERE parent_id IS NOT NULL AND parent_id NOT IN (SELECT id FROM directories)
+                 ORDER BY id",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?,
+    };
+    if !repair || report.is_empty() {
+        return Ok(report);
+    }
+    tx.execute(
+        "DELETE FROM file_chunks WHERE file_id NOT IN (SELECT id FROM files)",
+        [],
+    )?;
+    tx.execute(
+        "DELETE FROM file_tags
+         WHERE file_id NOT IN (SELECT id FROM files) OR tag_id NOT IN (SELECT id FROM tags)",
+        [],
+    )?;
+    for (id, _) in &report.directories {
+        tx.execute(
+            "UPDATE directories SET parent_id = NULL,
+                 name = CASE WHEN EXISTS (
+                     SELECT 1 FROM directories d WHERE d.parent_id IS NULL AND d.name = directories.name
+                 ) THEN name || ' (' || id || ')' ELSE name END
+             WHERE id = ?1",
+            params![id],
+        )?;
+    }
+    for (file_id, rows) in &report.chunks {
+        audit(
+            &tx,
+            "repair",
+            "file",
+            *file_id,
+            &format!("deleted {rows} orphaned chunk row(s)"),
+        )?;
+    }
+    for (id, parent_id) in &report.directories {
+        audit(
+            &tx,
+            "repair",
+            "directory",
+            *id,
+            &format!("moved to the root, parent {parent_id} no longer exists"),
+        )?;
+    }
+    tx.commit()?;
+    Ok(report)
+}
+
+/// One line of an `import-urls` CSV
+#[derive(Deserialize)]
+struct ImportedChunk {
+    idx: String,
+    message_id: String,
+    url: String,
+}
+
+/// Insert (or replace) `file_chunks` rows from `csv`, returning how many were
+/// written and how many already matched the stored row exactly. The file is
+/// either a CSV with `idx,message_id,url` columns or the JSON lines printed
+/// by `ingest --upload-only`, which also carry each chunk's hash and
+/// encoding.
+pub fn import_urls(conn: &mut Connection, file_id: i64, csv: &Path) -> Result<(usize, usize)> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM files WHERE id = ?1)",
+        params![file_id],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        bail!("no file with id {file_id}");
+    }
+
+    let text =
+        fs::read_to_string(csv).with_context(|| format!("failed to read {}", csv.display()))?;
+    let rows = if text.trim_start().starts_with('{') {
+        read_chunk_lines(csv, &text)?
+    } else {
+        read_chunk_csv(csv, &text)?
+    };
+
+    let tx = conn.transaction()?;
+    let (mut inserted, mut skipped) = (0, 0);
+    for row in rows {
+        let duplicate: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM file_chunks
+             WHERE file_id = ?1 AND idx = ?2 AND message_id = ?3 AND url = ?4)",
+            params![file_id, row.idx, row.message_id, row.url],
+            |row| row.get(0),
+        )?;
+        if duplicate {
+            skipped += 1;
+            continue;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO file_chunks
+                 (file_id, idx, url, message_id, sha256, compressed, encrypted, nonce_hex,
+                  webhook_idx, stored_size, overlap)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                file_id,
+                row.idx,
+                row.url,
+                row.message_id,
+                row.sha256,
+                row.compressed,
+                row.nonce_hex.is_some(),
+                row.nonce_hex,
+                row.webhook_idx,
+                row.stored_size,
+                row.overlap
+            ],
+        )?;
+        inserted += 1;
+    }
+    recount_chunks(&tx, file_id)?;
+    // a file created just to import into learns its encoding from the chunks
+    tx.execute(
+        "UPDATE files SET
+             compressed = (SELECT MAX(compressed) FROM file_chunks WHERE file_id = ?1),
+             encrypted = (SELECT MAX(encrypted) FROM file_chunks WHERE file_id = ?1),
+             chunk_overlap = (SELECT MAX(overlap) FROM file_chunks WHERE file_id = ?1)
+         WHERE id = ?1 AND compressed IS NULL AND chunk_count > 0",
+        params![file_id],
+    )?;
+    update_file_status(&tx, file_id)?;
+    audit(
+        &tx,
+        "import-urls",
+        "file",
+        file_id,
+        &format!("imported {} chunk(s) from {}", inserted, csv.display()),
+    )?;
+    tx.commit()?;
+    Ok((inserted, skipped))
+}
+
+/// The rows of an `import-urls` file in `ingest --upload-only` format.
+fn read_chunk_lines(path: &Path, text: &str) -> Result<Vec<ChunkLine>> {
+    let mut rows = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_no = i + 1;
+        let mut row: ChunkLine = serde_json::from_str(line)
+            .with_context(|| format!("{}:{}", path.display(), line_no))?;
+        if row.idx < 0 {
+            bail!("{}:{}: idx must not be negative", path.display(), line_no);
+        }
+        row.url = row.url.trim().to_string();
+        if row.url.is_empty() {
+            bail!("{}:{}: url is empty", path.display(), line_no);
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// The rows of an `import-urls` CSV, which only has the URLs.
+fn read_chunk_csv(csv: &Path, text: &str) -> Result<Vec<ChunkLine>> {
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let mut rows = Vec::new();
+    for (i, record) in reader.deserialize::<ImportedChunk>().enumerate() {
+        // header is line 1
+        let line = i + 2;
+        let record = record.with_context(|| format!("{}:{}", csv.display(), line))?;
+        let idx: i64 =
+            record.idx.trim().parse().with_context(|| {
+                format!("{}:{}: invalid idx '{}'", csv.display(), line, record.idx)
+            })?;
+        if idx < 0 {
+            bail!("{}:{}: idx must not be negative", csv.display(), line);
+        }
+        let url = record.url.trim();
+        if url.is_empty() {
+            bail!("{}:{}: url is empty", csv.display(), line);
+        }
+        rows.push(ChunkLine {
+            idx,
+            message_id: record.message_id.trim().to_string(),
+            url: url.to_string(),
+            sha256: String::new(),
+            compressed: false,
+            nonce_hex: None,
+            webhook_idx: 0,
+            stored_size: None,
+            overlap: 0,
+        });
+    }
+    Ok(rows)
+}
+
+/// `import-from-dir`: fill in the missing chunks of `file_id` from the
+/// `{idx}.chunk` files in `dir`, e.g. a backup of `storage/{file_id}/`. The
+/// files may hold raw bytes or chunks as `ingest` stored them, compressed
+/// and/or encrypted (which needs `cipher`), and every chunk after the first
+/// starts with the `chunk_overlap` bytes the file was ingested with. Each
+/// chunk is uploaded to `webhooks`; with `None` it is registered under a
+/// `local://` URL instead, so exports read it straight from `dir`, and the
+/// file is marked `local_only`. Returns how many chunks were imported.
+pub async fn import_from_dir(
+    conn: &mut Connection,
+    file_id: i64,
+    dir: &Path,
+    webhooks: Option<&[String]>,
+    transfer: &TransferOptions,
+    cipher: Option<ChunkCipher>,
+) -> Result<usize> {
+    let (filesize, chunk_size, overlap): (i64, i64, i64) = conn
+        .query_row(
+            "SELECT filesize, chunk_size, COALESCE(chunk_overlap, 0) FROM files WHERE id = ?1",
+            params![file_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    // `local://` URLs have to keep working from any working directory
+    let dir = dir.canonicalize()?;
+    let algo = file_hash_algo(conn, file_id)?;
+    let done: HashSet<i64> = load_chunks(conn, file_id)?.iter().map(|c| c.idx).collect();
+    let todo: Vec<i64> = (0..(filesize as u64).div_ceil(chunk_size as u64) as i64)
+        .filter(|idx| !done.contains(idx))
+        .collect();
+
+    let client = transfer.pacing.client()?;
+    let sent = Arc::new(AtomicU64::new(0));
+    let pb = progress_bar(
+        todo.len() as u64,
+        "[chunk {pos} / {len}] importing… {wide_bar} {elapsed_precise}",
+        &transfer.progress,
+    );
+    let mut missing = Vec::new();
+    let mut imported = 0;
+    for idx in todo {
+        let path = dir.join(format!("{idx}.chunk"));
+        if !path.is_file() {
+            missing.push(idx.to_string());
+            pb.inc(1);
+            continue;
+        }
+        let data = fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let len = (filesize - idx * chunk_size).min(chunk_size) as usize;
+        let overlap = if idx > 0 { overlap as usize } else { 0 };
+        let (chunk, raw) = sniff_chunk(idx, &data, len, overlap, cipher.as_ref())
+            .with_context(|| path.display().to_string())?;
+        let stored_size = data.len();
+
+        let (message_id, url, webhook_idx) = match webhooks {
+            Some(webhooks) => {
+                reserve_quota(conn, &webhooks[idx as usize % webhooks.len()])?;
+                let uploaded = upload_chunk_with_retry(
+                    &client,
+                    webhooks,
+                    data.into(),
+                    idx as usize,
+                    transfer.max_retries,
+                    &transfer.pacing,
+                    &sent,
+                )
+                .await;
+                let landed = uploaded.as_ref().ok().map(|(webhook_idx, _)| *webhook_idx);
+                settle_quota(conn, webhooks, idx as usize, landed)?;
+                let (webhook_idx, (message_id, url)) = uploaded?;
+                (message_id, url, webhook_idx)
+            }
+            None => (
+                String::new(),
+                format!("{LOCAL_URL_PREFIX}{}", path.display()),
+                0,
+            ),
+        };
+        with_busy_retry(|| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "INSERT INTO file_chunks
+                    (file_id, idx, message_id, url, sha256, compressed, encrypted, nonce_hex,
+                     webhook_idx, stored_size, overlap)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    file_id,
+                    idx,
+                    message_id,
+                    url,
+                    algo.digest(&raw),
+                    chunk.compressed,
+                    chunk.encrypted,
+                    chunk.nonce_hex,
+                    webhook_idx as i64,
+                    stored_size as i64,
+                    chunk.overlap as i64,
+                ],
+            )?;
+            add_chunk_count(&tx, file_id, 1)?;
+            tx.commit()
+        })?;
+        imported += 1;
+        pb.inc(1);
+    }
+    pb.finish();
+
+    conn.execute(
+        "UPDATE files SET local_only = EXISTS (
+             SELECT 1 FROM file_chunks WHERE file_id = ?1 AND url LIKE 'local://%'
+         )
+         WHERE id = ?1",
+        params![file_id],
+    )?;
+    update_file_status(conn, file_id)?;
+    audit(
+        conn,
+        "import-from-dir",
+        "file",
+        file_id,
+        &format!(
+            "imported {} chunk(s) from {}{}",
+            imported,
+            dir.display(),
+            if webhooks.is_none() {
+                " without uploading"
+            } else {
+                ""
+            }
+        ),
+    )?;
+    if !missing.is_empty() {
+        bail!(
+            "{} chunk(s) of file_id={} not found in {}: {}",
+            missing.len(),
+            file_id,
+            dir.display(),
+            missing.join(", ")
+        );
+    }
+    Ok(imported)
+}
+
+/// Work out how a chunk file read back from disk is encoded: whichever of
+/// encrypted and/or compressed decodes it to the `len` bytes expected once
+/// the leading `overlap` bytes are dropped. An encrypted chunk starts with
+/// its nonce, as `encode_chunk` writes it. Returns the encoding and the raw
+/// bytes without the overlap, which is what `ingest` hashes.
+fn sniff_chunk(
+    idx: i64,
+    data: &[u8],
+    len: usize,
+    overlap: usize,
+    cipher: Option<&ChunkCipher>,
+) -> Result<(StoredChunk, Vec<u8>)> {
+    let nonce_hex = data.get(..NONCE_LEN).map(hex::encode);
+    for (encrypted, compressed) in [(true, true), (true, false), (false, true), (false, false)] {
+        if encrypted && (cipher.is_none() || nonce_hex.is_none()) {
+            continue;
+        }
+        let chunk = StoredChunk {
+            idx,
+            url: String::new(),
+            sha256: String::new(),
+            compressed,
+            encrypted,
+            nonce_hex: nonce_hex.clone().filter(|_| encrypted),
+            overlap,
+        };
+        if let Ok(raw) = decode_chunk(data, &chunk, cipher) {
+            if raw.len() == len {
+                return Ok((chunk, raw));
+            }
+        }
+    }
+    bail!(
+        "chunk {idx} doesn't decode to the expected {} bytes{}",
+        len + overlap,
+        if cipher.is_none() {
+            "; pass --key-file if it is encrypted"
+        } else {
+            ""
+        }
+    );
+}
+
+/// Mark a file `complete` once every chunk its size calls for is in `file_chunks`.
+fn update_file_status(conn: &Connection, file_id: i64) -> Result<()> {
+    with_busy_retry(|| {
+        conn.execute(
+            "UPDATE files SET status = CASE
+                WHEN chunk_count = (filesize + chunk_size - 1) / chunk_size
+                THEN 'complete' ELSE 'pending' END
+             WHERE id = ?1",
+            params![file_id],
+        )
+    })?;
+    Ok(())
+}
+
+/// Turn raw chunk bytes into what gets uploaded: compressed if a level is given,
+/// then encrypted with a fresh nonce (prepended to the ciphertext) if a cipher is given.
+/// Returns the encoded bytes and the hex nonce used, if any.
+fn encode_chunk(data: Vec<u8>, encoding: &ChunkEncoding) -> Result<(Vec<u8>, Option<String>)> {
+    let data = match encoding.compress_level {
+        Some(level) => zstd::encode_all(&data[..], level)?,
+        None => data,
+    };
+    let Some(cipher) = &encoding.cipher else {
+        return Ok((data, None));
+    };
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::fill(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(&nonce, &data[..])
+        .map_err(|_| anyhow!("encryption failed"))?;
+    let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok((out, Some(hex::encode(nonce))))
+}
+
+/// Inverse of `encode_chunk`: recover the raw bytes of a downloaded chunk.
+fn decode_chunk(data: &[u8], chunk: &StoredChunk, cipher: Option<&ChunkCipher>) -> Result<Vec<u8>> {
+    let idx = chunk.idx;
+    let data = if chunk.encrypted {
+        let cipher =
+            cipher.with_context(|| format!("chunk {idx} is encrypted; pass --key-file"))?;
+        let nonce = hex::decode(chunk.nonce_hex.as_deref().unwrap_or_default())
+            .with_context(|| format!("chunk {idx} has a malformed nonce"))?;
+        if nonce.len() != NONCE_LEN {
+            bail!(
+                "chunk {idx} has a {}-byte nonce, expected {NONCE_LEN}",
+                nonce.len()
+            );
+        }
+        // the uploaded bytes start with the nonce; the stored copy is authoritative
+        let Some(ciphertext) = data.get(NONCE_LEN..) else {
+            bail!("chunk {idx} is too short to contain its nonce");
+        };
+        cipher
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| anyhow!("chunk {idx} failed to decrypt; is the key file correct?"))?
+    } else {
+        data.to_vec()
+    };
+
+    let mut data = if chunk.compressed {
+        zstd::decode_all(&data[..])?
+    } else {
+        data
+    };
+    if data.len() < chunk.overlap {
+        bail!(
+            "chunk {idx} is shorter than its {}-byte overlap",
+            chunk.overlap
+        );
+    }
+    data.drain(..chunk.overlap);
+    Ok(data)
+}
+
+fn load_chunks(conn: &Connection, file_id: i64) -> Result<Vec<StoredChunk>> {
+    let mut stmt = conn.prepare(
+        "SELECT idx, url, sha256, compressed, encrypted, nonce_hex, overlap
+         FROM file_chunks WHERE file_id = ?1 ORDER BY idx ASC",
+    )?;
+    let chunks = stmt
+        .query_map(params![file_id], |row| {
+            Ok(StoredChunk {
+                idx: row.get(0)?,
+                url: row.get(1)?,
+                sha256: row.get(2)?,
+                compressed: row.get(3)?,
+                encrypted: row.get(4)?,
+                nonce_hex: row.get(5)?,
+                overlap: row.get::<_, i64>(6)? as usize,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(chunks)
+}
+
+/// AES-GCM with whichever key size the key file holds
+#[derive(Clone)]
+pub enum ChunkCipher {
+    /// 16-byte key
+    Aes128(Aes128Gcm),
+    /// 24-byte key
+    Aes192(AesGcm<aes::Aes192, U12>),
+    /// 32-byte key
+    Aes256(Aes256Gcm),
+}
+
+impl ChunkCipher {
+    fn new(key: &[u8]) -> Result<Self> {
+        Ok(match key.len() {
+            16 => ChunkCipher::Aes128(Aes128Gcm::new_from_slice(key)?),
+            24 => ChunkCipher::Aes192(AesGcm::new_from_slice(key)?),
+            32 => ChunkCipher::Aes256(Aes256Gcm::new_from_slice(key)?),
+            n => bail!("a key must be 16, 24 or 32 bytes, not {n}"),
+        })
+    }
+
+    fn encrypt(&self, nonce: &[u8], data: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(nonce);
+        match self {
+            ChunkCipher::Aes128(cipher) => cipher.encrypt(nonce, data),
+            ChunkCipher::Aes192(cipher) => cipher.encrypt(nonce, data),
+            ChunkCipher::Aes256(cipher) => cipher.encrypt(nonce, data),
+        }
+    }
+
+    fn decrypt(&self, nonce: &[u8], data: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(nonce);
+        match self {
+            ChunkCipher::Aes128(cipher) => cipher.decrypt(nonce, data),
+            ChunkCipher::Aes192(cipher) => cipher.decrypt(nonce, data),
+            ChunkCipher::Aes256(cipher) => cipher.decrypt(nonce, data),
+        }
+    }
+}
+
+/// Algorithm behind a file's whole-file and chunk hashes, stored in `files.hash_algo`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgo {
+    /// SHA-256
+    #[default]
+    Sha256,
+    /// BLAKE3
+    Blake3,
+}
+
+impl HashAlgo {
+    fn as_str(self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+
+    fn from_db(name: &str) -> Result<Self> {
+        match name {
+            "sha256" => Ok(HashAlgo::Sha256),
+            "blake3" => Ok(HashAlgo::Blake3),
+            other => bail!("unknown hash algorithm '{other}' in the database"),
+        }
+    }
+
+    /// How the algorithm is named in messages
+    fn label(self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "SHA-256",
+            HashAlgo::Blake3 => "BLAKE3",
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            HashAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgo::Blake3 => Hasher::Blake3(blake3::Hasher::new()),
+        }
+    }
+
+    /// Hex digest of `data` in one go
+    fn digest(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// An in-progress hash in either supported algorithm
+#[allow(clippy::large_enum_variant)]
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(blake3::Hasher),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// The digest as lowercase hex; both algorithms give 32 bytes
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// The algorithm `file_id`'s hashes were computed with.
+fn file_hash_algo(conn: &Connection, file_id: i64) -> Result<HashAlgo> {
+    let name: String = conn
+        .query_row(
+            "SELECT hash_algo FROM files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    HashAlgo::from_db(&name)
+}
+
+/// Reads a raw 16, 24 or 32 byte key file
+pub fn read_key(path: &Path) -> Result<Vec<u8>> {
+    let key = fs::read(path).with_context(|| format!("reading key file {}", path.display()))?;
+    if ![16, 24, 32].contains(&key.len()) {
+        bail!(
+            "key file {} holds {} bytes, expected 16, 24 or 32",
+            path.display(),
+            key.len()
+        );
+    }
+    Ok(key)
+}
+
+/// Reads a key file into a cipher
+pub fn load_key(path: &Path) -> Result<ChunkCipher> {
+    ChunkCipher::new(&read_key(path)?)
+}
+
+/// Hex SHA-256 of a key, to tell keys apart without showing them.
+pub fn key_fingerprint(key: &[u8]) -> String {
+    hex::encode(Sha256::digest(key))
+}
+
+/// Write `bits / 8` random bytes to a new file at `out`, readable only by
+/// the owner on Unix. Returns the key.
+pub fn generate_key(out: &Path, bits: u32) -> Result<Vec<u8>> {
+    if ![128, 192, 256].contains(&bits) {
+        bail!("--bits must be 128, 192 or 256, not {bits}");
+    }
+    let mut key = vec![0u8; bits as usize / 8];
+    rand::fill(&mut key[..]);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(out).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            anyhow!("refusing to overwrite existing key file {}", out.display())
+        } else {
+            anyhow!(e).context(format!("writing key file {}", out.display()))
+        }
+    })?;
+    file.write_all(&key)
+        .with_context(|| format!("writing key file {}", out.display()))?;
+    Ok(key)
+}
+
+/// Where progress bars are drawn: stderr, or nowhere when `quiet` is set or
+/// stdout isn't a terminal (e.g. when exporting into a pipe).
+pub fn progress_target(quiet: bool) -> MultiProgress {
+    if quiet || !std::io::stdout().is_terminal() {
+        return MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    }
+    MultiProgress::new()
+}
+
+/// A progress bar stacked with any others already on `progress`.
+fn progress_bar(len: u64, template: &str, progress: &MultiProgress) -> ProgressBar {
+    let pb = progress.add(ProgressBar::new(len));
+    // byte counts in the same units as everywhere else
+    pb.set_style(
+        ProgressStyle::with_template(template)
+            .expect("valid progress template")
+            .with_key("bytes", |state: &ProgressState, w: &mut dyn fmt::Write| {
+                write!(w, "{}", format_size(state.pos() as i64)).unwrap()
+            })
+            .with_key(
+                "total_bytes",
+                |state: &ProgressState, w: &mut dyn fmt::Write| {
+                    write!(w, "{}", format_size(state.len().unwrap_or(0) as i64)).unwrap()
+                },
+            )
+            .with_key(
+                "bytes_per_sec",
+                |state: &ProgressState, w: &mut dyn fmt::Write| {
+                    write!(w, "{}/s", format_size(state.per_sec() as i64)).unwrap()
+                },
+            )
+            .progress_chars("=> "),
+    );
+    pb
+}
+
+/// The byte counts `ByteMeter` sampled over the last `RATE_WINDOW`
+struct RateWindow {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateWindow {
+    fn new(start: Instant) -> Self {
+        Self {
+            samples: VecDeque::from([(start, 0)]),
+        }
+    }
+
+    /// Record that `bytes` had been sent by `now` and return the average
+    /// bytes per second over the window.
+    fn rate(&mut self, now: Instant, bytes: u64) -> f64 {
+        // a failed attempt takes its bytes back off; start the window again
+        if self.samples.back().is_some_and(|&(_, last)| bytes < last) {
+            self.samples.clear();
+        }
+        self.samples.push_back((now, bytes));
+        while self.samples.len() > 2 && now - self.samples[1].0 >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        let (since, base) = self.samples[0];
+        let elapsed = (now - since).as_secs_f64();
+        if elapsed > 0.0 {
+            bytes.saturating_sub(base) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Counts bytes as they are streamed to Discord and, from a background task,
+/// shows how many have gone out with a rolling average speed and, when the
+/// total is known, the time left.
+struct ByteMeter {
+    sent: Arc<AtomicU64>,
+    pb: ProgressBar,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ByteMeter {
+    fn start(total: Option<u64>, progress: &MultiProgress) -> Self {
+        let pb = match total {
+            Some(total) => progress_bar(total, "{bytes} / {total_bytes} sent {msg}", progress),
+            None => progress_bar(0, "{bytes} sent {msg}", progress),
+        };
+        let sent = Arc::new(AtomicU64::new(0));
+        let task = tokio::spawn({
+            let sent = Arc::clone(&sent);
+            let pb = pb.clone();
+            async move {
+                let mut window = RateWindow::new(Instant::now());
+                let mut tick = tokio::time::interval(Duration::from_millis(500));
+                loop {
+                    tick.tick().await;
+                    let bytes = sent.load(Ordering::Relaxed);
+                    let rate = window.rate(Instant::now(), bytes);
+                    pb.set_position(bytes);
+                    let mut msg = format!("({}/s", format_size(rate as i64));
+                    if let Some(total) = total.filter(|_| rate >= 1.0) {
+                        let left = total.saturating_sub(bytes) as f64 / rate;
+                        msg += &format!(", ETA {}", HumanDuration(Duration::from_secs_f64(left)));
+                    }
+                    msg.push(')');
+                    pb.set_message(msg);
+                }
+            }
+        });
+        Self { sent, pb, task }
+    }
+
+    /// Shared counter to hand to `upload_chunk_with_retry`
+    fn counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.sent)
+    }
+
+    fn finish(self) {
+        self.task.abort();
+        self.pb.set_position(self.sent.load(Ordering::Relaxed));
+        self.pb.finish_with_message("");
+    }
+}
+
+/// Write every row of `BACKUP_TABLES` to `out` as JSON objects keyed by
+/// column name. Returns the number of rows written.
+pub fn backup_db(conn: &Connection, out: &Path) -> Result<usize> {
+    let mut tables = serde_json::Map::new();
+    let mut total = 0;
+    for table in BACKUP_TABLES {
+        let mut stmt = conn.prepare(&format!("SELECT * FROM {table}"))?;
+        let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        let rows = stmt
+            .query_map([], |row| {
+                let mut object = serde_json::Map::new();
+                for (i, column) in columns.iter().enumerate() {
+                    let value = match row.get_ref(i)? {
+                        ValueRef::Null => serde_json::Value::Null,
+                        ValueRef::Integer(n) => n.into(),
+                        ValueRef::Real(f) => f.into(),
+                        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+                        ValueRef::Blob(b) => hex::encode(b).into(),
+                    };
+                    object.insert(column.clone(), value);
+                }
+                Ok(serde_json::Value::Object(object))
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        total += rows.len();
+        tables.insert(table.to_string(), rows.into());
+    }
+
+    let backup = serde_json::json!({
+        "version": BACKUP_VERSION,
+        "schema_version": schema_version(conn)?,
+        "tables": tables,
+    });
+    let file = File::create(out).with_context(|| format!("failed to create {}", out.display()))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), &backup)?;
+    Ok(total)
+}
+
+/// How `merge-db` handles a file whose name is already used in the same directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MergeConflict {
+    /// Keep the existing file and leave the incoming one out
+    Skip,
+    /// Drop the existing file's rows and take the incoming one
+    Replace,
+    /// Import the incoming file as `name (2).ext`, `name (3).ext`, …
+    Rename,
+}
+
+/// Counts printed at the end of `merge-db`
+#[derive(Debug, Default)]
+pub struct MergeSummary {
+    files: usize,
+    chunks: usize,
+    skipped: usize,
+    replaced: usize,
+    renamed: usize,
+    directories: usize,
+    tags: usize,
+}
+
+/// "directory" or "directories", as `(s)` doesn't work for this one.
+fn directories_noun(count: usize) -> &'static str {
+    if count == 1 {
+        "directory"
+    } else {
+        "directories"
+    }
+}
+
+impl fmt::Display for MergeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Merged {} file(s) with {} chunk(s) ({} replaced, {} renamed), skipped {}; added {} {} and {} tag(s)",
+            self.files,
+            self.chunks,
+            self.replaced,
+            self.renamed,
+            self.skipped,
+            self.directories,
+            directories_noun(self.directories),
+            self.tags
+        )
+    }
+}
+
+/// Copy the `files`, `file_chunks`, `directories`, `tags` and `file_tags` rows
+/// of `source` into `conn`. Every row gets a fresh id and the references to it
+/// are rewritten. Directories with the same name and parent, and tags with the
+/// same name, are shared instead of duplicated; files whose name is taken are
+/// handled as `conflict` says.
+pub fn merge_db(
+    conn: &mut Connection,
+    source: &Path,
+    conflict: MergeConflict,
+) -> Result<MergeSummary> {
+    let main_path: Option<String> = conn.query_row(
+        "SELECT file FROM pragma_database_list WHERE name = 'main'",
+        [],
+        |row| row.get(0),
+    )?;
+    if let (Some(main_path), Ok(source_path)) = (main_path, source.canonicalize()) {
+        if Path::new(&main_path).canonicalize().ok() == Some(source_path) {
+            bail!("{} is the current database", source.display());
+        }
+    }
+    conn.execute(
+        "ATTACH DATABASE ?1 AS src",
+        params![source.to_string_lossy()],
+    )
+    .with_context(|| format!("attaching {}", source.display()))?;
+    let merged = merge_attached(conn, source, conflict);
+    conn.execute_batch("DETACH DATABASE src")?;
+    merged
+}
+
+fn merge_attached(
+    conn: &mut Connection,
+    source: &Path,
+    conflict: MergeConflict,
+) -> Result<MergeSummary> {
+    let mut summary = MergeSummary::default();
+    let tx = conn.transaction()?;
+
+    // Parents first: keep going over the rest until every directory is placed
+    let mut pending = tx
+        .prepare(
+            "SELECT id, name, parent_id, created_at, deleted_at FROM src.directories ORDER BY id",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<i64>>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let mut dir_map: HashMap<i64, i64> = HashMap::new();
+    while !pending.is_empty() {
+        let before = pending.len();
+        let mut rest = Vec::new();
+        for (id, name, parent_id, created_at, deleted_at) in pending {
+            let parent = match parent_id {
+                None => None,
+                Some(parent_id) => match dir_map.get(&parent_id) {
+                    Some(&mapped) => Some(mapped),
+                    None => {
+                        rest.push((id, name, Some(parent_id), created_at, deleted_at));
+                        continue;
+                    }
+                },
+            };
+            let existing: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM main.directories WHERE name = ?1 AND parent_id IS ?2",
+                    params![name, parent],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let new_id = match existing {
+                Some(existing) => existing,
+                None => {
+                    tx.execute(
+                        "INSERT INTO main.directories (name, parent_id, created_at, deleted_at)
+                         VALUES (?1, ?2, ?3, ?4)",
+                        params![name, parent, created_at, deleted_at],
+                    )?;
+                    summary.directories += 1;
+                    tx.last_insert_rowid()
+                }
+            };
+            dir_map.insert(id, new_id);
+        }
+        if rest.len() == before {
+            bail!(
+                "cannot merge {} {} from {}: missing or circular parent",
+                rest.len(),
+                directories_noun(rest.len()),
+                source.display()
+            );
+        }
+        pending = rest;
+    }
+
+    let mut tag_map: HashMap<i64, i64> = HashMap::new();
+    let tags = tx
+        .prepare("SELECT id, name FROM src.tags")?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    for (id, name) in tags {
+        let inserted = tx.execute(
+            "INSERT OR IGNORE INTO main.tags (name) VALUES (?1)",
+            params![name],
+        )?;
+        summary.tags += inserted;
+        let new_id = tx.query_row(
+            "SELECT id FROM main.tags WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )?;
+        tag_map.insert(id, new_id);
+    }
+
+    // A new version is always ingested after its parent, so parents come first
+    let files = tx
+        .prepare("SELECT id, filename, directory_id, parent_file_id FROM src.files ORDER BY id")?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<i64>>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let mut file_map: HashMap<i64, i64> = HashMap::new();
+    for (src_id, filename, directory_id, parent_file_id) in files {
+        let dir = directory_id.and_then(|id| dir_map.get(&id).copied());
+        let taken = |name: &str| -> Result<Option<i64>> {
+            Ok(tx
+                .query_row(
+                    "SELECT id FROM main.files WHERE filename = ?1 AND directory_id IS ?2",
+                    params![name, dir],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        };
+        let mut name = filename.clone();
+        if let Some(existing) = taken(&filename)? {
+            match conflict {
+                MergeConflict::Skip => {
+                    info!(file_id = src_id, "'{}' already exists, skipping", filename);
+                    summary.skipped += 1;
+                    continue;
+                }
+                MergeConflict::Replace => {
+                    tx.execute(
+                        "DELETE FROM main.file_chunks WHERE file_id = ?1",
+                        params![existing],
+                    )?;
+                    unlink_version(&tx, existing)?;
+                    tx.execute("DELETE FROM main.files WHERE id = ?1", params![existing])?;
+                    audit(
+                        &tx,
+                        "delete",
+                        "file",
+                        existing,
+                        &format!("replaced by '{}' from {}", filename, source.display()),
+                    )?;
+                    summary.replaced += 1;
+                }
+                MergeConflict::Rename => {
+                    let path = Path::new(&filename);
+                    let stem = path
+                        .file_stem()
+                        .map_or(filename.clone(), |s| s.to_string_lossy().into_owned());
+                    let ext = path
+                        .extension()
+                        .map(|e| format!(".{}", e.to_string_lossy()))
+                        .unwrap_or_default();
+                    let mut n = 2;
+                    loop {
+                        name = format!("{stem} ({n}){ext}");
+                        if taken(&name)?.is_none() {
+                            break;
+                        }
+                        n += 1;
+                    }
+                    summary.renamed += 1;
+                }
+            }
+        }
+
+        let parent = parent_file_id.and_then(|id| file_map.get(&id).copied());
+        tx.execute(
+            "INSERT INTO main.files
+                 (filename, filesize, chunk_size, created_at, directory_id, status, mime_type,
+                  description, deleted_at, sha256, original_path, version, parent_file_id,
+                  hash_algo, compressed, encrypted, chunk_overlap, local_only,
+                  ingest_duration_ms)
+             SELECT ?1, filesize, chunk_size, created_at, ?2, status, mime_type,
+                    description, deleted_at, sha256, original_path, version, ?3, hash_algo,
+                    compressed, encrypted, chunk_overlap, local_only, ingest_duration_ms
+             FROM src.files WHERE id = ?4",
+            params![name, dir, parent, src_id],
+        )?;
+        let new_id = tx.last_insert_rowid();
+        file_map.insert(src_id, new_id);
+        let chunks = tx.execute(
+            "INSERT INTO main.file_chunks
+                 (file_id, idx, url, message_id, sha256, compressed, encrypted, nonce_hex,
+                  webhook_idx, stored_size, overlap)
+             SELECT ?1, idx, url, message_id, sha256, compressed, encrypted, nonce_hex,
+                    webhook_idx, stored_size, overlap
+             FROM src.file_chunks WHERE file_id = ?2",
+            params![new_id, src_id],
+        )?;
+        add_chunk_count(&tx, new_id, chunks)?;
+        summary.chunks += chunks;
+        let file_tags = tx
+            .prepare("SELECT tag_id FROM src.file_tags WHERE file_id = ?1")?
+            .query_map(params![src_id], |row| row.get::<_, i64>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for tag_id in file_tags {
+            if let Some(tag_id) = tag_map.get(&tag_id) {
+                tx.execute(
+                    "INSERT OR IGNORE INTO main.file_tags (file_id, tag_id) VALUES (?1, ?2)",
+                    params![new_id, tag_id],
+                )?;
+            }
+        }
+        audit(
+            &tx,
+            "merge",
+            "file",
+            new_id,
+            &format!(
+                "merged file {} of {} as '{}'",
+                src_id,
+                source.display(),
+                name
+            ),
+        )?;
+        summary.files += 1;
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+/// Insert the rows of a `backup` file, keeping existing rows unless `merge`
+/// is set. Columns this schema doesn't know are ignored. Returns the number
+/// of rows inserted or replaced.
+pub fn restore_db(conn: &mut Connection, backup: &Path, merge: bool) -> Result<usize> {
+    let file =
+        File::open(backup).with_context(|| format!("failed to open {}", backup.display()))?;
+    let backup: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file))
+        .context("backup is not valid JSON")?;
+    let version = backup["version"]
+        .as_i64()
+        .context("backup has no `version`")?;
+    if version > BACKUP_VERSION {
+        bail!("backup version {version} is newer than this build supports ({BACKUP_VERSION})");
+    }
+
+    let tx = conn.transaction()?;
+    // rows may reference ones later in the file, so only check at commit
+    tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+    let verb = if merge {
+        "INSERT OR REPLACE"
+    } else {
+        "INSERT OR IGNORE"
+    };
+    let mut total = 0;
+    for table in BACKUP_TABLES {
+        let Some(rows) = backup["tables"][table].as_array() else {
+            continue;
+        };
+        let known: HashSet<String> = {
+            let mut stmt = tx.prepare(&format!("PRAGMA table_info({table})"))?;
+            let names = stmt
+                .query_map([], |row| row.get::<_, String>(1))?
+                .collect::<std::result::Result<_, _>>()?;
+            names
+        };
+        for row in rows {
+            let object = row
+                .as_object()
+                .with_context(|| format!("malformed row in `{table}`"))?;
+            let (columns, values): (Vec<&str>, Vec<rusqlite::types::Value>) = object
+                .iter()
+                .filter(|(column, _)| known.contains(column.as_str()))
+                .map(|(column, value)| {
+                    let value = match value {
+                        serde_json::Value::Null => rusqlite::types::Value::Null,
+                        serde_json::Value::Bool(b) => (*b as i64).into(),
+                        serde_json::Value::Number(n) => match n.as_i64() {
+                            Some(n) => n.into(),
+                            None => n.as_f64().unwrap_or_default().into(),
+                        },
+                        serde_json::Value::String(s) => s.clone().into(),
+                        other => other.to_string().into(),
+                    };
+                    (column.as_str(), value)
+                })
+                .unzip();
+            if columns.is_empty() {
+                continue;
+            }
+            let placeholders = vec!["?"; columns.len()].join(", ");
+            total += tx.execute(
+                &format!(
+                    "{verb} INTO {table} ({}) VALUES ({placeholders})",
+                    columns.join(", ")
+                ),
+                rusqlite::params_from_iter(values),
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(total)
+}
+
+/// Delete `storage/` entries with no matching row: whole directories of
+/// unknown files and `{idx}.chunk` files missing from `file_chunks`. Returns
+/// how many entries were (or with `dry_run`, would be) removed and their size.
+pub fn vacuum_storage(conn: &Connection, dry_run: bool) -> Result<(usize, u64)> {
+    let root = Path::new("storage");
+    if !root.exists() {
+        return Ok((0, 0));
+    }
+    let mut file_exists = conn.prepare("SELECT EXISTS (SELECT 1 FROM files WHERE id = ?1)")?;
+    let mut chunk_exists =
+        conn.prepare("SELECT EXISTS (SELECT 1 FROM file_chunks WHERE file_id = ?1 AND idx = ?2)")?;
+
+    let mut count = 0;
+    let mut bytes = 0;
+    for entry in fs::read_dir(root)? {
+        let dir = entry?.path();
+        let Some(file_id) = dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.parse::<i64>().ok())
+        else {
+            continue;
+        };
+        if !dir.is_dir() {
+            continue;
+        }
+
+        if !file_exists.query_row(params![file_id], |row| row.get::<_, bool>(0))? {
+            let size = dir_size(&dir);
+            println!(
+                "{} {} (no file_id={}, {})",
+                if dry_run { "Would remove" } else { "Removing" },
+                dir.display(),
+                file_id,
+                format_size(size as i64)
+            );
+            if !dry_run {
+                fs::remove_dir_all(&dir)?;
+            }
+            count += 1;
+            bytes += size;
+            continue;
+        }
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(idx) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".chunk"))
+                .and_then(|n| n.parse::<i64>().ok())
+            else {
+                continue;
+            };
+            if chunk_exists.query_row(params![file_id, idx], |row| row.get::<_, bool>(0))? {
+                continue;
+            }
+            let size = path.metadata()?.len();
+            println!(
+                "{} {} ({})",
+                if dry_run { "Would remove" } else { "Removing" },
+                path.display(),
+                format_size(size as i64)
+            );
+            if !dry_run {
+                fs::remove_file(&path)?;
+            }
+            count += 1;
+            bytes += size;
+        }
+    }
+    Ok((count, bytes))
+}
+
+/// Delete `storage/{file_id}/` after checking that every `file_chunks` row of
+/// the file has a URL, so nothing is lost that isn't already on Discord.
+/// Returns how many bytes were freed.
+pub fn truncate_storage(conn: &Connection, file_id: i64) -> Result<u64> {
+    let (chunks, without_url): (i64, i64) = conn.query_row(
+        "SELECT COUNT(*), COUNT(*) FILTER (WHERE url IS NULL OR url = '')
+         FROM file_chunks WHERE file_id = ?1",
+        params![file_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    if chunks == 0 {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM files WHERE id = ?1)",
+            params![file_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            bail!("no file with id {file_id}");
+        }
+    }
+    if without_url > 0 {
+        bail!(
+            "{without_url} of {chunks} chunk(s) of file_id={file_id} have no URL; keeping local storage"
+        );
+    }
+    let dir = storage_dir(file_id);
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let size = dir_size(&dir);
+    fs::remove_dir_all(&dir).with_context(|| format!("removing {}", dir.display()))?;
+    info!(file_id, "removed {}", dir.display());
+    Ok(size)
+}
+
+/// `truncate_storage` every `complete` file that still has local storage.
+/// Returns how many were truncated and the bytes freed.
+pub fn truncate_all_storage(conn: &Connection) -> Result<(usize, u64)> {
+    let mut stmt = conn.prepare("SELECT id FROM files WHERE status = 'complete' ORDER BY id")?;
+    let file_ids = stmt
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let (mut count, mut freed) = (0, 0);
+    for file_id in file_ids {
+        if !storage_dir(file_id).exists() {
+            continue;
+        }
+        freed += truncate_storage(conn, file_id)?;
+        count += 1;
+    }
+    Ok((count, freed))
+}
+
+/// Total size of the regular files under `dir`.
+fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// `{file_id}.cancel` next to the database: while it exists, an upload of that
+/// file stops before its next chunk.
+fn cancel_sentinel(conn: &Connection, file_id: i64) -> PathBuf {
+    let dir = conn
+        .path()
+        .and_then(|db| Path::new(db).parent())
+        .unwrap_or(Path::new("."));
+    dir.join(format!("{file_id}.cancel"))
+}
+
+/// `cancel`: ask whichever process is uploading `file_id` to stop after the
+/// chunks already in flight. Returns the sentinel file created.
+pub fn request_cancel(conn: &Connection, file_id: i64) -> Result<PathBuf> {
+    let status: String = conn
+        .query_row(
+            "SELECT status FROM files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    if status == "complete" {
+        bail!("file_id={file_id} is already complete");
+    }
+    let sentinel = cancel_sentinel(conn, file_id);
+    fs::write(&sentinel, b"")
+        .with_context(|| format!("failed to create {}", sentinel.display()))?;
+    Ok(sentinel)
+}
+
+fn storage_dir(file_id: i64) -> PathBuf {
+    PathBuf::from("storage").join(file_id.to_string())
+}
+
+/// Fill `buf` from `f`, returning fewer bytes than its length only at end of file.
+async fn read_chunk<R: AsyncRead + Unpin>(f: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = f.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Full-jitter exponential backoff: a random wait between zero and
+/// `min(BACKOFF_CAP, BACKOFF_BASE * 2^attempt)`.
+fn backoff_delay(attempt: u32) -> Duration {
+    let ceiling = BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(BACKOFF_CAP);
+    Duration::from_millis(rand::rng().random_range(0..=ceiling.as_millis() as u64))
+}
+
+/// Upload a chunk as `{idx}.chunk`, starting at webhook `idx % webhooks.len()` and moving on to
+/// the next webhook when one is rate limited. Returns the index of the webhook that
+/// accepted the chunk together with the Discord message id and attachment URL.
+/// Every upload, successful or not, is followed by a random `upload_delay` pause.
+async fn upload_chunk_with_retry(
+    client: &Client,
+    webhooks: &[String],
+    data: bytes::Bytes,
+    idx: usize,
+    max_retries: u32,
+    pacing: &UploadConfig,
+    sent: &Arc<AtomicU64>,
+) -> Result<(usize, (String, String))> {
+    let uploaded = post_chunk(client, webhooks, &data, idx, max_retries, pacing, sent).await;
+    // Spread requests out so a run of chunks doesn't trip the rate limit
+    tokio::time::sleep(pacing.upload_pause()).await;
+    uploaded
+}
+
+/// The message id and attachment URL from the reply to a webhook upload.
+fn parse_upload_response(body: &str) -> Result<(String, String)> {
+    let json: serde_json::Value = serde_json::from_str(body)
+        .with_context(|| format!("Discord's response is not JSON: {body}"))?;
+    let message_id = json["id"]
+        .as_str()
+        .with_context(|| format!("Discord's response has no message id: {json}"))?;
+    let url = json["attachments"][0]["url"]
+        .as_str()
+        .with_context(|| format!("Discord's response has no attachment URL: {json}"))?;
+    Ok((message_id.to_string(), url.to_string()))
+}
+
+/// The bytes one upload attempt has added to the shared `sent` counter.
+/// Unless the attempt is `kept`, they are taken off again when it is dropped,
+/// so a chunk that is retried or moved to another webhook counts only once.
+struct AttemptBytes {
+    sent: Arc<AtomicU64>,
+    streamed: Arc<AtomicU64>,
+    kept: bool,
+}
+
+impl AttemptBytes {
+    fn new(sent: &Arc<AtomicU64>) -> Self {
+        Self {
+            sent: Arc::clone(sent),
+            streamed: Arc::new(AtomicU64::new(0)),
+            kept: false,
+        }
+    }
+
+    /// Stream `data` in `UPLOAD_PIECE` slices, counting each one as reqwest
+    /// takes it.
+    fn body(&self, data: bytes::Bytes) -> reqwest::Body {
+        let (sent, streamed) = (Arc::clone(&self.sent), Arc::clone(&self.streamed));
+        let pieces = (0..data.len()).step_by(UPLOAD_PIECE).map(move |start| {
+            let piece = data.slice(start..data.len().min(start + UPLOAD_PIECE));
+            sent.fetch_add(piece.len() as u64, Ordering::Relaxed);
+            streamed.fetch_add(piece.len() as u64, Ordering::Relaxed);
+            Ok::<_, std::io::Error>(piece)
+        });
+        reqwest::Body::wrap_stream(futures_util::stream::iter(pieces))
+    }
+}
+
+impl Drop for AttemptBytes {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.sent
+                .fetch_sub(self.streamed.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+}
+
+/// The retry loop behind `upload_chunk_with_retry`.
+async fn post_chunk(
+    client: &Client,
+    webhooks: &[String],
+    data: &bytes::Bytes,
+    idx: usize,
+    max_retries: u32,
+    pacing: &UploadConfig,
+    sent: &Arc<AtomicU64>,
+) -> Result<(usize, (String, String))> {
+    let file_name = format!("{}.chunk", idx);
+    let mut webhook_idx = idx % webhooks.len();
+    let mut rate_limited = 0;
+    let mut rate_limit_rounds = 0;
+    let mut attempts = 0;
+    loop {
+        let mut counted = AttemptBytes::new(sent);
+        let part =
+            multipart::Part::stream_with_length(counted.body(data.clone()), data.len() as u64)
+                .file_name(file_name.clone());
+        let form = multipart::Form::new().part("file", part);
+        let resp = client
+            .post(&webhooks[webhook_idx])
+            .multipart(form)
+            .send()
+            .await;
+
+        let failure = match resp {
+            Ok(r) if r.status().as_u16() == 429 => {
+                drop(counted);
+                webhook_idx = (webhook_idx + 1) % webhooks.len();
+                rate_limited += 1;
+                if rate_limited < webhooks.len() {
+                    // Try the next webhook straight away
+                    info!(
+                        chunk = idx,
+                        webhook = webhook_idx,
+                        "rate limited, switching webhook"
+                    );
+                    continue;
+                }
+
+                // Every webhook is rate limited, back off and retry
+                rate_limited = 0;
+                if rate_limit_rounds >= max_retries {
+                    bail!(
+                        "uploading chunk {idx}: every webhook was still rate limited after {} round(s)",
+                        rate_limit_rounds + 1
+                    );
+                }
+                rate_limit_rounds += 1;
+                let delay = pacing.rate_limit_pause();
+                debug!(
+                    chunk = idx,
+                    "rate limited on every webhook, sleeping {:?}", delay
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            // Discord's side failed; the same upload may well go through later
+            Ok(r) if r.status().is_server_error() => {
+                let status = r.status();
+                let body = r.text().await.unwrap_or_default();
+                anyhow!("uploading chunk {idx} (HTTP {status}): {body}")
+            }
+            Ok(r) => {
+                let status = r.status();
+                match r.text().await {
+                    Ok(body) => {
+                        // Anything else Discord didn't accept (a 4xx) fails the same
+                        // way every time, so it isn't retried
+                        let (message_id, url) = parse_upload_response(&body).map_err(|e| {
+                            debug!(chunk = idx, %status, body, "unexpected upload response");
+                            e.context(format!("uploading chunk {idx} (HTTP {status})"))
+                        })?;
+                        info!(
+                            chunk = idx,
+                            webhook = webhook_idx,
+                            message_id,
+                            "uploaded chunk"
+                        );
+                        counted.kept = true;
+                        return Ok((webhook_idx, (message_id, url)));
+                    }
+                    Err(e) => anyhow::Error::new(e).context(format!(
+                        "reading the response to chunk {idx} (HTTP {status})"
+                    )),
+                }
+            }
+            Err(e) => e.into(),
+        };
+        drop(counted);
+
+        if attempts >= max_retries {
+            return Err(failure);
+        }
+        attempts += 1;
+        let delay = backoff_delay(attempts);
+        debug!(
+            chunk = idx,
+            attempt = attempts,
+            max_retries,
+            "upload failed: {:#}. Retrying in {:?}",
+            failure,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// What Discord reports about a webhook, as shown by `webhook-info`
+#[derive(Serialize)]
+pub struct WebhookInfoRow {
+    id: String,
+    name: String,
+    channel_id: String,
+    guild_id: String,
+    has_token: bool,
+}
+
+impl fmt::Display for WebhookInfoRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} name={} channel_id={} guild_id={} token={}",
+            self.id,
+            self.name,
+            self.channel_id,
+            self.guild_id,
+            if self.has_token { "present" } else { "missing" }
+        )
+    }
+}
+
+/// GET the webhook `url` and read its metadata, explaining the usual failures.
+pub async fn webhook_info(client: &Client, url: &str) -> Result<WebhookInfoRow> {
+    let webhook = Webhook::parse(url)?;
+    let resp = client
+        .get(url)
+        .send()
+        .await
+        .with_context(|| format!("could not reach webhook {}", webhook.id))?;
+    match resp.status().as_u16() {
+        401 => bail!(
+            "webhook {}: the token in the URL is invalid (401)",
+            webhook.id
+        ),
+        404 => bail!(
+            "webhook {}: Discord doesn't know this webhook (404); it may have been deleted",
+            webhook.id
+        ),
+        _ => {}
+    }
+    let json: serde_json::Value = resp
+        .error_for_status()
+        .with_context(|| format!("looking up webhook {}", webhook.id))?
+        .json()
+        .await
+        .with_context(|| format!("webhook {} did not return JSON", webhook.id))?;
+    let field = |key: &str| match &json[key] {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => "-".to_string(),
+        other => other.to_string(),
+    };
+    Ok(WebhookInfoRow {
+        id: field("id"),
+        name: field("name"),
+        channel_id: field("channel_id"),
+        guild_id: field("guild_id"),
+        has_token: json["token"].as_str().is_some_and(|t| !t.is_empty()),
+    })
+}
+
+/// A `quotas` row as shown by `show-quota`
+#[derive(Serialize)]
+pub struct QuotaRow {
+    webhook_id: String,
+    max_messages: i64,
+    used_messages: i64,
+}
+
+impl fmt::Display for QuotaRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "webhook={} used={}/{}",
+            self.webhook_id, self.used_messages, self.max_messages
+        )
+    }
+}
+
+impl QuotaRow {
+    fn new(url: &str, max_messages: i64, used_messages: i64) -> Self {
+        // keep the token out of listings
+        let webhook_id = Webhook::parse(url).map_or_else(|_| url.to_string(), |w| w.id);
+        Self {
+            webhook_id,
+            max_messages,
+            used_messages,
+        }
+    }
+}
+
+/// Create or change `webhook`'s message limit, keeping the count so far.
+pub fn set_quota(conn: &Connection, webhook: &str, max: u64) -> Result<QuotaRow> {
+    Webhook::parse(webhook)?;
+    let max = i64::try_from(max).context("--max is too large")?;
+    conn.execute(
+        "INSERT INTO quotas (webhook_url, max_messages) VALUES (?1, ?2)
+         ON CONFLICT(webhook_url) DO UPDATE SET max_messages = excluded.max_messages",
+        params![webhook, max],
+    )?;
+    let used: i64 = conn.query_row(
+        "SELECT used_messages FROM quotas WHERE webhook_url = ?1",
+        params![webhook],
+        |row| row.get(0),
+    )?;
+    Ok(QuotaRow::new(webhook, max, used))
+}
+
+/// All quotas by webhook
+pub fn list_quotas(conn: &Connection) -> Result<Vec<QuotaRow>> {
+    let mut stmt = conn.prepare(
+        "SELECT webhook_url, max_messages, used_messages FROM quotas ORDER BY webhook_url",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(QuotaRow::new(
+                &row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Count one more message against `webhook`'s quota, if it has one. Fails
+/// without counting it when the quota is used up.
+fn reserve_quota(conn: &Connection, webhook: &str) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let quota: Option<(i64, i64)> = tx
+        .query_row(
+            "SELECT max_messages, used_messages FROM quotas WHERE webhook_url = ?1",
+            params![webhook],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    if let Some((max, used)) = quota {
+        if used >= max {
+            let row = QuotaRow::new(webhook, max, used);
+            bail!(
+                "message quota of webhook {} is used up ({}/{}); raise it with `set-quota`",
+                row.webhook_id,
+                used,
+                max
+            );
+        }
+        tx.execute(
+            "UPDATE quotas SET used_messages = used_messages + 1 WHERE webhook_url = ?1",
+            params![webhook],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Give back one message of `webhook`'s quota, e.g. after deleting it.
+fn release_quota(conn: &Connection, webhook: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE quotas SET used_messages = MAX(used_messages - 1, 0) WHERE webhook_url = ?1",
+        params![webhook],
+    )?;
+    Ok(())
+}
+
+/// Square the quota reserved for chunk `idx` on its first-choice webhook
+/// with where the upload actually went: handed back if it failed, moved if
+/// a rate limit sent it to another webhook.
+fn settle_quota(
+    conn: &Connection,
+    webhooks: &[String],
+    idx: usize,
+    landed: Option<usize>,
+) -> Result<()> {
+    let reserved = idx % webhooks.len();
+    if landed == Some(reserved) {
+        return Ok(());
+    }
+    release_quota(conn, &webhooks[reserved])?;
+    if let Some(landed) = landed {
+        conn.execute(
+            "UPDATE quotas SET used_messages = used_messages + 1 WHERE webhook_url = ?1",
+            params![webhooks[landed]],
+        )?;
+    }
+    Ok(())
+}
+
+/// Check that every webhook answers a GET with its metadata, as Discord does,
+/// so a typo fails before any chunk is read instead of mid-upload.
+pub async fn validate_webhooks(client: &Client, webhooks: &[String]) -> Result<()> {
+    for url in webhooks {
+        let resp = client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("Webhook URL appears invalid: could not reach {url}"))?;
+        let status = resp.status();
+        if !status.is_success() {
+            bail!(
+                "Webhook URL appears invalid: received {} from {}",
+                status.as_u16(),
+                url
+            );
+        }
+        let json: serde_json::Value = resp
+            .json()
+            .await
+            .with_context(|| format!("Webhook URL appears invalid: {url} did not return JSON"))?;
+        if json["id"].as_str().is_none() {
+            bail!("Webhook URL appears invalid: {url} returned no webhook id");
+        }
+    }
+    Ok(())
+}
+
+/// A Discord webhook URL split into the parts needed to address its messages
+#[derive(Clone)]
+struct Webhook {
+    /// Everything before `/webhooks/`, e.g. `https://discord.com/api`
+    base: String,
+    id: String,
+    token: String,
+}
+
+impl Webhook {
+    pub fn parse(url: &str) -> Result<Self> {
+        let parsed =
+            reqwest::Url::parse(url).with_context(|| format!("invalid webhook URL: {url}"))?;
+        let segments: Vec<&str> = parsed
+            .path_segments()
+            .map(|s| s.collect())
+            .unwrap_or_default();
+        let pos = segments
+            .iter()
+            .position(|s| *s == "webhooks")
+            .with_context(|| format!("webhook URL has no /webhooks/ segment: {url}"))?;
+        let (Some(id), Some(token)) = (segments.get(pos + 1), segments.get(pos + 2)) else {
+            bail!("webhook URL is missing its id or token: {url}");
+        };
+
+        let mut base = parsed.origin().ascii_serialization();
+        for segment in &segments[..pos] {
+            base.push('/');
+            base.push_str(segment);
+        }
+        Ok(Self {
+            base,
+            id: id.to_string(),
+            token: token.to_string(),
+        })
+    }
+
+    fn message_url(&self, message_id: &str) -> String {
+        format!(
+            "{}/webhooks/{}/{}/messages/{}",
+            self.base, self.id, self.token, message_id
+        )
+    }
+}
+
+async fn delete_message(
+    client: &Client,
+    webhook: &Webhook,
+    message_id: &str,
+    pacing: &UploadConfig,
+) -> Result<()> {
+    loop {
+        let resp = client
+            .delete(webhook.message_url(message_id))
+            .send()
+            .await?;
+        match resp.status().as_u16() {
+            429 => {
+                let delay = pacing.rate_limit_pause();
+                info!(message_id, "rate limited, sleeping {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+            // already gone, nothing left to clean up
+            404 => {
+                warn!(message_id, "not found on Discord, skipping");
+                return Ok(());
+            }
+            _ if resp.status().is_success() => {
+                info!(message_id, "deleted Discord message");
+                return Ok(());
+            }
+            _ => {
+                resp.error_for_status()
+                    .with_context(|| format!("deleting Discord message {message_id}"))?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The current attachment URL of a chunk's message. `None` if the message is gone.
+async fn fetch_message_url(
+    client: &Client,
+    webhook: &Webhook,
+    message_id: &str,
+    pacing: &UploadConfig,
+) -> Result<Option<String>> {
+    loop {
+        let resp = client.get(webhook.message_url(message_id)).send().await?;
+        match resp.status().as_u16() {
+            429 => {
+                let delay = pacing.rate_limit_pause();
+                info!(message_id, "rate limited, sleeping {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+            404 => return Ok(None),
+            _ => {
+                let body = resp
+                    .error_for_status()
+                    .with_context(|| format!("fetching Discord message {message_id}"))?
+                    .text()
+                    .await?;
+                let (_, url) = parse_upload_response(&body)?;
+                return Ok(Some(url));
+            }
+        }
+    }
+}
+
+/// Fetch each chunk's message again and store its new attachment URL, since
+/// Discord CDN URLs expire. Chunks whose message is gone are skipped with a
+/// warning. Returns how many chunks were refreshed.
+async fn refresh_chunks(
+    conn: &Connection,
+    chunks: &[(i64, i64, String, usize)],
+    webhooks: &[String],
+    pacing: &UploadConfig,
+) -> Result<usize> {
+    let parsed = webhooks
+        .iter()
+        .map(|url| Webhook::parse(url))
+        .collect::<Result<Vec<_>>>()?;
+    let client = pacing.client()?;
+    let mut refreshed = 0;
+    for (file_id, idx, message_id, webhook_idx) in chunks {
+        let webhook = parsed.get(*webhook_idx).with_context(|| {
+            format!(
+                "message {message_id} was posted via webhook #{webhook_idx} but only {} --webhook given",
+                parsed.len()
+            )
+        })?;
+        let Some(url) = fetch_message_url(&client, webhook, message_id, pacing).await? else {
+            warn!(
+                file_id,
+                idx, message_id, "message not found on Discord, skipping"
+            );
+            continue;
+        };
+        conn.execute(
+            "UPDATE file_chunks SET url = ?1, last_refreshed_at = datetime('now')
+             WHERE file_id = ?2 AND idx = ?3",
+            params![url, file_id, idx],
+        )?;
+        refreshed += 1;
+    }
+    Ok(refreshed)
+}
+
+/// `refresh`: renew the URL of every chunk of a file. Returns how many were refreshed.
+pub async fn refresh_file(
+    conn: &Connection,
+    file_id: i64,
+    webhooks: &[String],
+    pacing: &UploadConfig,
+) -> Result<usize> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM files WHERE id = ?1)",
+        params![file_id],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        bail!("no file with id {file_id}");
+    }
+    let mut stmt = conn.prepare(
+        "SELECT file_id, idx, message_id, webhook_idx FROM file_chunks
+         WHERE file_id = ?1 AND url NOT LIKE 'local://%' ORDER BY idx",
+    )?;
+    let chunks = stmt
+        .query_map(params![file_id], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, i64>(3)? as usize,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    drop(stmt);
+    if chunks.is_empty() {
+        bail!("file_id={file_id} has no chunks on Discord");
+    }
+    refresh_chunks(conn, &chunks, webhooks, pacing).await
+}
+
+/// `export-url`: the stored URL of one chunk, after fetching its message again
+/// first when `refresh` gives the webhooks and pacing to do so.
+pub async fn chunk_url(
+    conn: &Connection,
+    file_id: i64,
+    idx: i64,
+    refresh: Option<(&[String], &UploadConfig)>,
+) -> Result<String> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM files WHERE id = ?1)",
+        params![file_id],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        bail!("no file with id {file_id}");
+    }
+    let (url, message_id, webhook_idx): (String, String, i64) = conn
+        .query_row(
+            "SELECT url, message_id, webhook_idx FROM file_chunks WHERE file_id = ?1 AND idx = ?2",
+            params![file_id, idx],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?
+        .with_context(|| format!("file_id={file_id} has no chunk {idx}"))?;
+    let Some((webhooks, pacing)) = refresh else {
+        return Ok(url);
+    };
+    if url.starts_with(LOCAL_URL_PREFIX) {
+        bail!("chunk {idx} of file_id={file_id} is stored locally, not on Discord");
+    }
+    let chunks = [(file_id, idx, message_id, webhook_idx as usize)];
+    if refresh_chunks(conn, &chunks, webhooks, pacing).await? == 0 {
+        bail!("the Discord message of chunk {idx} of file_id={file_id} is gone");
+    }
+    Ok(conn.query_row(
+        "SELECT url FROM file_chunks WHERE file_id = ?1 AND idx = ?2",
+        params![file_id, idx],
+        |row| row.get(0),
+    )?)
+}
+
+/// `refresh-all`: renew the URL of every chunk not refreshed in the last
+/// `older_than_hours` hours, including chunks never refreshed at all.
+/// Returns how many were refreshed.
+pub async fn refresh_all(
+    conn: &Connection,
+    older_than_hours: u32,
+    webhooks: &[String],
+    pacing: &UploadConfig,
+) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT file_id, idx, message_id, webhook_idx FROM file_chunks
+         WHERE (last_refreshed_at IS NULL OR last_refreshed_at <= datetime('now', ?1))
+           AND url NOT LIKE 'local://%'
+         ORDER BY file_id, idx",
+    )?;
+    let chunks = stmt
+        .query_map(params![format!("-{older_than_hours} hours")], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, i64>(3)? as usize,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    drop(stmt);
+    refresh_chunks(conn, &chunks, webhooks, pacing).await
+}
+
+/// Replace the Discord copy of chunks with a fresh upload of the local
+/// `storage/{file_id}/{idx}.chunk` file, deleting the old message first.
+/// Returns how many chunks were re-uploaded.
+pub async fn reupload_chunks(
+    conn: &mut Connection,
+    file_id: i64,
+    chunk_idx: Option<i64>,
+    webhooks: &[String],
+    transfer: &TransferOptions,
+) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT idx, message_id, webhook_idx FROM file_chunks
+         WHERE file_id = ?1 AND (?2 IS NULL OR idx = ?2) ORDER BY idx",
+    )?;
+    let chunks = stmt
+        .query_map(params![file_id, chunk_idx], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)? as usize,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    drop(stmt);
+    if chunks.is_empty() {
+        match chunk_idx {
+            Some(idx) => bail!("file_id={file_id} has no chunk {idx}"),
+            None => bail!("file_id={file_id} has no chunks"),
+        }
+    }
+
+    let parsed = webhooks
+        .iter()
+        .map(|url| Webhook::parse(url))
+        .collect::<Result<Vec<_>>>()?;
+    let dir = storage_dir(file_id);
+    if !dir.is_dir() {
+        bail!(
+            "file_id={file_id} has no local chunks in {}; it was ingested with --no-local-storage or its storage was truncated, so it can't be re-uploaded",
+            dir.display()
+        );
+    }
+    let client = transfer.pacing.client()?;
+    let semaphore = Arc::new(Semaphore::new(transfer.concurrency));
+    let mut tasks = JoinSet::new();
+    let meter = ByteMeter::start(None, &transfer.progress);
+
+    for (idx, old_message_id, old_webhook_idx) in chunks {
+        if old_message_id.is_empty() {
+            warn!(chunk = idx, "not on Discord, skipping");
+            continue;
+        }
+        let chunk_path = dir.join(format!("{}.chunk", idx));
+        if !chunk_path.exists() {
+            warn!(
+                chunk = idx,
+                "no local copy at {}, skipping",
+                chunk_path.display()
+            );
+            continue;
+        }
+        let Some(old_webhook) = parsed.get(old_webhook_idx).cloned() else {
+            warn!(
+                chunk = idx,
+                "posted via webhook #{} but only {} --webhook given, skipping",
+                old_webhook_idx,
+                parsed.len()
+            );
+            continue;
+        };
+        if let Err(e) = reserve_quota(conn, &webhooks[idx as usize % webhooks.len()]) {
+            error!(chunk = idx, "{:#}, skipping", e);
+            continue;
+        }
+
+        let permit = Arc::clone(&semaphore).acquire_owned().await?;
+        let client = client.clone();
+        let webhooks = webhooks.to_vec();
+        let max_retries = transfer.max_retries;
+        let pacing = transfer.pacing.clone();
+        let sent = meter.counter();
+        // Besides the upload, reports whether the old message is gone, so
+        // the quotas can be squared once the task is joined
+        tasks.spawn(async move {
+            let _permit = permit;
+            let data = match tokio::fs::read(&chunk_path).await {
+                Ok(data) => data,
+                Err(e) => {
+                    error!(
+                        chunk = idx,
+                        "failed to read {}: {}",
+                        chunk_path.display(),
+                        e
+                    );
+                    return (idx, None, None);
+                }
+            };
+            if let Err(e) = delete_message(&client, &old_webhook, &old_message_id, &pacing).await {
+                error!(chunk = idx, "failed to delete old message: {}", e);
+                return (idx, None, None);
+            }
+            let uploaded = match upload_chunk_with_retry(
+                &client,
+                &webhooks,
+                data.into(),
+                idx as usize,
+                max_retries,
+                &pacing,
+                &sent,
+            )
+            .await
+            {
+                Ok(res) => Some(res),
+                Err(e) => {
+                    error!(chunk = idx, "upload failed permanently: {}", e);
+                    None
+                }
+            };
+            (idx, Some((old_webhook_idx, old_message_id)), uploaded)
+        });
+    }
+
+    let mut count = 0;
+    while let Some(res) = tasks.join_next().await {
+        let (idx, deleted, uploaded) = res?;
+        let landed = uploaded.as_ref().map(|(webhook_idx, _)| *webhook_idx);
+        settle_quota(conn, webhooks, idx as usize, landed)?;
+        let Some((old_webhook_idx, old_message_id)) = deleted else {
+            continue;
+        };
+        release_quota(conn, &webhooks[old_webhook_idx])?;
+        let Some((webhook_idx, (message_id, url))) = uploaded else {
+            continue;
+        };
+        // Copies made with `copy-file` share the message, so they move too
+        conn.execute(
+            "UPDATE file_chunks SET url = ?1, message_id = ?2, webhook_idx = ?3
+             WHERE message_id = ?4",
+            params![url, message_id, webhook_idx as i64, old_message_id],
+        )?;
+        count += 1;
+    }
+    meter.finish();
+    audit(
+        conn,
+        "reupload",
+        "file",
+        file_id,
+        &format!("re-uploaded {} chunk(s)", count),
+    )?;
+    Ok(count)
+}
+
+/// Deletes a file's messages from Discord, then its record; `dry_run` only reports
+pub async fn delete_file(
+    conn: &mut Connection,
+    file_id: i64,
+    webhooks: &[String],
+    pacing: &UploadConfig,
+    keep_local: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let (filename, filesize, chunk_size): (String, i64, i64) = conn
+        .query_row(
+            "SELECT filename, filesize, chunk_size FROM files WHERE id = ?1",
+            params![file_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+
+    // Messages still referenced by a copy (see `copy_file`) stay on Discord
+    let mut stmt = conn.prepare(
+        "SELECT message_id, webhook_idx FROM file_chunks c
+         WHERE file_id = ?1 AND url NOT LIKE 'local://%' AND NOT EXISTS (
+             SELECT 1 FROM file_chunks o
+             WHERE o.message_id = c.message_id AND o.file_id != c.file_id
+         )
+         ORDER BY idx",
+    )?;
+    let messages = stmt
+        .query_map(params![file_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    drop(stmt);
+
+    let dir = storage_dir(file_id);
+    let remove_local = !keep_local && dir.exists();
+
+    if dry_run {
+        let (chunks, missing_ids): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(message_id = '' AND url NOT LIKE 'local://%'), 0)
+             FROM file_chunks WHERE file_id = ?1",
+            params![file_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if missing_ids > 0 {
+            bail!("file_id={file_id} has {missing_ids} chunk(s) without a Discord message id");
+        }
+        if let Some((message_id, webhook_idx)) =
+            messages.iter().find(|(_, idx)| *idx >= webhooks.len())
+        {
+            bail!(
+                "message {message_id} was posted via webhook #{webhook_idx} but only {} --webhook given",
+                webhooks.len()
+            );
+        }
+        let expected = (filesize + chunk_size - 1) / chunk_size.max(1);
+        println!(
+            "Would delete file_id={} '{}' ({}, {} of {} chunk(s)) and {} Discord message(s)",
+            file_id,
+            filename,
+            format_size(filesize),
+            chunks,
+            expected,
+            messages.len()
+        );
+        for (message_id, webhook_idx) in &messages {
+            println!(
+                "  Discord message {} (webhook #{})",
+                message_id, webhook_idx
+            );
+        }
+        if remove_local {
+            println!("  Local directory {}", dir.display());
+        }
+        return Ok(());
+    }
+
+    let parsed = webhooks
+        .iter()
+        .map(|url| Webhook::parse(url))
+        .collect::<Result<Vec<_>>>()?;
+
+    let client = pacing.client()?;
+    let targets = messages
+        .iter()
+        .map(|(message_id, webhook_idx)| {
+            let webhook = parsed.get(*webhook_idx).with_context(|| {
+                format!(
+                    "message {message_id} was posted via webhook #{webhook_idx} but only {} --webhook given",
+                    parsed.len()
+                )
+            })?;
+            Ok((message_id, webhook))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // The rows go only once every Discord message is gone, so after a network
+    // failure part way through running `delete` again finishes the job (messages
+    // already deleted count as done). No transaction is held open meanwhile.
+    for (message_id, webhook) in targets {
+        delete_message(&client, webhook, message_id, pacing).await?;
+    }
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM file_chunks WHERE file_id = ?1",
+        params![file_id],
+    )?;
+    unlink_version(&tx, file_id)?;
+    tx.execute("DELETE FROM files WHERE id = ?1", params![file_id])?;
+    for (_, webhook_idx) in &messages {
+        release_quota(&tx, &webhooks[*webhook_idx])?;
+    }
+    audit(
+        &tx,
+        "delete",
+        "file",
+        file_id,
+        &format!(
+            "deleted '{}' and {} Discord message(s)",
+            filename,
+            messages.len()
+        ),
+    )?;
+    tx.commit()?;
+
+    if remove_local {
+        fs::remove_dir_all(&dir)?;
+    }
+    println!("Deleted file_id={} '{}'", file_id, filename);
+    Ok(())
+}
+
+/// Soft-delete (`deleted = true`) or undelete a file. Soft-deleted files keep
+/// their chunks and Discord messages until `purge-deleted` removes them.
+pub fn set_deleted(conn: &Connection, file_id: i64, deleted: bool) -> Result<()> {
+    let deleted_at: Option<String> = conn
+        .query_row(
+            "SELECT deleted_at FROM files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    match (deleted, deleted_at) {
+        (true, Some(at)) => bail!("file_id={file_id} was already soft-deleted at {at}"),
+        (false, None) => bail!("file_id={file_id} is not soft-deleted"),
+        (true, None) => conn.execute(
+            "UPDATE files SET deleted_at = datetime('now') WHERE id = ?1",
+            params![file_id],
+        )?,
+        (false, Some(_)) => conn.execute(
+            "UPDATE files SET deleted_at = NULL WHERE id = ?1",
+            params![file_id],
+        )?,
+    };
+    if deleted {
+        audit(conn, "soft-delete", "file", file_id, "soft-deleted")?;
+    } else {
+        audit(conn, "undelete", "file", file_id, "undeleted")?;
+    }
+    Ok(())
+}
+
+/// `delete_file` every file soft-deleted more than `older_than_days` days ago.
+/// Returns how many were (or with `dry_run`, would be) purged.
+pub async fn purge_deleted(
+    conn: &mut Connection,
+    older_than_days: u32,
+    webhooks: &[String],
+    pacing: &UploadConfig,
+    dry_run: bool,
+) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT id FROM files
+         WHERE deleted_at IS NOT NULL AND deleted_at <= datetime('now', ?1)
+         ORDER BY id",
+    )?;
+    let file_ids = stmt
+        .query_map(params![format!("-{older_than_days} days")], |row| {
+            row.get::<_, i64>(0)
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    drop(stmt);
+
+    for &file_id in &file_ids {
+        delete_file(conn, file_id, webhooks, pacing, false, dry_run).await?;
+    }
+    Ok(file_ids.len())
+}
+
+/// Files sharing the same whole-file SHA-256
+pub struct DuplicateGroup {
+    /// Hash the files share
+    pub sha256: String,
+    /// `(file_id, filename)`, lowest id first
+    pub files: Vec<(i64, String)>,
+}
+
+/// Groups of two or more files with identical content, ignoring soft-deleted
+/// files and files ingested before whole-file hashes were recorded.
+pub fn find_duplicates(conn: &Connection) -> Result<Vec<DuplicateGroup>> {
+    let mut stmt = conn.prepare(
+        "SELECT sha256, COUNT(*) c FROM files
+         WHERE sha256 IS NOT NULL AND deleted_at IS NULL
+         GROUP BY sha256 HAVING c > 1
+         ORDER BY MIN(id)",
+    )?;
+    let hashes = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT id, filename FROM files
+         WHERE sha256 = ?1 AND deleted_at IS NULL ORDER BY id",
+    )?;
+    hashes
+        .into_iter()
+        .map(|sha256| {
+            let files = stmt
+                .query_map(params![sha256], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(DuplicateGroup { sha256, files })
+        })
+        .collect()
+}
+
+/// Record a change in `audit_log`.
+fn audit(
+    conn: &Connection,
+    action: &str,
+    entity_type: &str,
+    entity_id: i64,
+    detail: &str,
+) -> Result<()> {
+    with_busy_retry(|| {
+        conn.execute(
+            "INSERT INTO audit_log (action, entity_type, entity_id, detail, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                action,
+                entity_type,
+                entity_id,
+                detail,
+                Utc::now().to_rfc3339()
+            ],
+        )
+    })?;
+    Ok(())
+}
+
+/// An `audit_log` row as shown by `audit-log`
+#[derive(Serialize)]
+pub struct AuditRow {
+    id: i64,
+    action: String,
+    entity_type: String,
+    entity_id: i64,
+    detail: String,
+    created_at: String,
+}
+
+impl fmt::Display for AuditRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<15} {} {}: {}",
+            self.created_at, self.action, self.entity_type, self.entity_id, self.detail
+        )
+    }
+}
+
+/// Audit log entries, newest first.
+pub fn audit_log(
+    conn: &Connection,
+    entity_type: Option<&str>,
+    entity_id: Option<i64>,
+    limit: Option<usize>,
+) -> Result<Vec<AuditRow>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, action, entity_type, entity_id, detail, created_at FROM audit_log
+         WHERE (?1 IS NULL OR entity_type = ?1) AND (?2 IS NULL OR entity_id = ?2)
+         ORDER BY id DESC LIMIT ?3",
+    )?;
+    let limit = limit.map_or(-1, |n| n as i64);
+    let rows = stmt
+        .query_map(params![entity_type, entity_id, limit], |row| {
+            Ok(AuditRow {
+                id: row.get(0)?,
+                action: row.get(1)?,
+                entity_type: row.get(2)?,
+                entity_id: row.get(3)?,
+                detail: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Retry limit for a locked database, read by the busy handler, which
+/// SQLite only lets us register as a plain function.
+static DB_RETRY_COUNT: AtomicU32 = AtomicU32::new(DEFAULT_DB_RETRY_COUNT);
+
+/// Set how many times a write to a locked database is retried before the
+/// "database is locked" error is returned (`--db-retry-count`).
+pub fn set_db_retry_count(count: u32) {
+    DB_RETRY_COUNT.store(count, Ordering::Relaxed);
+}
+
+/// Full-jitter exponential backoff for a locked database: a random wait
+/// between zero and `min(DB_BACKOFF_CAP, DB_BACKOFF_BASE * 2^attempt)`.
+fn db_backoff_delay(attempt: u32) -> Duration {
+    let ceiling = DB_BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(DB_BACKOFF_CAP);
+    Duration::from_millis(rand::rng().random_range(0..=ceiling.as_millis() as u64))
+}
+
+/// Called by SQLite while another connection holds the lock; returning
+/// `true` makes it try again.
+fn db_busy_handler(attempt: i32) -> bool {
+    let attempt = attempt.max(0) as u32;
+    if attempt >= DB_RETRY_COUNT.load(Ordering::Relaxed) {
+        return false;
+    }
+    let delay = db_backoff_delay(attempt);
+    debug!(attempt = attempt + 1, ?delay, "database is locked, waiting");
+    std::thread::sleep(delay);
+    true
+}
+
+/// Run `op` again when it fails with `SQLITE_BUSY`. The busy handler
+/// covers most lock waits, but SQLite skips it when waiting could
+/// deadlock, e.g. a read transaction upgrading to a write.
+fn with_busy_retry<T>(mut op: impl FnMut() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e)
+                if e.sqlite_error_code() == Some(rusqlite::ErrorCode::DatabaseBusy)
+                    && attempt < DB_RETRY_COUNT.load(Ordering::Relaxed) =>
+            {
+                let delay = db_backoff_delay(attempt);
+                attempt += 1;
+                debug!(attempt, ?delay, "database is locked, retrying write");
+                std::thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Open the database in WAL mode so concurrent invocations don't trip over
+/// each other's locks, backing off and retrying while the database is
+/// busy, up to `--db-retry-count` times. With a
+/// `passphrase` the database is encrypted with SQLCipher, which needs the
+/// `sqlcipher` feature.
+pub fn open_db(path: &Path, passphrase: Option<&Passphrase>) -> Result<Connection> {
+    // plain SQLite ignores `PRAGMA key`, which would leave the database unencrypted
+    #[cfg(not(feature = "sqlcipher"))]
+    if passphrase.is_some() {
+        bail!("--encrypt-db needs a build with the `sqlcipher` feature: cargo build --release --features sqlcipher");
+    }
+    let conn = Connection::open(path).with_context(|| format!("opening db: {}", path.display()))?;
+    // `PRAGMA key` has to come before anything else reads the database
+    #[cfg(feature = "sqlcipher")]
+    if let Some(passphrase) = passphrase {
+        conn.pragma_update(None, "key", passphrase.expose())?;
+    }
+    conn.busy_handler(Some(db_busy_handler))?;
+    with_busy_retry(|| {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;
+             PRAGMA foreign_keys = ON;",
+        )
+    })
+    .map_err(|e| match e.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::NotADatabase) => match passphrase {
+            Some(_) => anyhow!("wrong passphrase for {}", path.display()),
+            None => anyhow!(
+                "{} is not a SQLite database; if it is encrypted, pass --encrypt-db or set OCTO_DB_PASSPHRASE",
+                path.display()
+            ),
+        },
+        _ => e.into(),
+    })?;
+    Ok(conn)
+}
+
+/// A database connection checked out of `AppState`'s pool
+pub type PooledConnection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+/// A pool of connections to the database. The CLI opens the database
+/// through it and runs most commands on one checked-out connection; only
+/// `verify-all` checks out one per file being verified, while chunk uploads
+/// are still recorded through the handler's connection.
+pub struct AppState {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
+
+impl AppState {
+    /// A pool of up to `size` connections to `path`, each set up like
+    /// `open_db`. Connections are opened as they are needed.
+    pub fn open(path: &Path, passphrase: Option<&Passphrase>, size: u32) -> Result<Self> {
+        // turns on WAL and reports a wrong passphrase or a file that isn't a
+        // database with a proper message, before the pool gets to it
+        drop(open_db(path, passphrase)?);
+        #[cfg(feature = "sqlcipher")]
+        let passphrase = passphrase.cloned();
+        let manager = SqliteConnectionManager::file(path).with_init(move |conn| {
+            #[cfg(feature = "sqlcipher")]
+            if let Some(passphrase) = &passphrase {
+                conn.pragma_update(None, "key", passphrase.expose())?;
+            }
+            conn.busy_handler(Some(db_busy_handler))?;
+            conn.execute_batch(
+                "PRAGMA synchronous = NORMAL;
+                 PRAGMA foreign_keys = ON;",
+            )
+        });
+        let pool = r2d2::Pool::builder()
+            .max_size(size.max(1))
+            .min_idle(Some(1))
+            .build(manager)
+            .with_context(|| format!("opening db: {}", path.display()))?;
+        Ok(Self { pool })
+    }
+
+    /// `--db-in-memory`: like `open_memory_db`, but every connection in the
+    /// pool sees the same database, which lasts as long as the pool does.
+    pub fn memory(size: u32) -> Result<Self> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let uri = format!(
+            "file:octo-potato-{}-{}?mode=memory&cache=shared",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let manager = SqliteConnectionManager::file(uri)
+            .with_init(|conn| conn.execute_batch("PRAGMA foreign_keys = ON;"));
+        // the database goes away with its last connection, so keep one open
+        let pool = r2d2::Pool::builder()
+            .max_size(size.max(1))
+            .min_idle(Some(1))
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(manager)?;
+        Ok(Self { pool })
+    }
+
+    /// Check out a connection, waiting for one to be returned if all
+    /// `size` are in use.
+    pub fn conn(&self) -> Result<PooledConnection> {
+        Ok(self.pool.get()?)
+    }
+
+    /// How many connections the pool holds at most
+    pub fn size(&self) -> u32 {
+        self.pool.max_size()
+    }
+}
+
+/// `change-db-passphrase`: re-encrypt a database opened with its current
+/// passphrase under `new`.
+pub fn change_db_passphrase(conn: &Connection, new: &str) -> Result<()> {
+    if new.is_empty() {
+        bail!("the new passphrase must not be empty");
+    }
+    conn.pragma_update(None, "rekey", new)?;
+    Ok(())
+}
+
+/// An empty database that lives only as long as the connection (`--db-in-memory`).
+pub fn open_memory_db() -> Result<Connection> {
+    let conn = Connection::open_in_memory()?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    Ok(conn)
+}
+
+/// A fully migrated in-memory database, for tests anywhere in the crate.
+#[cfg(test)]
+pub(crate) fn in_memory_conn() -> Connection {
+    let mut conn = open_memory_db().unwrap();
+    init_schema(&mut conn).unwrap();
+    conn
+}
+
+/// Run SQLite's integrity and foreign key checks, printing every problem found.
+pub fn check_db(conn: &Connection) -> Result<()> {
+    let mut problems = 0;
+
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let messages = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    for message in messages.iter().filter(|m| m.as_str() != "ok") {
+        println!("integrity: {}", message);
+        problems += 1;
+    }
+
+    let mut stmt = conn.prepare("PRAGMA foreign_key_check")?;
+    let violations = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<i64>>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    for (table, rowid, parent) in &violations {
+        match rowid {
+            Some(rowid) => println!(
+                "foreign key: {} row {} references a missing {} row",
+                table, rowid, parent
+            ),
+            None => println!(
+                "foreign key: a {} row references a missing {} row",
+                table, parent
+            ),
+        }
+        problems += 1;
+    }
+
+    if problems > 0 {
+        bail!("database check found {} problem(s)", problems);
+    }
+    Ok(())
+}
+
+/// `MIGRATIONS[i]` upgrades the schema from version `i + 1` to `i + 2`.
+const MIGRATIONS: [fn(&Connection) -> Result<()>; (CURRENT_VERSION - 1) as usize] = [
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+    migrate_v5_to_v6,
+    migrate_v6_to_v7,
+    migrate_v7_to_v8,
+    migrate_v8_to_v9,
+    migrate_v9_to_v10,
+    migrate_v10_to_v11,
+    migrate_v11_to_v12,
+    migrate_v12_to_v13,
+    migrate_v13_to_v14,
+    migrate_v14_to_v15,
+    migrate_v15_to_v16,
+    migrate_v16_to_v17,
+    migrate_v17_to_v18,
+    migrate_v18_to_v19,
+    migrate_v19_to_v20,
+    migrate_v20_to_v21,
+    migrate_v21_to_v22,
+    migrate_v22_to_v23,
+    migrate_v23_to_v24,
+];
+
+/// Create the tables if they don't exist and bring an older schema up to
+/// `CURRENT_VERSION`, one migration per transaction.
+pub fn init_schema(conn: &mut Connection) -> Result<()> {
+    let fresh = !table_exists(conn, "files")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS files (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            filename TEXT NOT NULL,
+            filesize INTEGER NOT NULL,
+            chunk_size INTEGER NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_chunks (
+            file_id INTEGER NOT NULL,
+            idx INTEGER NOT NULL,
+            url TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            PRIMARY KEY(file_id, idx)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS directories (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            parent_id INTEGER,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY(parent_id) REFERENCES directories(id)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_versions (version INTEGER NOT NULL)",
+        [],
+    )?;
+    // databases from before versioning are treated as v1; the migrations
+    // only add what is missing, so columns added back then are left alone
+    conn.execute(
+        "INSERT INTO schema_versions (version)
+         SELECT 1 WHERE NOT EXISTS (SELECT 1 FROM schema_versions)",
+        [],
+    )?;
+
+    let version = schema_version(conn)?;
+    if version > CURRENT_VERSION {
+        bail!(
+            "database schema is v{version} but this build only understands up to v{CURRENT_VERSION}"
+        );
+    }
+    for (from, migrate) in (version..).zip(&MIGRATIONS[(version - 1) as usize..]) {
+        let tx = conn.transaction()?;
+        migrate(&tx)?;
+        tx.execute("UPDATE schema_versions SET version = ?1", params![from + 1])?;
+        tx.commit()?;
+        if !fresh {
+            info!("Migrated database schema from v{} to v{}", from, from + 1);
+        }
+    }
+    Ok(())
+}
+
+/// Tables dropped by `reset_schema`, children before the tables they reference
+const SCHEMA_TABLES: [&str; 8] = [
+    "file_tags",
+    "audit_log",
+    "file_chunks",
+    "files",
+    "tags",
+    "directories",
+    "quotas",
+    "schema_versions",
+];
+
+/// `init --force`: drop every table, losing all data, and create the schema
+/// again from scratch. Works whatever version the database was at.
+pub fn reset_schema(conn: &mut Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+    for table in SCHEMA_TABLES {
+        tx.execute(&format!("DROP TABLE IF EXISTS {table}"), [])?;
+    }
+    tx.commit()?;
+    warn!("dropped all tables");
+    init_schema(conn)
+}
+
+/// Fail unless the database is already at `CURRENT_VERSION` (`--skip-migration`).
+pub fn check_schema_version(conn: &Connection) -> Result<()> {
+    let version = if table_exists(conn, "schema_versions")? {
+        schema_version(conn)?
+    } else {
+        0
+    };
+    if version != CURRENT_VERSION {
+        bail!(
+            "database schema is v{version}, expected v{CURRENT_VERSION}; run without --skip-migration to upgrade it"
+        );
+    }
+    Ok(())
+}
+
+fn schema_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("SELECT version FROM schema_versions", [], |row| row.get(0))?)
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![table],
+        |row| row.get(0),
+    )?)
+}
+
+/// Chunk hashes and file directories.
+fn migrate_v1_to_v2(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "file_chunks", "sha256", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(
+        conn,
+        "files",
+        "directory_id",
+        "INTEGER REFERENCES directories(id)",
+    )?;
+    Ok(())
+}
+
+/// zstd-compressed chunks.
+fn migrate_v2_to_v3(conn: &Connection) -> Result<()> {
+    add_column_if_missing(
+        conn,
+        "file_chunks",
+        "compressed",
+        "BOOLEAN NOT NULL DEFAULT 0",
+    )?;
+    Ok(())
+}
+
+/// AES-GCM encrypted chunks, with the key size taken from the key file.
+fn migrate_v3_to_v4(conn: &Connection) -> Result<()> {
+    add_column_if_missing(
+        conn,
+        "file_chunks",
+        "encrypted",
+        "BOOLEAN NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(conn, "file_chunks", "nonce_hex", "TEXT")?;
+    Ok(())
+}
+
+/// Ingest status, so interrupted ingests can be resumed.
+fn migrate_v4_to_v5(conn: &Connection) -> Result<()> {
+    if add_column_if_missing(conn, "files", "status", "TEXT NOT NULL DEFAULT 'pending'")? {
+        // files ingested before status tracking: anything with all its chunks is complete
+        conn.execute(
+            "UPDATE files SET status = 'complete'
+             WHERE (SELECT COUNT(*) FROM file_chunks WHERE file_id = files.id)
+                   = (filesize + chunk_size - 1) / chunk_size",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Which webhook each chunk was posted through.
+fn migrate_v5_to_v6(conn: &Connection) -> Result<()> {
+    add_column_if_missing(
+        conn,
+        "file_chunks",
+        "webhook_idx",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    Ok(())
+}
+
+/// Size of each chunk as uploaded, after compression and encryption.
+fn migrate_v6_to_v7(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "file_chunks", "stored_size", "INTEGER")?;
+    Ok(())
+}
+
+/// File tags.
+fn migrate_v7_to_v8(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS tags (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE IF NOT EXISTS file_tags (
+            file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+            tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+            PRIMARY KEY(file_id, tag_id)
+        );",
+    )?;
+    Ok(())
+}
+
+/// Detected MIME type of each file.
+fn migrate_v8_to_v9(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "mime_type", "TEXT")?;
+    Ok(())
+}
+
+/// Free-form notes per file.
+fn migrate_v9_to_v10(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "description", "TEXT")?;
+    Ok(())
+}
+
+/// Soft deletion of files and directories.
+fn migrate_v10_to_v11(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "deleted_at", "TEXT")?;
+    add_column_if_missing(conn, "directories", "deleted_at", "TEXT")?;
+    Ok(())
+}
+
+/// SHA-256 of each whole file, for finding duplicates.
+fn migrate_v11_to_v12(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "sha256", "TEXT")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_sha256 ON files(sha256);")?;
+    Ok(())
+}
+
+/// Audit log of mutations.
+fn migrate_v12_to_v13(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY,
+            action TEXT NOT NULL,
+            entity_type TEXT NOT NULL,
+            entity_id INTEGER NOT NULL,
+            detail TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_audit_log_entity ON audit_log(entity_type, entity_id);",
+    )?;
+    Ok(())
+}
+
+/// Path each file was ingested from.
+fn migrate_v13_to_v14(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "original_path", "TEXT")?;
+    Ok(())
+}
+
+/// File version chains.
+fn migrate_v14_to_v15(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "version", "INTEGER NOT NULL DEFAULT 1")?;
+    add_column_if_missing(
+        conn,
+        "files",
+        "parent_file_id",
+        "INTEGER REFERENCES files(id)",
+    )?;
+    Ok(())
+}
+
+/// Which algorithm each file's hashes use.
+fn migrate_v15_to_v16(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "hash_algo", "TEXT NOT NULL DEFAULT 'sha256'")?;
+    Ok(())
+}
+
+/// Per-webhook message quotas.
+fn migrate_v16_to_v17(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS quotas (
+            webhook_url TEXT PRIMARY KEY,
+            max_messages INTEGER NOT NULL,
+            used_messages INTEGER NOT NULL DEFAULT 0
+        );",
+    )?;
+    Ok(())
+}
+
+/// When each chunk's URL was last fetched again by `refresh`.
+fn migrate_v17_to_v18(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "file_chunks", "last_refreshed_at", "TEXT")?;
+    Ok(())
+}
+
+/// How long each ingest took.
+fn migrate_v18_to_v19(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "ingest_duration_ms", "INTEGER")?;
+    Ok(())
+}
+
+/// Files whose chunks were registered by `import-from-dir --skip-upload`
+/// and only exist on local disk.
+fn migrate_v19_to_v20(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "local_only", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+/// How many `file_chunks` rows each file has, kept up to date by whatever
+/// inserts them.
+fn migrate_v20_to_v21(conn: &Connection) -> Result<()> {
+    if add_column_if_missing(conn, "files", "chunk_count", "INTEGER NOT NULL DEFAULT 0")? {
+        conn.execute(
+            "UPDATE files SET chunk_count =
+                 (SELECT COUNT(*) FROM file_chunks WHERE file_id = files.id)",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// How many bytes of the previous chunk each chunk repeats (`--chunk-overlap`).
+fn migrate_v21_to_v22(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "file_chunks", "overlap", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+/// Directory names are unique within their parent. Existing duplicates keep
+/// the oldest under its name; the others get their id appended. The root
+/// is parent 0 in the index, as NULLs never collide.
+fn migrate_v22_to_v23(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE directories SET name = name || ' (' || id || ')'
+         WHERE id NOT IN (
+             SELECT MIN(id) FROM directories GROUP BY COALESCE(parent_id, 0), name
+         )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS directories_parent_name
+         ON directories (COALESCE(parent_id, 0), name)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// How a file's chunks are encoded, so `resume` can upload the rest the same
+/// way. Files that already have chunks get it from them; it stays NULL
+/// (unknown) for the others.
+fn migrate_v23_to_v24(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "compressed", "INTEGER")?;
+    add_column_if_missing(conn, "files", "encrypted", "INTEGER")?;
+    add_column_if_missing(conn, "files", "chunk_overlap", "INTEGER")?;
+    conn.execute(
+        "UPDATE files SET
+             compressed = (SELECT MAX(compressed) FROM file_chunks WHERE file_id = files.id),
+             encrypted = (SELECT MAX(encrypted) FROM file_chunks WHERE file_id = files.id),
+             chunk_overlap = (SELECT MAX(overlap) FROM file_chunks WHERE file_id = files.id)
+         WHERE compressed IS NULL",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Add `column` to `table` unless it's already there. Returns whether it was added.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if columns.iter().any(|c| c == column) {
+        return Ok(false);
+    }
+    conn.execute(
+        &format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"),
+        [],
+    )?;
+    Ok(true)
+}
+
+/// Creates a directory under `parent_id`, or at the root; returns its id
+pub fn create_directory(conn: &mut Connection, name: &str, parent_id: Option<i64>) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO directories (name, parent_id, created_at) VALUES (?1, ?2, datetime('now'))",
+        params![name, parent_id],
+    )
+    .map_err(|e| dir_name_taken(e, name))?;
+    let dir_id = conn.last_insert_rowid();
+    audit(
+        conn,
+        "create",
+        "directory",
+        dir_id,
+        &format!("created '{}' in {}", name, describe_parent(parent_id)),
+    )?;
+    Ok(dir_id)
+}
+
+/// A readable error for an insert or update that would give two
+/// directories in the same parent the name `name`.
+fn dir_name_taken(e: rusqlite::Error, name: &str) -> anyhow::Error {
+    if e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation) {
+        anyhow!("A directory named '{name}' already exists in this parent")
+    } else {
+        e.into()
+    }
+}
+
+/// `directory 3`, or `the root` for `None`.
+fn describe_parent(dir_id: Option<i64>) -> String {
+    match dir_id {
+        Some(id) => format!("directory {id}"),
+        None => "the root".to_string(),
+    }
+}
+
+/// `(id, filename)` of the files in a directory, or at the root
+pub fn list_files_in_directory(
+    conn: &Connection,
+    dir_id: Option<i64>,
+) -> Result<Vec<(i64, String)>> {
+    let mut stmt = match dir_id {
+        Some(_) => {
+            conn.prepare("SELECT id, filename FROM files WHERE directory_id = ?1 ORDER BY id")
+        }
+        None => {
+            conn.prepare("SELECT id, filename FROM files WHERE directory_id IS NULL ORDER BY id")
+        }
+    }?;
+
+    let rows: Vec<(i64, String)> = match dir_id {
+        Some(did) => stmt
+            .query_map(params![did], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+        None => stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+    };
+
+    Ok(rows)
+}
+
+/// Moves a file into a directory, or to the root with `None`
+pub fn move_file_to_directory(
+    conn: &mut Connection,
+    file_id: i64,
+    dir_id: Option<i64>,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE files SET directory_id = ?1 WHERE id = ?2",
+        params![dir_id, file_id],
+    )?;
+    if conn.changes() == 0 {
+        bail!("no file with id {file_id}");
+    }
+    audit(
+        conn,
+        "move",
+        "file",
+        file_id,
+        &format!("moved to {}", describe_parent(dir_id)),
+    )?;
+    Ok(())
+}
+
+/// Names end up as output filenames on export, so they must be a single path component.
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        bail!("name must not be empty");
+    }
+    if name.contains(['/', '\\']) {
+        bail!("name must not contain path separators: {name}");
+    }
+    Ok(())
+}
+
+/// Rename a file, returning its previous name.
+pub fn rename_file(conn: &mut Connection, file_id: i64, name: &str) -> Result<String> {
+    validate_name(name)?;
+    let old: Option<String> = conn
+        .query_row(
+            "SELECT filename FROM files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )
+        .ok();
+    conn.execute(
+        "UPDATE files SET filename = ?1 WHERE id = ?2",
+        params![name, file_id],
+    )?;
+    if conn.changes() == 0 {
+        bail!("no file with id {file_id}");
+    }
+    let old = old.unwrap_or_default();
+    audit(
+        conn,
+        "rename",
+        "file",
+        file_id,
+        &format!("renamed from '{}' to '{}'", old, name),
+    )?;
+    Ok(old)
+}
+
+/// Store `description` for a file; an empty string clears it.
+pub fn set_description(conn: &Connection, file_id: i64, description: &str) -> Result<()> {
+    let description = (!description.is_empty()).then_some(description);
+    conn.execute(
+        "UPDATE files SET description = ?1 WHERE id = ?2",
+        params![description, file_id],
+    )?;
+    if conn.changes() == 0 {
+        bail!("no file with id {file_id}");
+    }
+    let detail = match description {
+        Some(_) => "set description",
+        None => "cleared description",
+    };
+    audit(conn, "set-description", "file", file_id, detail)?;
+    Ok(())
+}
+
+/// Insert a new `files` row named `name` whose chunks point at the same
+/// Discord messages as `src_file_id`. Returns the new file ID.
+pub fn copy_file(
+    conn: &mut Connection,
+    src_file_id: i64,
+    name: &str,
+    dir_id: Option<i64>,
+) -> Result<i64> {
+    validate_name(name)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO files
+             (filename, filesize, chunk_size, created_at, directory_id, status, sha256,
+              hash_algo, compressed, encrypted, chunk_overlap)
+         SELECT ?1, filesize, chunk_size, ?2, ?3, status, sha256, hash_algo,
+                compressed, encrypted, chunk_overlap
+         FROM files WHERE id = ?4",
+        params![name, Utc::now().to_rfc3339(), dir_id, src_file_id],
+    )?;
+    if tx.changes() == 0 {
+        bail!("no file with id {src_file_id}");
+    }
+    let new_id = tx.last_insert_rowid();
+    let chunks = tx.execute(
+        "INSERT INTO file_chunks
+             (file_id, idx, url, message_id, sha256, compressed, encrypted, nonce_hex,
+              webhook_idx, stored_size, overlap)
+         SELECT ?1, idx, url, message_id, sha256, compressed, encrypted, nonce_hex,
+                webhook_idx, stored_size, overlap
+         FROM file_chunks WHERE file_id = ?2",
+        params![new_id, src_file_id],
+    )?;
+    add_chunk_count(&tx, new_id, chunks)?;
+    audit(
+        &tx,
+        "copy",
+        "file",
+        new_id,
+        &format!("copied from file {} as '{}'", src_file_id, name),
+    )?;
+    tx.commit()?;
+    Ok(new_id)
+}
+
+/// Rename a directory, returning its previous name.
+pub fn rename_directory(conn: &mut Connection, dir_id: i64, name: &str) -> Result<String> {
+    validate_name(name)?;
+    let old: Option<String> = conn
+        .query_row(
+            "SELECT name FROM directories WHERE id = ?1",
+            params![dir_id],
+            |row| row.get(0),
+        )
+        .ok();
+    conn.execute(
+        "UPDATE directories SET name = ?1 WHERE id = ?2",
+        params![name, dir_id],
+    )
+    .map_err(|e| dir_name_taken(e, name))?;
+    if conn.changes() == 0 {
+        bail!("no directory with id {dir_id}");
+    }
+    let old = old.unwrap_or_default();
+    audit(
+        conn,
+        "rename",
+        "directory",
+        dir_id,
+        &format!("renamed from '{}' to '{}'", old, name),
+    )?;
+    Ok(old)
+}
+
+fn count_label(n: usize) -> String {
+    format!("{} file{}", n, if n == 1 { "" } else { "s" })
+}
+
+/// Print the hierarchy under `dir_id` (or the root), loading one directory at a time.
+pub fn print_tree(conn: &Connection, dir_id: Option<i64>, depth: Option<u32>) -> Result<()> {
+    let name = match dir_id {
+        Some(id) => conn
+            .query_row(
+                "SELECT name FROM directories WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .with_context(|| format!("no directory with id {id}"))?,
+        None => ".".to_string(),
+    };
+    let files = list_files_in_directory(conn, dir_id)?;
+    println!("{}/ ({})", name, count_label(files.len()));
+    print_tree_level(conn, dir_id, files, "", depth)
+}
+
+fn print_tree_level(
+    conn: &Connection,
+    dir_id: Option<i64>,
+    files: Vec<(i64, String)>,
+    prefix: &str,
+    depth: Option<u32>,
+) -> Result<()> {
+    if depth == Some(0) {
+        return Ok(());
+    }
+    let mut dirs = list_directories(conn, dir_id)?;
+    dirs.sort_by(|a, b| a.1.cmp(&b.1));
+    let total = dirs.len() + files.len();
+
+    for (i, (id, name)) in dirs.into_iter().enumerate() {
+        let last = i + 1 == total;
+        let child_files = list_files_in_directory(conn, Some(id))?;
+        println!(
+            "{}{} {}/ ({})",
+            prefix,
+            if last { "└──" } else { "├──" },
+            name,
+            count_label(child_files.len())
+        );
+        let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        print_tree_level(
+            conn,
+            Some(id),
+            child_files,
+            &child_prefix,
+            depth.map(|d| d - 1),
+        )?;
+    }
+
+    let mut size_stmt = conn.prepare_cached("SELECT filesize FROM files WHERE id = ?1")?;
+    let offset = total - files.len();
+    for (i, (id, filename)) in files.into_iter().enumerate() {
+        let last = offset + i + 1 == total;
+        let size: i64 = size_stmt.query_row(params![id], |row| row.get(0))?;
+        println!(
+            "{}{} [{}] {} ({})",
+            prefix,
+            if last { "└──" } else { "├──" },
+            id,
+            filename,
+            format_size(size)
+        );
+    }
+    Ok(())
+}
+
+/// Reparent a directory, refusing moves into itself or one of its descendants.
+pub fn move_directory(conn: &mut Connection, dir_id: i64, parent_id: Option<i64>) -> Result<()> {
+    if let Some(parent_id) = parent_id {
+        // Walk up from the new parent; meeting `dir_id` on the way means a cycle
+        let (exists, cycle): (bool, bool) = conn.query_row(
+            "WITH RECURSIVE ancestors(id) AS (
+                SELECT id FROM directories WHERE id = ?1
+                UNION
+                SELECT d.parent_id FROM directories d JOIN ancestors a ON d.id = a.id
+                WHERE d.parent_id IS NOT NULL
+             )
+             SELECT EXISTS (SELECT 1 FROM ancestors),
+                    EXISTS (SELECT 1 FROM ancestors WHERE id = ?2)",
+            params![parent_id, dir_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if !exists {
+            bail!("no directory with id {parent_id}");
+        }
+        if cycle {
+            bail!("cannot move directory {dir_id} into {parent_id}: it is the same directory or one of its descendants");
+        }
+    }
+    conn.execute(
+        "UPDATE directories SET parent_id = ?1 WHERE id = ?2",
+        params![parent_id, dir_id],
+    )
+    .map_err(|e| {
+        let name: String = conn
+            .query_row(
+                "SELECT name FROM directories WHERE id = ?1",
+                params![dir_id],
+                |row| row.get(0),
+            )
+            .unwrap_or_default();
+        dir_name_taken(e, &name)
+    })?;
+    if conn.changes() == 0 {
+        bail!("no directory with id {dir_id}");
+    }
+    audit(
+        conn,
+        "move",
+        "directory",
+        dir_id,
+        &format!("moved into {}", describe_parent(parent_id)),
+    )?;
+    Ok(())
+}
+
+/// Prints the totals shown under `list`
+pub fn print_list_footer(conn: &Connection) -> Result<()> {
+    // chunks from before size tracking count at their file's full chunk size,
+    // so the last (usually shorter) chunk of those files is over-estimated
+    let (files, total, on_discord): (i64, i64, i64) = conn.query_row(
+        "SELECT (SELECT COUNT(*) FROM files),
+                (SELECT COALESCE(SUM(filesize), 0) FROM files),
+                (SELECT COALESCE(SUM(COALESCE(c.stored_size, f.chunk_size)), 0)
+                 FROM file_chunks c JOIN files f ON f.id = c.file_id)",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    println!(
+        "--- {} file{}, {} total, {} on Discord (with chunk overhead) ---",
+        files,
+        if files == 1 { "" } else { "s" },
+        format_size(total),
+        format_size(on_discord)
+    );
+    Ok(())
+}
+
+/// Prints database-wide totals for `stats`
+pub fn print_stats(conn: &Connection) -> Result<()> {
+    let (files, total_bytes): (i64, i64) = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(filesize), 0) FROM files",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let directories: i64 =
+        conn.query_row("SELECT COUNT(*) FROM directories", [], |row| row.get(0))?;
+    let (chunks, stored_bytes): (i64, i64) = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(stored_size), 0) FROM file_chunks",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let avg_chunk: Option<f64> = conn.query_row(
+        "SELECT AVG(stored_size) FROM file_chunks WHERE stored_size IS NOT NULL",
+        [],
+        |row| row.get(0),
+    )?;
+
+    println!("Files:          {}", files);
+    println!("Directories:    {}", directories);
+    println!("Chunks:         {} (one Discord upload each)", chunks);
+    println!("Total size:     {}", format_size(total_bytes));
+    println!("Stored size:    {}", format_size(stored_bytes));
+    match avg_chunk {
+        Some(avg) => println!("Avg chunk size: {}", format_size(avg as i64)),
+        None => println!("Avg chunk size: n/a"),
+    }
+
+    // SQLite fills the bare columns from the row that produced the MAX/MIN
+    let extreme = |agg: &str| -> Result<Option<(i64, String, i64)>> {
+        let row: (Option<i64>, Option<String>, Option<i64>) = conn.query_row(
+            &format!("SELECT id, filename, {agg}(filesize) FROM files"),
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        Ok(match row {
+            (Some(id), Some(name), Some(size)) => Some((id, name, size)),
+            _ => None,
+        })
+    };
+    if let Some((id, name, size)) = extreme("MAX")? {
+        println!(
+            "Largest file:   id={} size={} file={}",
+            id,
+            format_size(size),
+            name
+        );
+    }
+    if let Some((id, name, size)) = extreme("MIN")? {
+        println!(
+            "Smallest file:  id={} size={} file={}",
+            id,
+            format_size(size),
+            name
+        );
+    }
+    Ok(())
+}
+
+/// What the TUI's detail pane shows about a file
+pub struct FileDetails {
+    /// File id
+    pub id: i64,
+    /// Stored name
+    pub filename: String,
+    /// Size in bytes
+    pub filesize: i64,
+    /// `complete`, `pending` or `cancelled`
+    pub status: String,
+    /// Number of chunks stored
+    pub chunks: i64,
+    /// Whole-file hash, in the file's `hash_algo`
+    pub sha256: Option<String>,
+    /// Tag names, sorted
+    pub tags: Vec<String>,
+    /// Text set with `describe`
+    pub description: Option<String>,
+}
+
+/// Details of one file, tags included
+pub fn file_details(conn: &Connection, file_id: i64) -> Result<FileDetails> {
+    let mut details = conn
+        .query_row(
+            "SELECT filename, filesize, status, sha256, description, chunk_count
+             FROM files WHERE id = ?1",
+            params![file_id],
+            |row| {
+                Ok(FileDetails {
+                    id: file_id,
+                    filename: row.get(0)?,
+                    filesize: row.get(1)?,
+                    status: row.get(2)?,
+                    sha256: row.get(3)?,
+                    description: row.get(4)?,
+                    chunks: row.get(5)?,
+                    tags: Vec::new(),
+                })
+            },
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    let mut stmt = conn.prepare(
+        "SELECT t.name FROM tags t JOIN file_tags ft ON ft.tag_id = t.id
+         WHERE ft.file_id = ?1 ORDER BY t.name",
+    )?;
+    details.tags = stmt
+        .query_map(params![file_id], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(details)
+}
+
+/// Prints one file's details for `stats <id>`
+pub fn print_file_stats(conn: &Connection, file_id: i64) -> Result<()> {
+    #[allow(clippy::type_complexity)]
+    let (
+        filename,
+        filesize,
+        created_at,
+        status,
+        description,
+        original_path,
+        duration_ms,
+        local_only,
+    ): (
+        String,
+        i64,
+        String,
+        String,
+        Option<String>,
+        Option<String>,
+        Option<i64>,
+        bool,
+    ) = conn
+        .query_row(
+            "SELECT filename, filesize, created_at, status, description, original_path,
+                    ingest_duration_ms, local_only
+             FROM files WHERE id = ?1",
+            params![file_id],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                ))
+            },
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    let (chunks, stored_bytes, compressed, unknown_size): (i64, i64, bool, i64) = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(stored_size), 0), COALESCE(MAX(compressed), 0),
+                COUNT(*) - COUNT(stored_size)
+         FROM file_chunks WHERE file_id = ?1",
+        params![file_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+
+    println!("File:           {} (id={})", filename, file_id);
+    if let Some(original_path) = original_path {
+        println!("Original path:  {}", original_path);
+    }
+    println!("Created at:     {}", created_at);
+    println!("Status:         {}", status);
+    if local_only {
+        println!("Stored:         local only (imported with --skip-upload)");
+    }
+    if let Some(description) = description {
+        println!("Description:    {}", description);
+    }
+    println!("Size:           {}", format_size(filesize));
+    println!("Chunks:         {}", chunks);
+    if unknown_size > 0 {
+        println!(
+            "Stored size:    unknown ({} chunk(s) predate size tracking)",
+            unknown_size
+        );
+    } else {
+        println!("Stored size:    {}", format_size(stored_bytes));
+        if compressed && stored_bytes > 0 {
+            println!(
+                "Compression:    {:.2}x",
+                filesize as f64 / stored_bytes as f64
+            );
+        }
+    }
+    if let Some(ms) = duration_ms {
+        // whole seconds for the readable form; the exact figure follows it
+        let duration = Duration::from_secs(ms as u64 / 1000);
+        println!(
+            "Ingest time:    {} ({:.1} s)",
+            humantime::format_duration(duration),
+            ms as f64 / 1000.0
+        );
+        let uploaded = if unknown_size > 0 {
+            filesize
+        } else {
+            stored_bytes
+        };
+        if ms > 0 {
+            println!(
+                "Upload speed:   {}/s",
+                format_size((uploaded as f64 * 1000.0 / ms as f64) as i64)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `(id, name)` of the directories under `parent_id`, or at the root
+pub fn list_directories(conn: &Connection, parent_id: Option<i64>) -> Result<Vec<(i64, String)>> {
+    let mut stmt = match parent_id {
+        Some(_) => conn.prepare("SELECT id, name FROM directories WHERE parent_id = ?1"),
+        None => conn.prepare("SELECT id, name FROM directories WHERE parent_id IS NULL"),
+    }?;
+
+    let rows: Vec<(i64, String)> = match parent_id {
+        Some(pid) => stmt
+            .query_map(params![pid], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+        None => stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+    };
+
+    Ok(rows)
+}
+
+/// Re-download every chunk of `file_id` and compare it against the hash
+/// recorded at ingest, also reporting gaps in the chunk indices. Fails if any
+/// chunk is missing or doesn't match.
+pub async fn verify_file(
+    conn: &mut Connection,
+    file_id: i64,
+    proxy_base: &str,
+    pacing: &UploadConfig,
+    cipher: Option<&ChunkCipher>,
+) -> Result<()> {
+    let report = check_chunks(conn, file_id, proxy_base, pacing, cipher).await?;
+    for line in &report.lines {
+        println!("{line}");
+    }
+    let mut summary = format!(
+        "file_id={}: {} ok, {} mismatched, {} missing",
+        file_id, report.ok, report.mismatched, report.missing
+    );
+    if report.unhashed > 0 {
+        summary.push_str(&format!(", {} without a stored hash", report.unhashed));
+    }
+    println!("{summary}");
+    if report.bad_chunks() > 0 {
+        bail!("file_id={file_id} failed verification");
+    }
+    Ok(())
+}
+
+/// What re-downloading the chunks of a file turned up
+struct ChunkReport {
+    ok: usize,
+    mismatched: usize,
+    missing: usize,
+    unhashed: usize,
+    /// One line per missing, mismatched or unhashed chunk, in index order
+    lines: Vec<String>,
+}
+
+impl ChunkReport {
+    fn bad_chunks(&self) -> usize {
+        self.mismatched + self.missing
+    }
+}
+
+async fn check_chunks(
+    conn: &Connection,
+    file_id: i64,
+    proxy_base: &str,
+    pacing: &UploadConfig,
+    cipher: Option<&ChunkCipher>,
+) -> Result<ChunkReport> {
+    let (filesize, chunk_size): (i64, i64) = conn
+        .query_row(
+            "SELECT filesize, chunk_size FROM files WHERE id = ?1",
+            params![file_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .with_context(|| format!("no file with id {file_id}"))?;
+    let algo = file_hash_algo(conn, file_id)?;
+    let chunks = load_chunks(conn, file_id)?;
+
+    // indices should run 0..N with N set by the file and chunk size
+    let present: HashSet<i64> = chunks.iter().map(|c| c.idx).collect();
+    let expected = (filesize as u64).div_ceil(chunk_size as u64) as i64;
+    let end = chunks.last().map_or(expected, |c| expected.max(c.idx + 1));
+    let missing: Vec<i64> = (0..end).filter(|idx| !present.contains(idx)).collect();
+    let mut report = ChunkReport {
+        ok: 0,
+        mismatched: 0,
+        missing: missing.len(),
+        unhashed: 0,
+        lines: missing
+            .iter()
+            .map(|idx| format!("MISSING chunk at index {idx}"))
+            .collect(),
+    };
+
+    let client = pacing.client()?;
+    for chunk in chunks {
+        let idx = chunk.idx;
+        let stored = &chunk.sha256;
+        if stored.is_empty() {
+            report
+                .lines
+                .push(format!("Chunk {idx}: no stored hash, skipping"));
+            report.unhashed += 1;
+            continue;
+        }
+        let data = decode_chunk(
+            &download_chunk(&client, proxy_base, &chunk.url, idx).await?,
+            &chunk,
+            cipher,
+        )?;
+        let calc = algo.digest(&data);
+        if &calc != stored {
+            report.lines.push(format!(
+                "Chunk {idx}: MISMATCH (stored={stored}, calc={calc})"
+            ));
+            report.mismatched += 1;
+        } else {
+            report.ok += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// One line of the `verify-all` summary
+#[derive(Serialize)]
+pub struct VerifyRow {
+    file_id: i64,
+    filename: String,
+    /// `OK`, `FAILED` or `SKIPPED`
+    status: &'static str,
+    /// Chunks that were missing or didn't match their hash
+    bad_chunks: usize,
+    /// Why the file failed or was skipped
+    detail: Option<String>,
+}
+
+impl VerifyRow {
+    /// Whether the file failed verification
+    pub fn failed(&self) -> bool {
+        self.status == "FAILED"
+    }
+}
+
+impl fmt::Display for VerifyRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={:<3} {:<7} bad_chunks={:<4} file={}",
+            self.file_id, self.status, self.bad_chunks, self.filename
+        )?;
+        if let Some(detail) = &self.detail {
+            write!(f, " ({detail})")?;
+        }
+        Ok(())
+    }
+}
+
+/// `verify-all`: check every file the way `verify` does, skipping deleted
+/// and incomplete ones. Up to `concurrency` files are checked at a time, each
+/// on a connection of its own from `state`. Errors such as a failed download
+/// count as a failed file instead of ending the run, unless `fail_fast` is
+/// set, which stops after the first failure.
+pub async fn verify_all(
+    state: &AppState,
+    proxy_base: &str,
+    pacing: &UploadConfig,
+    cipher: Option<&ChunkCipher>,
+    concurrency: usize,
+    fail_fast: bool,
+) -> Result<Vec<VerifyRow>> {
+    let files = state
+        .conn()?
+        .prepare("SELECT id, filename, status, deleted_at IS NOT NULL FROM files ORDER BY id")?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, bool>(3)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    // the caller usually holds a connection of its own, so leave it one
+    let workers = concurrency
+        .min(state.size().saturating_sub(1) as usize)
+        .max(1);
+    let mut checks = futures_util::stream::iter(files)
+        .map(|(file_id, filename, status, deleted)| async move {
+            let skipped = if deleted {
+                Some("deleted".to_string())
+            } else if status != "complete" {
+                Some(format!("status is {status}"))
+            } else {
+                None
+            };
+            if let Some(detail) = skipped {
+                return VerifyRow {
+                    file_id,
+                    filename,
+                    status: "SKIPPED",
+                    bad_chunks: 0,
+                    detail: Some(detail),
+                };
+            }
+            debug!(file_id, "verifying");
+            let report = match state.conn() {
+                Ok(conn) => check_chunks(&conn, file_id, proxy_base, pacing, cipher).await,
+                Err(e) => Err(e),
+            };
+            match report {
+                Ok(report) if report.bad_chunks() == 0 => VerifyRow {
+                    file_id,
+                    filename,
+                    status: "OK",
+                    bad_chunks: 0,
+                    detail: None,
+                },
+                Ok(report) => VerifyRow {
+                    file_id,
+                    filename,
+                    status: "FAILED",
+                    bad_chunks: report.bad_chunks(),
+                    detail: Some(format!(
+                        "{} mismatched, {} missing",
+                        report.mismatched, report.missing
+                    )),
+                },
+                Err(e) => VerifyRow {
+                    file_id,
+                    filename,
+                    status: "FAILED",
+                    bad_chunks: 0,
+                    detail: Some(format!("{e:#}")),
+                },
+            }
+        })
+        .buffered(workers);
+
+    let mut rows = Vec::new();
+    while let Some(row) = checks.next().await {
+        let failed = row.failed();
+        rows.push(row);
+        if failed && fail_fast {
+            break;
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert_file(conn: &Connection, filename: &str) -> i64 {
+        conn.execute(
+            "INSERT INTO files (filename, filesize, chunk_size, created_at)
+             VALUES (?1, 0, ?2, ?3)",
+            params![filename, DEFAULT_CHUNK_SIZE as i64, Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+        conn.last_insert_rowid()
+    }
+
+    /// A path under the temp directory that no other test uses.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("octo-{}-{name}", std::process::id()))
+    }
+
+    /// Set `file_id` up as a `size`-byte file cut into `chunk_size` chunks,
+    /// stored unencoded.
+    fn set_layout(conn: &Connection, file_id: i64, size: usize, chunk_size: usize) {
+        conn.execute(
+            "UPDATE files SET filesize = ?1, chunk_size = ?2,
+                 compressed = 0, encrypted = 0, chunk_overlap = 0
+             WHERE id = ?3",
+            params![size as i64, chunk_size as i64, file_id],
+        )
+        .unwrap();
+    }
+
+    /// Store chunk `idx` of `file_id` on disk under a `local://` URL, named
+    /// after the file so tests running side by side don't share it.
+    fn insert_local_chunk(conn: &Connection, file_id: i64, idx: i64, data: &[u8], overlap: usize) {
+        let filename: String = conn
+            .query_row(
+                "SELECT filename FROM files WHERE id = ?1",
+                params![file_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let path = temp_path(&format!("{filename}-{idx}.chunk"));
+        fs::write(&path, data).unwrap();
+        conn.execute(
+            "INSERT INTO file_chunks (file_id, idx, message_id, url, overlap)
+             VALUES (?1, ?2, '', ?3, ?4)",
+            params![
+                file_id,
+                idx,
+                format!("{LOCAL_URL_PREFIX}{}", path.display()),
+                overlap as i64
+            ],
+        )
+        .unwrap();
+        recount_chunks(conn, file_id).unwrap();
+    }
+
+    fn remove_local_chunks(conn: &Connection, file_id: i64) {
+        for chunk in load_chunks(conn, file_id).unwrap() {
+            fs::remove_file(chunk.url.strip_prefix(LOCAL_URL_PREFIX).unwrap()).unwrap();
+        }
+    }
+
+    /// Transfers without pauses, progress bars or proxies.
+    fn quiet_transfer() -> TransferOptions {
+        TransferOptions {
+            concurrency: 2,
+            max_retries: 0,
+            progress: progress_target(true),
+            pacing: UploadConfig {
+                upload_delay: 0..=0,
+                rate_limit_delay: 0..=0,
+                timeout: Duration::from_secs(10),
+                user_agent: "octo-potato-test".to_string(),
+                headers: HeaderMap::new(),
+                proxy: ProxySetting::Disabled,
+            },
+            local_storage: false,
+        }
+    }
+
+    /// A local HTTP server that takes `requests` requests, one per connection,
+    /// and answers each with the status and body `respond` picks for its
+    /// request line and body. Returns the server's base URL and a handle
+    /// giving back the request lines in the order they came in.
+    fn serve(
+        requests: usize,
+        respond: impl Fn(&str, &[u8]) -> (u16, String) + Send + 'static,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            for _ in 0..requests {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let (status, reply) = respond(&request_line, &body);
+                let mut stream = stream;
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reply}",
+                    reply.len()
+                )
+                .unwrap();
+                seen.push(request_line.trim_end().to_string());
+            }
+            seen
+        });
+        (base, server)
+    }
+
+    fn filenames(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT filename FROM files ORDER BY filename")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn in_memory_conn_is_at_current_schema() {
+        let conn = in_memory_conn();
+        assert_eq!(schema_version(&conn).unwrap(), CURRENT_VERSION);
+    }
+
+    #[test]
+    fn moved_files_are_listed_in_directory() {
+        let mut conn = in_memory_conn();
+
+        let a = insert_file(&conn, "a.bin");
+        let b = insert_file(&conn, "b.bin");
+        let dir = create_directory(&mut conn, "music", None).unwrap();
+        move_file_to_directory(&mut conn, a, Some(dir)).unwrap();
+        move_file_to_directory(&mut conn, b, Some(dir)).unwrap();
+
+        let files = list_files_in_directory(&conn, Some(dir)).unwrap();
+        assert_eq!(
+            files,
+            vec![(a, "a.bin".to_string()), (b, "b.bin".to_string())]
+        );
+        assert!(list_files_in_directory(&conn, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compressed_chunk_round_trips() {
+        let payload = b"2024-01-01T00:00:00Z INFO request handled in 3ms\n".repeat(10_000);
+
+        let stored = |compressed| StoredChunk {
+            idx: 0,
+            url: String::new(),
+            sha256: String::new(),
+            compressed,
+            encrypted: false,
+            nonce_hex: None,
+            overlap: 0,
+        };
+
+        let compress = ChunkEncoding {
+            compress_level: Some(DEFAULT_COMPRESS_LEVEL),
+            cipher: None,
+            ..Default::default()
+        };
+        let (encoded, _) = encode_chunk(payload.clone(), &compress).unwrap();
+        assert!(encoded.len() < payload.len() / 10);
+        assert_eq!(
+            decode_chunk(&encoded, &stored(true), None).unwrap(),
+            payload
+        );
+
+        let (plain, _) = encode_chunk(payload.clone(), &ChunkEncoding::default()).unwrap();
+        assert_eq!(decode_chunk(&plain, &stored(false), None).unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn export_downloads_through_proxy() {
+        let payload = b"chunk served by the proxy".to_vec();
+
+        // a one-shot HTTP proxy that answers any request with `payload`
+        let body = String::from_utf8(payload.clone()).unwrap();
+        let (proxy_url, proxy) = serve(1, move |_, _| (200, body.clone()));
+
+        let mut conn = in_memory_conn();
+        let file_id = insert_file(&conn, "proxied.bin");
+        conn.execute(
+            "UPDATE files SET filesize = ?1, sha256 = ?2 WHERE id = ?3",
+            params![
+                payload.len() as i64,
+                HashAlgo::Sha256.digest(&payload),
+                file_id
+            ],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO file_chunks (file_id, idx, message_id, url)
+             VALUES (?1, 0, '1', 'https://cdn.discordapp.invalid/att/1')",
+            params![file_id],
+        )
+        .unwrap();
+
+        let mut transfer = quiet_transfer();
+        transfer.pacing.proxy = ProxySetting::parse(Some(&proxy_url)).unwrap();
+        let out = std::env::temp_dir().join(format!("octo-proxy-{}.bin", std::process::id()));
+        // neither host resolves, so the download only succeeds via the proxy
+        let integrity = export_file(
+            &mut conn,
+            file_id,
+            "http://discord-proxy.invalid",
+            Some(out.clone()),
+            ChunkCheck::Off,
+            0,
+            OutputFormat::File,
+            &transfer,
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(integrity, FileIntegrity::Ok);
+        assert_eq!(fs::read(&out).unwrap(), payload);
+        fs::remove_file(&out).unwrap();
+        let request_line = &proxy.join().unwrap()[0];
+        assert!(
+            request_line.starts_with("GET http://discord-proxy.invalid/?"),
+            "{request_line}"
+        );
+    }
+
+    #[test]
+    fn import_urls_reads_upload_only_lines() {
+        let mut conn = in_memory_conn();
+        let file_id = insert_file(&conn, "big.iso");
+        let lines = std::env::temp_dir().join(format!("octo-import-{}.jsonl", std::process::id()));
+        fs::write(
+            &lines,
+            concat!(
+                r#"{"idx":0,"message_id":"10","url":"https://cdn/0","sha256":"aa","compressed":true,"nonce_hex":"00ff","webhook_idx":1,"stored_size":7,"overlap":0}"#,
+                "\n",
+                r#"{"idx":1,"message_id":"11","url":"https://cdn/1","sha256":"bb","compressed":true,"nonce_hex":"0fff","webhook_idx":0,"stored_size":9,"overlap":16}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(import_urls(&mut conn, file_id, &lines).unwrap(), (2, 0));
+        assert_eq!(import_urls(&mut conn, file_id, &lines).unwrap(), (0, 2));
+        fs::remove_file(&lines).unwrap();
+
+        let chunks = load_chunks(&conn, file_id).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].nonce_hex.as_deref(), Some("0fff"));
+        assert_eq!(chunks[1].overlap, 16);
+        let encoding: (bool, bool, i64) = conn
+            .query_row(
+                "SELECT compressed, encrypted, chunk_overlap FROM files WHERE id = ?1",
+                params![file_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(encoding, (true, true, 16));
+    }
+
+    #[tokio::test]
+    async fn upload_gives_up_when_every_webhook_stays_rate_limited() {
+        // answers every request with a 429
+        let (base, server) = serve(1, |_, _| (429, String::new()));
+        let webhook = format!("{base}/api/webhooks/1/tok");
+
+        let sent = Arc::new(AtomicU64::new(0));
+        let err = post_chunk(
+            &Client::new(),
+            &[webhook],
+            &bytes::Bytes::from_static(b"chunk"),
+            0,
+            0,
+            &UploadConfig::default(),
+            &sent,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err.to_string().contains("rate limited"), "{err:#}");
+        // the rejected attempt is taken off the progress again
+        assert_eq!(sent.load(Ordering::Relaxed), 0);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn parse_size_reads_units() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("8MB").unwrap(), 8_000_000);
+        assert_eq!(parse_size("1.5 GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_size("2kib").unwrap(), 2048);
+        assert!(parse_size("3 parsecs").is_err());
+        assert!(parse_size("99999999999TB").is_err());
+    }
+
+    #[test]
+    fn old_schema_is_migrated_to_current() {
+        let mut conn = open_memory_db().unwrap();
+        // the tables as they were before schema versions were recorded
+        conn.execute_batch(
+            "CREATE TABLE files (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 filename TEXT NOT NULL,
+                 filesize INTEGER NOT NULL,
+                 chunk_size INTEGER NOT NULL,
+                 created_at TEXT NOT NULL
+             );
+             CREATE TABLE file_chunks (
+                 file_id INTEGER NOT NULL,
+                 idx INTEGER NOT NULL,
+                 url TEXT NOT NULL,
+                 message_id TEXT NOT NULL,
+                 PRIMARY KEY(file_id, idx)
+             );
+             INSERT INTO files VALUES (1, 'old.bin', 4, 4, '2023-01-01T00:00:00+00:00');
+             INSERT INTO file_chunks VALUES (1, 0, 'https://cdn/old', '7');",
+        )
+        .unwrap();
+
+        init_schema(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), CURRENT_VERSION);
+        let chunks = load_chunks(&conn, 1).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].url, "https://cdn/old");
+        assert!(!chunks[0].compressed && !chunks[0].encrypted);
+        // the file has a chunk, so its encoding is filled in from it and its
+        // chunk count is backfilled
+        let encoding: (Option<bool>, i64) = conn
+            .query_row(
+                "SELECT encrypted, chunk_count FROM files WHERE id = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(encoding, (Some(false), 1));
+        // running it again is a no-op
+        init_schema(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), CURRENT_VERSION);
+    }
+
+    #[test]
+    fn quota_is_reserved_until_used_up_and_released() {
+        let conn = in_memory_conn();
+        let webhook = "https://discord.com/api/webhooks/1/tok";
+        conn.execute(
+            "INSERT INTO quotas (webhook_url, max_messages) VALUES (?1, 2)",
+            params![webhook],
+        )
+        .unwrap();
+        let used = || -> i64 {
+            conn.query_row(
+                "SELECT used_messages FROM quotas WHERE webhook_url = ?1",
+                params![webhook],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        reserve_quota(&conn, webhook).unwrap();
+        reserve_quota(&conn, webhook).unwrap();
+        let err = reserve_quota(&conn, webhook).unwrap_err();
+        assert!(err.to_string().contains("used up"), "{err:#}");
+        assert_eq!(used(), 2);
+
+        release_quota(&conn, webhook).unwrap();
+        reserve_quota(&conn, webhook).unwrap();
+        assert_eq!(used(), 2);
+        // webhooks without a quota are never refused
+        reserve_quota(&conn, "https://discord.com/api/webhooks/2/tok").unwrap();
+    }
+
+    #[test]
+    fn check_orphans_repairs_dangling_rows() {
+        let mut conn = in_memory_conn();
+        let file_id = insert_file(&conn, "kept.bin");
+        conn.execute_batch("PRAGMA foreign_keys = OFF;").unwrap();
+        conn.execute(
+            "INSERT INTO file_chunks (file_id, idx, url, message_id) VALUES (99, 0, 'u', 'm')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO directories (name, parent_id, created_at) VALUES ('music', 42, ?1)",
+            params![Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+        let dir = create_directory(&mut conn, "music", None).unwrap();
+
+        let report = check_orphans(&mut conn, false).unwrap();
+        assert_eq!(report.chunks, vec![(99, 1)]);
+        assert_eq!(report.directories.len(), 1);
+        // a dry run changes nothing
+        assert_eq!(check_orphans(&mut conn, false).unwrap().chunks.len(), 1);
+
+        check_orphans(&mut conn, true).unwrap();
+        assert!(check_orphans(&mut conn, false).unwrap().is_empty());
+        let names: Vec<String> = conn
+            .prepare("SELECT name FROM directories WHERE parent_id IS NULL ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            names,
+            vec![format!("music ({})", dir - 1), "music".to_string()]
+        );
+        assert_eq!(filenames(&conn), vec!["kept.bin"]);
+        assert!(load_chunks(&conn, file_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge_db_renames_clashing_files() {
+        let source = temp_path("merge-source.db");
+        let _ = fs::remove_file(&source);
+        let mut other = Connection::open(&source).unwrap();
+        init_schema(&mut other).unwrap();
+        let incoming = insert_file(&other, "a.bin");
+        insert_file(&other, "b.bin");
+        other
+            .execute(
+                "INSERT INTO file_chunks (file_id, idx, url, message_id)
+                 VALUES (?1, 0, 'https://cdn/a', '5')",
+                params![incoming],
+            )
+            .unwrap();
+        other
+            .execute(
+                "UPDATE files SET local_only = 1, ingest_duration_ms = 1234 WHERE id = ?1",
+                params![incoming],
+            )
+            .unwrap();
+        recount_chunks(&other, incoming).unwrap();
+        drop(other);
+
+        let mut conn = in_memory_conn();
+        insert_file(&conn, "a.bin");
+        let summary = merge_db(&mut conn, &source, MergeConflict::Rename).unwrap();
+        fs::remove_file(&source).unwrap();
+
+        assert_eq!((summary.files, summary.renamed, summary.chunks), (2, 1, 1));
+        assert_eq!(filenames(&conn), vec!["a (2).bin", "a.bin", "b.bin"]);
+        let (renamed, local_only, duration): (i64, bool, Option<i64>) = conn
+            .query_row(
+                "SELECT id, local_only, ingest_duration_ms FROM files WHERE filename = 'a (2).bin'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(load_chunks(&conn, renamed).unwrap()[0].url, "https://cdn/a");
+        assert!(local_only);
+        assert_eq!(duration, Some(1234));
+    }
+
+    #[tokio::test]
+    async fn export_range_reads_only_the_chunks_it_overlaps() {
+        let conn = in_memory_conn();
+        let file_id = insert_file(&conn, "range.bin");
+        let payload: Vec<u8> = (0..10).collect();
+        set_layout(&conn, file_id, payload.len(), 4);
+        // chunk 0 is missing, but bytes 4..9 don't need it
+        insert_local_chunk(&conn, file_id, 1, &payload[4..8], 0);
+        insert_local_chunk(&conn, file_id, 2, &payload[8..], 0);
+
+        let out = temp_path("range.out");
+        let transfer = quiet_transfer();
+        export_range(&conn, file_id, 4..9, &out, "", 0, &transfer, None)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&out).unwrap(), &payload[4..9]);
+        export_range(&conn, file_id, 5..6, &out, "", 0, &transfer, None)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&out).unwrap(), &payload[5..6]);
+
+        let err = export_range(&conn, file_id, 3..5, &out, "", 0, &transfer, None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("missing some of chunks 0..=1"),
+            "{err:#}"
+        );
+        assert!(
+            export_range(&conn, file_id, 4..11, &out, "", 0, &transfer, None)
+                .await
+                .is_err()
+        );
+        let _ = fs::remove_file(&out);
+        remove_local_chunks(&conn, file_id);
+    }
+
+    #[tokio::test]
+    async fn export_drops_the_overlap_between_chunks() {
+        let mut conn = in_memory_conn();
+        let file_id = insert_file(&conn, "overlap.bin");
+        let payload = b"every chunk repeats a little".to_vec();
+        set_layout(&conn, file_id, payload.len(), 10);
+        conn.execute(
+            "UPDATE files SET sha256 = ?1, chunk_overlap = 3 WHERE id = ?2",
+            params![HashAlgo::Sha256.digest(&payload), file_id],
+        )
+        .unwrap();
+        insert_local_chunk(&conn, file_id, 0, &payload[..10], 0);
+        insert_local_chunk(&conn, file_id, 1, &payload[7..20], 3);
+        insert_local_chunk(&conn, file_id, 2, &payload[17..], 3);
+        update_file_status(&conn, file_id).unwrap();
+
+        let out = temp_path("overlap.out");
+        let integrity = export_file(
+            &mut conn,
+            file_id,
+            "",
+            Some(out.clone()),
+            ChunkCheck::Off,
+            0,
+            OutputFormat::File,
+            &quiet_transfer(),
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(integrity, FileIntegrity::Ok);
+        assert_eq!(fs::read(&out).unwrap(), payload);
+        fs::remove_file(&out).unwrap();
+        remove_local_chunks(&conn, file_id);
+    }
+
+    #[tokio::test]
+    async fn import_from_dir_strips_the_overlap_before_hashing() {
+        let mut conn = in_memory_conn();
+        let file_id = insert_file(&conn, "imported-overlap.bin");
+        let payload = b"every chunk repeats a little".to_vec();
+        set_layout(&conn, file_id, payload.len(), 10);
+        conn.execute(
+            "UPDATE files SET sha256 = ?1, chunk_overlap = 3 WHERE id = ?2",
+            params![HashAlgo::Sha256.digest(&payload), file_id],
+        )
+        .unwrap();
+        let dir = temp_path("imported-overlap");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0.chunk"), &payload[..10]).unwrap();
+        fs::write(dir.join("1.chunk"), &payload[7..20]).unwrap();
+        fs::write(dir.join("2.chunk"), &payload[17..]).unwrap();
+
+        let imported = import_from_dir(&mut conn, file_id, &dir, None, &quiet_transfer(), None)
+            .await
+            .unwrap();
+        assert_eq!(imported, 3);
+        let chunks = load_chunks(&conn, file_id).unwrap();
+        let own = [&payload[..10], &payload[10..20], &payload[20..]];
+        for (chunk, own) in chunks.iter().zip(own) {
+            assert_eq!(chunk.overlap, if chunk.idx > 0 { 3 } else { 0 });
+            assert_eq!(chunk.sha256, HashAlgo::Sha256.digest(own));
+        }
+
+        let out = temp_path("imported-overlap.out");
+        let integrity = export_file(
+            &mut conn,
+            file_id,
+            "",
+            Some(out.clone()),
+            ChunkCheck::Off,
+            0,
+            OutputFormat::File,
+            &quiet_transfer(),
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(integrity, FileIntegrity::Ok);
+        assert_eq!(fs::read(&out).unwrap(), payload);
+        fs::remove_file(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skipped_chunks_past_the_end_dont_count() {
+        let skip = HashSet::from([1, 2, 7, -1]);
+        let mut sizes = skipped_chunk_sizes(&skip, 10, 4);
+        sizes.sort();
+        // chunk 2 is the short last one; 7 and -1 aren't in the file
+        assert_eq!(sizes, [2, 4]);
+        assert!(skipped_chunk_sizes(&skip, 0, 4).is_empty());
+    }
+
+    #[tokio::test]
+    async fn resume_uploads_only_the_missing_chunks() {
+        let mut conn = in_memory_conn();
+        let file_id = insert_file(&conn, "resumed.bin");
+        let payload: Vec<u8> = (0..10).collect();
+        set_layout(&conn, file_id, payload.len(), 4);
+        let path = temp_path("resumed.bin");
+        fs::write(&path, &payload).unwrap();
+        // chunk 1 made it before the ingest was interrupted
+        let done = UploadedChunk {
+            idx: 1,
+            message_id: "earlier".to_string(),
+            url: "https://cdn/earlier".to_string(),
+            sha256: HashAlgo::Sha256.digest(&payload[4..8]),
+            nonce_hex: None,
+            webhook_idx: 0,
+            stored_size: 4,
+            overlap: 0,
+        };
+        record_chunk(&conn, file_id, &done, &ChunkEncoding::default()).unwrap();
+
+        let (base, server) = serve(2, |_, body| {
+            let body = String::from_utf8_lossy(body);
+            let idx = if body.contains("filename=\"0.chunk\"") {
+                0
+            } else {
+                2
+            };
+            let reply =
+                format!(r#"{{"id":"m{idx}","attachments":[{{"url":"https://cdn/{idx}"}}]}}"#);
+            (200, reply)
+        });
+        let uploaded = resume_ingest(
+            &mut conn,
+            file_id,
+            &path,
+            &[format!("{base}/api/webhooks/1/tok")],
+            &quiet_transfer(),
+            None,
+        )
+        .await
+        .unwrap();
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(uploaded, 2);
+        let urls: Vec<String> = load_chunks(&conn, file_id)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.url)
+            .collect();
+        assert_eq!(
+            urls,
+            ["https://cdn/0", "https://cdn/earlier", "https://cdn/2"]
+        );
+        let (count, status): (i64, String) = conn
+            .query_row(
+                "SELECT chunk_count, status FROM files WHERE id = ?1",
+                params![file_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((count, status.as_str()), (3, "complete"));
+    }
+
+    #[tokio::test]
+    async fn roll_back_deletes_uploaded_chunks_and_the_file() {
+        let mut conn = in_memory_conn();
+        let file_id = insert_file(&conn, "failed.bin");
+        let (base, server) = serve(2, |_, _| (204, String::new()));
+        let webhook = format!("{base}/api/webhooks/1/tok");
+        conn.execute(
+            "INSERT INTO quotas (webhook_url, max_messages, used_messages) VALUES (?1, 10, 2)",
+            params![webhook],
+        )
+        .unwrap();
+        let mut uploaded = UploadedFile::default();
+        for idx in [0, 2] {
+            let chunk = UploadedChunk {
+                idx,
+                message_id: format!("{}", 100 + idx),
+                url: format!("https://cdn/{idx}"),
+                sha256: String::new(),
+                nonce_hex: None,
+                webhook_idx: 0,
+                stored_size: 1,
+                overlap: 0,
+            };
+            record_chunk(&conn, file_id, &chunk, &ChunkEncoding::default()).unwrap();
+            uploaded.chunks.push(chunk);
+        }
+        uploaded.failed.push((1, anyhow!("HTTP 500")));
+        assert_eq!(load_chunks(&conn, file_id).unwrap().len(), 2);
+
+        let err = uploaded
+            .roll_back(&mut conn, file_id, &[webhook], &quiet_transfer().pacing)
+            .await
+            .unwrap_err();
+        let mut deleted = server.join().unwrap();
+        deleted.sort();
+
+        assert!(err.to_string().contains("chunk 1: HTTP 500"), "{err:#}");
+        assert_eq!(
+            deleted,
+            [
+                "DELETE /api/webhooks/1/tok/messages/100 HTTP/1.1",
+                "DELETE /api/webhooks/1/tok/messages/102 HTTP/1.1"
+            ]
+        );
+        assert!(filenames(&conn).is_empty());
+        assert!(load_chunks(&conn, file_id).unwrap().is_empty());
+        let used: i64 = conn
+            .query_row("SELECT used_messages FROM quotas", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(used, 0);
+    }
+
+    #[test]
+    fn rate_window_survives_a_failed_attempt() {
+        let sent = Arc::new(AtomicU64::new(0));
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+        let mut window = RateWindow::new(start);
+
+        let mut done = AttemptBytes::new(&sent);
+        let failing = AttemptBytes::new(&sent);
+        for attempt in [&done, &failing] {
+            attempt.sent.fetch_add(100, Ordering::Relaxed);
+            attempt.streamed.fetch_add(100, Ordering::Relaxed);
+        }
+        done.kept = true;
+        drop(done);
+        assert_eq!(window.rate(at(1.0), sent.load(Ordering::Relaxed)), 200.0);
+        // the first sample has left the window, so the oldest one holds 200 bytes
+        assert_eq!(window.rate(at(12.0), sent.load(Ordering::Relaxed)), 0.0);
+
+        drop(failing);
+        assert_eq!(sent.load(Ordering::Relaxed), 100);
+        assert_eq!(window.rate(at(12.5), 100), 0.0);
+        assert_eq!(window.rate(at(13.5), 300), 200.0);
+    }
+}
@@ -1,19 +1,20 @@
-use anyhow::{Context, Result};
-use chrono::Utc;
-use clap::{Parser, Subcommand};
-use rand::Rng;
-use reqwest::blocking::Client;
-use rusqlite::{params, Connection};
-use sha2::{Digest, Sha256};
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use anyhow::{bail, Context, Result};
+use clap::builder::RangedU64ValueParser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use indicatif::ProgressDrawTarget;
+use rusqlite::params;
+use std::fs;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::Duration;
+use tracing::info;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::prelude::*;
 
-const DEFAULT_DB: &str = "app-data/store.db";
-const DEFAULT_CHUNK_SIZE: usize = 7_000_000;
+use octo_potato::*;
+
+#[cfg(feature = "tui")]
+mod tui;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -21,20 +22,233 @@ const DEFAULT_CHUNK_SIZE: usize = 7_000_000;
     about = "Split files into chunks, store in SQLite, and reconstruct."
 )]
 struct Cli {
-    #[arg(long, default_value = DEFAULT_DB)]
-    db: PathBuf,
+    /// SQLite database path [default: app-data/store.db]
+    #[arg(long)]
+    db: Option<PathBuf>,
+
+    /// Use the database and webhooks of this workspace from the config file,
+    /// overriding --db and --webhook
+    #[arg(long)]
+    workspace: Option<String>,
+
+    /// Discord webhook URL; repeat to spread uploads across several webhooks
+    #[arg(long, short)]
+    webhook: Vec<String>,
+
+    /// Number of chunks uploaded or downloaded at the same time, 1 to 10
+    /// [default: 3]. Higher values hit Discord's rate limits sooner.
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..=MAX_CONCURRENCY as u64))]
+    concurrency: Option<usize>,
+
+    /// How many times a failed chunk upload is retried before giving up
+    #[arg(long, visible_alias = "max-chunk-retries", default_value_t = DEFAULT_MAX_RETRIES)]
+    max_retries: u32,
+
+    /// How many times a write to a locked database is retried, with
+    /// exponential backoff from 50 ms up to 2 s, before giving up
+    #[arg(long, default_value_t = DEFAULT_DB_RETRY_COUNT)]
+    db_retry_count: u32,
+
+    /// Shortest random pause, in seconds, after each chunk upload
+    #[arg(long, default_value_t = *DEFAULT_UPLOAD_DELAY.start())]
+    upload_delay_min: u64,
+
+    /// Longest random pause, in seconds, after each chunk upload
+    #[arg(long, default_value_t = *DEFAULT_UPLOAD_DELAY.end())]
+    upload_delay_max: u64,
+
+    /// Shortest random wait, in seconds, when Discord rate limits a delete or
+    /// an upload on every webhook; at least 1
+    #[arg(long, default_value_t = *DEFAULT_RATE_LIMIT_DELAY.start())]
+    rate_limit_delay_min: u64,
+
+    /// Longest random wait, in seconds, when Discord rate limits a delete or
+    /// an upload on every webhook
+    #[arg(long, default_value_t = *DEFAULT_RATE_LIMIT_DELAY.end())]
+    rate_limit_delay_max: u64,
+
+    /// Seconds an HTTP request to Discord may take before it is abandoned and
+    /// retried; connecting may take half of this
+    #[arg(long, default_value_t = DEFAULT_TIMEOUT.as_secs(), value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    timeout: u64,
+
+    /// User-Agent sent with every HTTP request [default: octo-potato/<version>]
+    #[arg(long)]
+    user_agent: Option<String>,
+
+    /// Extra `name:value` HTTP header sent with every request; repeat for several
+    #[arg(long)]
+    extra_header: Vec<String>,
 
+    /// Send every HTTP request through this http://, https:// or socks5://
+    /// proxy; `--proxy ""` ignores any system proxy [env: OCTO_PROXY]
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Send every HTTP request through the SOCKS5 proxy at this host:port;
+    /// shorthand for `--proxy socks5://host:port`
+    #[arg(long, value_name = "HOST:PORT", conflicts_with = "proxy")]
+    socks5: Option<String>,
+
+    /// Output format for list, list-dirs, list-chunks, search and other listings
+    #[arg(long, global = true, value_enum, default_value_t = Formatter::Human)]
+    format: Formatter,
+
+    /// Log at info level, including the effective configuration, and show
+    /// extra columns in listings
     #[arg(long, short)]
-    webhook: String,
+    verbose: bool,
+
+    /// Don't draw progress bars
+    #[arg(long, short)]
+    quiet: bool,
+
+    /// 16-, 24- or 32-byte AES key used to encrypt chunks on ingest and
+    /// decrypt them on export [config: encryption_key]
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// Don't upgrade an older database schema; fail instead
+    #[arg(long)]
+    skip_migration: bool,
+
+    /// Use a fresh in-memory database that is thrown away on exit, e.g. for
+    /// tests and CI; --db is ignored
+    #[arg(long, conflicts_with_all = ["skip_migration", "encrypt_db"])]
+    db_in_memory: bool,
+
+    /// Passphrase the database is encrypted with; a new database is created
+    /// encrypted. Needs the `sqlcipher` feature [env: OCTO_DB_PASSPHRASE]
+    #[arg(long, value_name = "PASSPHRASE")]
+    encrypt_db: Option<String>,
+
+    /// Most detailed log events to show [default: warn, or info with --verbose]
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+
+    /// Also append log events to this file as JSON lines
+    #[arg(long)]
+    log_file: Option<PathBuf>,
 
     #[command(subcommand)]
     cmd: Commands,
 }
 
+impl Cli {
+    /// The config settings given on the command line.
+    fn overrides(&self) -> CliConfig<'_> {
+        let (chunk_size, compress) = match &self.cmd {
+            Commands::Ingest {
+                chunk_size,
+                compress,
+                ..
+            } => (*chunk_size, *compress),
+            Commands::BatchIngest { chunk_size, .. } => (*chunk_size, false),
+            Commands::IngestDir { chunk_size, .. } => (*chunk_size, false),
+            Commands::IngestStdin { chunk_size, .. } => (*chunk_size, false),
+            _ => (None, false),
+        };
+        CliConfig {
+            db: self.db.as_ref(),
+            webhook: &self.webhook,
+            chunk_size,
+            concurrency: self.concurrency,
+            compress,
+            workspace: self.workspace.as_ref(),
+            encryption_key: self.key_file.as_ref(),
+            user_agent: self.user_agent.as_ref(),
+            extra_header: &self.extra_header,
+            proxy: self.proxy.as_ref(),
+            db_passphrase: self.encrypt_db.as_ref(),
+        }
+    }
+
+    fn pacing(&self, config: &Config) -> Result<UploadConfig> {
+        let proxy = match &self.socks5 {
+            Some(addr) => ProxySetting::socks5(addr)?,
+            None => ProxySetting::parse(config.proxy.as_deref())?,
+        };
+        UploadConfig::new(
+            self.upload_delay_min..=self.upload_delay_max,
+            self.rate_limit_delay_min..=self.rate_limit_delay_max,
+            Duration::from_secs(self.timeout),
+            config,
+            proxy,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Send log events to stderr, and with `--log-file` to that file as JSON.
+fn init_logging(cli: &Cli) -> Result<()> {
+    let level = cli
+        .log_level
+        .map(LevelFilter::from)
+        .unwrap_or(if cli.verbose {
+            LevelFilter::INFO
+        } else {
+            LevelFilter::WARN
+        });
+    let stderr = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .without_time();
+    let file = match &cli.log_file {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("opening log file {}", path.display()))?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(std::sync::Mutex::new(file)),
+            )
+        }
+        None => None,
+    };
+    // dependencies (reqwest, hyper) stay at warn so debug output is ours
+    let filter = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(level.min(LevelFilter::WARN));
+    tracing_subscriber::registry()
+        .with(stderr.and_then(file).with_filter(filter))
+        .init();
+    Ok(())
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Create tables if they don't exist
-    Init,
+    Init {
+        /// Drop every table first and start over with an empty database
+        #[arg(long)]
+        force: bool,
+        /// Don't ask for confirmation before --force
+        #[arg(long, requires = "force")]
+        yes: bool,
+    },
     /// Ingest a file into the database as 2MB chunks
     Ingest {
         /// Path to the file to ingest
@@ -43,24 +257,345 @@ enum Commands {
         /// Optional override for chunk size in bytes
         #[arg(long)]
         chunk_size: Option<usize>,
+        /// Compress each chunk with zstd before upload
+        #[arg(long)]
+        compress: bool,
+        /// zstd compression level used with --compress
+        #[arg(long, default_value_t = DEFAULT_COMPRESS_LEVEL)]
+        compress_level: i32,
+        /// Encrypt each chunk with AES-GCM, key size taken from --key-file
+        #[arg(long)]
+        encrypt: bool,
+        /// Print the chunks that would be uploaded without uploading anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Note stored with the file
+        #[arg(long)]
+        description: Option<String>,
+        /// Pick a chunk size giving about 100 chunks, ignoring --chunk-size
+        #[arg(long)]
+        auto_chunk: bool,
+        /// Delete the local chunk files once the upload has finished
+        #[arg(long)]
+        auto_cleanup: bool,
+        /// Upload chunks straight from memory without writing them to storage/;
+        /// `reupload` won't work for this file
+        #[arg(long, conflicts_with = "auto_cleanup")]
+        no_local_storage: bool,
+        /// Algorithm for the whole-file and per-chunk hashes
+        #[arg(long, value_enum, default_value_t = HashAlgo::Sha256)]
+        hash_algo: HashAlgo,
+        /// Start each chunk with the last N bytes of the previous one, so a
+        /// damaged boundary can be recovered from either side
+        #[arg(long, value_name = "BYTES", default_value_t = 0)]
+        chunk_overlap: usize,
+        /// Upload the chunks and print each one as a JSON line for
+        /// `import-urls`, without recording anything in the database
+        #[arg(long, conflicts_with_all = ["dry_run", "description", "auto_cleanup"])]
+        upload_only: bool,
+    },
+    /// Ingest every file listed in a manifest, one path per line
+    BatchIngest {
+        /// File with one path per line (`-` for stdin); blank lines and `#` comments are skipped
+        manifest: PathBuf,
+        /// Optional override for chunk size in bytes
+        #[arg(long)]
+        chunk_size: Option<usize>,
+        /// Directory to place the ingested files in
+        #[arg(long)]
+        dir_id: Option<i64>,
+    },
+    /// Ingest data piped into stdin, e.g. `my-command | octo-potato ingest-stdin --name out.bin`
+    IngestStdin {
+        /// Filename to store the data under
+        #[arg(long)]
+        name: String,
+        /// Optional override for chunk size in bytes
+        #[arg(long)]
+        chunk_size: Option<usize>,
+    },
+    /// Ingest every file in a directory
+    IngestDir {
+        /// Directory to ingest
+        dir: PathBuf,
+        /// Descend into subdirectories
+        #[arg(long)]
+        recursive: bool,
+        /// Only ingest files whose name matches this glob
+        #[arg(long)]
+        pattern: Option<String>,
+        /// Mirror the subdirectory layout as directories in the database
+        #[arg(long)]
+        create_subdirs: bool,
+        /// Optional override for chunk size in bytes
+        #[arg(long)]
+        chunk_size: Option<usize>,
+    },
+    /// Check the database for corruption and broken references
+    DbCheck,
+    /// Save all metadata to a JSON file that `restore` can load into another database
+    Backup {
+        /// Where to write the JSON
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Load a JSON file written by `backup`
+    Restore {
+        /// JSON file written by `backup`
+        #[arg(long)]
+        backup: PathBuf,
+        /// Overwrite rows that already exist instead of keeping them
+        #[arg(long)]
+        merge: bool,
+    },
+    /// Copy every file, chunk, directory and tag of another database into this one
+    MergeDb {
+        /// Database to copy from; it is migrated to the current schema first
+        source_db: PathBuf,
+        /// What to do with a file whose name is already taken in its directory
+        #[arg(long, value_enum, default_value_t = MergeConflict::Skip)]
+        conflict: MergeConflict,
+    },
+    /// Remove local chunk files that no database row refers to
+    Vacuum {
+        /// Only print what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Delete a file's local `storage/{file_id}/` chunks once every chunk is on Discord
+    TruncateStorage {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+    },
+    /// Run truncate-storage for every file whose status is `complete`
+    TruncateAllStorage,
+    /// Recompute each file's chunk count from its chunk rows, e.g. after
+    /// editing the database by hand
+    RepairChunkCount,
+    /// Find chunks, file tags and directories left pointing at rows that no longer exist
+    CheckOrphans {
+        /// Delete the orphaned chunk and file tag rows and move orphaned directories to the root
+        #[arg(long)]
+        repair: bool,
     },
     /// List stored files
-    List,
+    List {
+        #[command(flatten)]
+        filter: ListFilter,
+        #[command(flatten)]
+        page: ListPage,
+        /// Add a column with the name of each file's directory (`/` for the root)
+        #[arg(long)]
+        with_dir: bool,
+    },
+    /// Find files by name
+    Search {
+        /// Glob pattern (or regex with --regex) matched against filenames
+        pattern: String,
+        /// Treat the pattern as a regular expression
+        #[arg(long)]
+        regex: bool,
+        /// Only search this directory and its subdirectories
+        #[arg(long)]
+        dir_id: Option<i64>,
+        /// Only match files whose MIME type matches this glob, e.g. `video/*`
+        #[arg(long)]
+        mime_type: Option<String>,
+        #[command(flatten)]
+        size: SizeRange,
+    },
+    /// Add tags to a file
+    TagFile {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Tags to add
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a file
+    UntagFile {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Tags to remove
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// List tags and how many files use each
+    ListTags,
+    /// Find files by tag
+    SearchByTag {
+        /// Tags to look for
+        #[arg(required = true)]
+        tags: Vec<String>,
+        /// Only match files that have every tag, instead of any of them
+        #[arg(long)]
+        all: bool,
+    },
     /// List directories
     ListDirs,
+    /// List the files in a directory
     ListFileInDir {
         /// ID of the directory
         #[arg(long)]
         dir_id: i64,
     },
+    /// List the files that are not in any directory
+    ListRootFiles,
     /// Export (reconstruct) a stored file by ID
     Export {
         /// ID from the `files` table
         #[arg(long)]
         file_id: i64,
-        /// Output path to write the reconstructed file
+        /// Output path to write the reconstructed file; `-` for stdout, so
+        /// `export --out - | gzip` works. Defaults to the original filename
+        /// in the current directory
+        #[arg(long, short)]
+        out: Option<PathBuf>,
+        /// Write to stdout (the same as `--out -`)
+        #[arg(long, conflicts_with = "out")]
+        stdout: bool,
+        /// Write each chunk to `{idx}.chunk` in this directory instead of
+        /// reassembling the file; the same as `--output-format dir --out <DIR>`
+        #[arg(long, conflicts_with_all = ["out", "stdout", "use_original_path", "output_format"])]
+        out_dir: Option<PathBuf>,
+        /// Treat --out as a directory and recreate the path the file was
+        /// ingested from beneath it
+        #[arg(long, requires = "out")]
+        use_original_path: bool,
+        /// Check each downloaded chunk against its stored SHA-256
+        #[arg(long)]
+        verify: bool,
+        /// Like --verify, but keep going past mismatches: bad chunks are left
+        /// out of the output and all of them are reported at the end
+        #[arg(long, conflicts_with = "verify")]
+        integrity_check: bool,
+        /// Export even if not every chunk was uploaded
+        #[arg(long)]
+        force: bool,
+        /// How many times to retry a failed chunk download before giving up
+        #[arg(long, default_value_t = DEFAULT_DOWNLOAD_RETRIES)]
+        retries: u32,
+        /// Write the reassembled file, or the chunks into a SQLite database, tar
+        /// archive or directory
+        #[arg(long, value_enum, default_value_t = OutputFormat::File)]
+        output_format: OutputFormat,
+        /// Export this version of the file instead of the latest one
+        #[arg(long)]
+        version: Option<i64>,
+        /// Don't compare the SHA-256 of the whole exported file with the one
+        /// recorded at ingest
+        #[arg(long)]
+        no_integrity: bool,
+    },
+    /// Export only the bytes `[start, end)` of a stored file, downloading just
+    /// the chunks that overlap them
+    ExportRange {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Offset of the first byte to export
+        #[arg(long)]
+        start: u64,
+        /// Offset just past the last byte to export
+        #[arg(long)]
+        end: u64,
+        /// Where to write the bytes
+        #[arg(long, short)]
+        out: PathBuf,
+        /// How many times to retry a failed chunk download before giving up
+        #[arg(long, default_value_t = DEFAULT_DOWNLOAD_RETRIES)]
+        retries: u32,
+    },
+    /// Print the first lines of a text file, downloading only its first chunk
+    Preview {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// How many lines to print
+        #[arg(long, default_value_t = 20)]
+        lines: usize,
+        /// Preview the file even if it looks binary
+        #[arg(long)]
+        force: bool,
+    },
+    /// Export every file in a directory into a local folder
+    ExportDir {
+        /// ID of the directory
+        #[arg(long)]
+        dir_id: i64,
+        /// Folder to write the files into; created if missing
+        #[arg(long)]
+        out_dir: PathBuf,
+        /// Also export files from subdirectories
+        #[arg(long)]
+        recursive: bool,
+        /// Check each downloaded chunk against its stored SHA-256
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Export several files straight into a zip archive, one entry per file
+    Zip {
+        /// IDs from the `files` table
+        #[arg(required_unless_present = "dir_id", conflicts_with = "dir_id")]
+        file_ids: Vec<i64>,
+        /// Zip every file directly in this directory instead
+        #[arg(long)]
+        dir_id: Option<i64>,
+        /// Path of the zip archive to write
         #[arg(long, short)]
         out: PathBuf,
+        /// Verify each chunk's SHA-256 and abort on the first mismatch
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Show the stored chunk rows of a file; takes the global --format
+    ListChunks {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+    },
+    /// Write a file's chunk URLs to a manifest for curl, wget or aria2c, without downloading
+    ExportManifest {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Where to write the manifest
+        #[arg(long)]
+        out: PathBuf,
+        /// Manifest format (the global --format only affects listings)
+        #[arg(long, value_enum)]
+        manifest_format: ManifestFormat,
+    },
+    /// Ingest a file as the next version of a stored one
+    NewVersion {
+        /// ID of any version of the stored file
+        #[arg(long)]
+        file_id: i64,
+        /// Path to the new contents
+        #[arg(long, short)]
+        path: PathBuf,
+    },
+    /// Look up each configured webhook on Discord and show its details; takes
+    /// the global --format
+    WebhookInfo,
+    /// Limit how many Discord messages may be posted through a webhook
+    SetQuota {
+        /// Webhook URL the limit applies to
+        webhook: String,
+        /// Most messages the webhook may hold; uploads past this fail
+        #[arg(long)]
+        max: u64,
+    },
+    /// Show message usage for every webhook with a quota; takes the global --format
+    ShowQuota,
+    /// Show every version of a file; takes the global --format
+    ListVersions {
+        /// ID of any version of the file
+        #[arg(long)]
+        file_id: i64,
     },
     /// Verify checksums of chunks for a file
     Verify {
@@ -68,6 +603,13 @@ enum Commands {
         #[arg(long)]
         file_id: i64,
     },
+    /// Run verify on every file and print a summary; fails if any file does.
+    /// Combine with the global `--format json` for CI
+    VerifyAll {
+        /// Stop at the first file that fails
+        #[arg(long)]
+        fail_fast: bool,
+    },
     /// Move file to a directory
     MoveFile {
         /// ID from the `files` table
@@ -83,386 +625,1152 @@ enum Commands {
         #[arg(long)]
         name: String,
     },
+    /// Show directories and their files as a tree
+    Tree {
+        /// Start from this directory instead of the root
+        #[arg(long)]
+        dir_id: Option<i64>,
+        /// How many levels of directories to descend
+        #[arg(long)]
+        depth: Option<u32>,
+    },
+    /// Move a directory under another directory
+    MoveDir {
+        /// ID of the directory to move
+        #[arg(long)]
+        dir_id: i64,
+        /// New parent directory; leave out (or give no value) to move to the root
+        #[arg(long, num_args = 0..=1)]
+        parent_id: Option<i64>,
+    },
+    /// Change the stored filename of a file
+    Rename {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// New filename
+        #[arg(long)]
+        name: String,
+    },
+    /// Register an existing file under another name, sharing its chunks
+    CopyFile {
+        /// ID of the file to copy
+        #[arg(long)]
+        src_file_id: i64,
+        /// Filename of the copy
+        #[arg(long)]
+        name: String,
+        /// Directory to place the copy in
+        #[arg(long)]
+        dir_id: Option<i64>,
+    },
+    /// Set or clear the note stored with a file
+    SetDescription {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// New description; an empty string clears it
+        #[arg(long)]
+        description: String,
+    },
+    /// Change the name of a directory
+    RenameDir {
+        /// ID of the directory
+        #[arg(long)]
+        dir_id: i64,
+        /// New directory name
+        #[arg(long)]
+        name: String,
+    },
+    /// Delete a file, its chunks and the Discord messages holding them
+    Delete {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Keep the local `storage/{file_id}` directory
+        #[arg(long)]
+        keep_local: bool,
+        /// Print what would be deleted without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Hide a file from `list` without touching Discord; undo with `undelete`
+    SoftDelete {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+    },
+    /// Bring back a file hidden by `soft-delete`
+    Undelete {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+    },
+    /// Permanently delete files soft-deleted more than N days ago, along with
+    /// their Discord messages
+    PurgeDeleted {
+        #[arg(long)]
+        older_than_days: u32,
+        /// List the files that would be purged without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show recorded changes, newest first
+    AuditLog {
+        /// Only show entries about this kind of entity: `file` or `directory`
+        #[arg(long)]
+        entity_type: Option<String>,
+        /// Only show entries about this file or directory ID
+        #[arg(long)]
+        entity_id: Option<i64>,
+        /// Show at most this many entries
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// List files with identical content
+    DuplicateCheck {
+        /// Keep the lowest file_id of each group and soft-delete the others
+        #[arg(long)]
+        delete_duplicates: bool,
+    },
+    /// Report storage usage, for the whole database or a single file
+    Stats {
+        /// Only report on this file
+        #[arg(long)]
+        file_id: Option<i64>,
+    },
+    /// Register chunk URLs that are already on Discord from a CSV of `idx,message_id,url`
+    ImportUrls {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// CSV file with a header row, or the JSON lines printed by
+        /// `ingest --upload-only`
+        #[arg(long)]
+        csv: PathBuf,
+    },
+    /// Fill in a file's missing chunks from `{idx}.chunk` files, e.g. a
+    /// backup of its local storage
+    ImportFromDir {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Directory holding the `.chunk` files
+        #[arg(long)]
+        storage_dir: PathBuf,
+        /// Don't upload; export reads the chunks from `--storage-dir` instead
+        #[arg(long)]
+        skip_upload: bool,
+    },
+    /// Upload chunks again from local storage to replace expired or broken URLs
+    Reupload {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Only re-upload this chunk
+        #[arg(long)]
+        chunk_idx: Option<i64>,
+    },
+    /// Fetch each chunk's Discord message again to replace its expired CDN URL
+    Refresh {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+    },
+    /// Print the Discord CDN URL of one chunk, through the proxy if PROXY_BASE is set
+    ExportUrl {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        #[arg(long)]
+        chunk_idx: i64,
+        /// Fetch the chunk's Discord message first to get a fresh URL
+        #[arg(long)]
+        refresh: bool,
+    },
+    /// Run `refresh` on every chunk not refreshed in the last N hours
+    RefreshAll {
+        #[arg(long)]
+        older_than_hours: u32,
+    },
+    /// Upload the missing chunks of an interrupted ingest
+    Resume {
+        /// ID from the `files` table
+        #[arg(long)]
+        file_id: i64,
+        /// Path to the original file
+        #[arg(long, short)]
+        path: PathBuf,
+    },
+    /// Browse directories and files interactively; needs the `tui` feature
+    Tui,
+    /// Re-encrypt a SQLCipher database under a new passphrase
+    ChangeDbPassphrase {
+        /// Current passphrase; takes the place of --encrypt-db
+        #[arg(long)]
+        old: String,
+        /// New passphrase
+        #[arg(long)]
+        new: String,
+    },
+    /// Stop a running ingest or resume of a file after the chunks already uploading
+    Cancel {
+        /// ID from the `files` table; `list` shows an ingest in progress as `[pending]`
+        #[arg(long)]
+        file_id: i64,
+    },
+    /// Generate a random key file for --encrypt and print its fingerprint
+    #[command(alias = "gen-key")]
+    GenerateKey {
+        /// Where to write the key
+        #[arg(long, short)]
+        out: PathBuf,
+        /// Key size: 128, 192 or 256 bits for AES-128, AES-192 or AES-256
+        #[arg(long)]
+        bits: Option<u32>,
+    },
+    /// Print a key file's length and fingerprint, without the key itself
+    ShowKeyInfo {
+        /// Key file to inspect
+        key_file: PathBuf,
+    },
+    /// Print a completion script for a shell to stdout
+    ///
+    /// Redirect the output to where the shell looks for completions:
+    ///
+    ///   bash:       ~/.local/share/bash-completion/completions/octo-potato
+    ///   zsh:        a directory on $fpath, e.g. ~/.zfunc/_octo-potato
+    ///   fish:       ~/.config/fish/completions/octo-potato.fish
+    ///   powershell: dot-source it from $PROFILE, e.g. `. ~/octo-potato.ps1`
+    ///   elvish:     ~/.config/elvish/lib/octo-potato.elv, then `use octo-potato`
+    #[command(verbatim_doc_comment)]
+    ShellCompletion {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// List the workspaces in the config files, marking the active one with `*`
+    WorkspaceList,
+    /// Add a named database and webhook to ~/.config/octo-potato/config.toml
+    WorkspaceAdd {
+        /// Name to select it with --workspace
+        #[arg(long)]
+        name: String,
+        /// SQLite database path
+        #[arg(long)]
+        db: PathBuf,
+        /// Discord webhook URL
+        #[arg(long)]
+        webhook: String,
+    },
+    /// Remove a workspace from ~/.config/octo-potato/config.toml
+    WorkspaceRemove {
+        /// Name of the workspace
+        #[arg(long)]
+        name: String,
+    },
 }
 
-fn main() -> Result<()> {
+/// Ingest settings from the config and global flags: the configured chunk
+/// size, compressed at the default level if `compress` is set.
+fn ingest_options(config: &Config, transfer: &TransferOptions) -> IngestOptions {
+    IngestOptions::new()
+        .transfer(transfer.clone())
+        .chunk_size(config.chunk_size)
+        .compress(config.compress.then_some(DEFAULT_COMPRESS_LEVEL))
+}
+
+/// Print `warning` and ask for a yes on stdin. Refuses when stdin isn't a
+/// terminal, so scripts have to pass `--yes`.
+fn confirm(warning: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("{warning}\nstdin is not a terminal; pass --yes to confirm");
+    }
+    eprint!("{warning}\nContinue? [y/N] ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// The proxy that chunk downloads go through, from `PROXY_BASE`.
+fn proxy_base() -> Result<String> {
+    std::env::var("PROXY_BASE").context("PROXY_BASE must be set")
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
     let cli = Cli::parse();
+    // needs no config or database, and shouldn't create app-data/ either
+    if let Commands::ShellCompletion { shell } = cli.cmd {
+        clap_complete::generate(
+            shell,
+            &mut Cli::command(),
+            env!("CARGO_PKG_NAME"),
+            &mut std::io::stdout(),
+        );
+        return Ok(());
+    }
+    init_logging(&cli)?;
+    let config = Config::load(&cli.overrides())?;
+    info!("Effective config: {config:#?}");
+    let transfer = TransferOptions {
+        concurrency: config.concurrency,
+        max_retries: cli.max_retries,
+        progress: progress_target(cli.quiet),
+        pacing: cli.pacing(&config)?,
+        local_storage: true,
+    };
     let data_dir = Path::new("app-data");
     if !data_dir.exists() {
         fs::create_dir_all(data_dir)?;
     }
-    let mut conn =
-        Connection::open(&cli.db).with_context(|| format!("opening db: {}", cli.db.display()))?;
+    set_db_retry_count(cli.db_retry_count);
+    // one connection for the handler below and one for each worker
+    let pool_size = config.concurrency as u32 + 1;
+    let state = if cli.db_in_memory {
+        AppState::memory(pool_size)?
+    } else {
+        // the current passphrase is only needed to get the database open
+        let passphrase = match &cli.cmd {
+            Commands::ChangeDbPassphrase { old, .. } => Some(old.clone().into()),
+            _ => config.db_passphrase.clone(),
+        };
+        AppState::open(&config.db, passphrase.as_ref(), pool_size)?
+    };
+    let mut conn = state.conn()?;
+    if let Commands::Init {
+        force: true, yes, ..
+    } = cli.cmd
+    {
+        let target = if cli.db_in_memory {
+            "the in-memory database".to_string()
+        } else {
+            config.db.display().to_string()
+        };
+        if !yes
+            && !confirm(&format!(
+                "This drops every table in {target}; all data will be lost. Chunks already \
+             posted to Discord stay there but can no longer be exported."
+            ))?
+        {
+            bail!("aborted");
+        }
+        reset_schema(&mut conn)?;
+    }
+    if cli.skip_migration {
+        check_schema_version(&conn)?;
+    } else {
+        init_schema(&mut conn)?;
+    }
 
     match cli.cmd {
-        Commands::Init => {
-            init_schema(&mut conn)?;
-            println!("Database initialized at {}", cli.db.display());
-        }
-        Commands::Ingest { path, chunk_size } => {
-            init_schema(&mut conn)?;
-            let webhook = cli.webhook.as_str();
-            let file_id = ingest_file(
+        Commands::Init { .. } => {
+            if cli.db_in_memory {
+                println!("Database initialized in memory");
+            } else {
+                println!("Database initialized at {}", config.db.display());
+            }
+            if !config.webhook.is_empty() {
+                validate_webhooks(&transfer.pacing.client()?, &config.webhook).await?;
+                println!("Verified {} webhook(s)", config.webhook.len());
+            }
+        }
+        Commands::Ingest {
+            path,
+            dry_run,
+            auto_chunk,
+            ..
+        } if dry_run => {
+            let chunk_size = if auto_chunk {
+                auto_chunk_size(&path)?
+            } else {
+                config.chunk_size
+            };
+            dry_run_ingest(&path, chunk_size).await?;
+        }
+        Commands::Ingest {
+            path,
+            compress_level,
+            encrypt,
+            description,
+            auto_chunk,
+            auto_cleanup,
+            no_local_storage,
+            hash_algo,
+            chunk_overlap,
+            upload_only,
+            ..
+        } => {
+            let chunk_size = if auto_chunk {
+                auto_chunk_size(&path)?
+            } else {
+                config.chunk_size
+            };
+            let cipher = if encrypt {
+                let key_file = config
+                    .encryption_key
+                    .as_deref()
+                    .context("--encrypt requires --key-file or `encryption_key` in the config")?;
+                Some(load_key(key_file)?)
+            } else {
+                None
+            };
+            let options = ingest_options(&config, &transfer)
+                .chunk_size(chunk_size)
+                .compress(config.compress.then_some(compress_level))
+                .encrypt(cipher)
+                .hash_algo(hash_algo)
+                .chunk_overlap(chunk_overlap)
+                .local_storage(!no_local_storage);
+            if upload_only {
+                let count = upload_only_file(
+                    &conn,
+                    &path,
+                    config.webhooks()?,
+                    &options,
+                    &mut std::io::stdout().lock(),
+                )
+                .await?;
+                info!("Uploaded {} chunk(s) of '{}'", count, path.display());
+                return Ok(());
+            }
+            let file_id = ingest_file(&mut conn, &path, config.webhooks()?, &options).await?;
+            if let Some(description) = &description {
+                set_description(&conn, file_id, description)?;
+            }
+            println!("Ingested '{}' with file_id={}", path.display(), file_id);
+            if auto_cleanup {
+                let freed = truncate_storage(&conn, file_id)?;
+                println!("Removed local chunks, freed {}", format_size(freed as i64));
+            }
+        }
+        Commands::BatchIngest {
+            manifest, dir_id, ..
+        } => {
+            batch_ingest(
+                &mut conn,
+                &manifest,
+                dir_id,
+                config.webhooks()?,
+                &ingest_options(&config, &transfer),
+            )
+            .await?;
+        }
+        Commands::IngestStdin { name, .. } => {
+            let file_id = ingest_stdin(
                 &mut conn,
+                &name,
+                config.webhooks()?,
+                &ingest_options(&config, &transfer),
+            )
+            .await?;
+            println!("Ingested stdin as '{}' with file_id={}", name, file_id);
+        }
+        Commands::IngestDir {
+            dir,
+            recursive,
+            pattern,
+            create_subdirs,
+            ..
+        } => {
+            let count = ingest_dir(
+                &mut conn,
+                &dir,
+                recursive,
+                pattern.as_deref(),
+                create_subdirs,
+                config.webhooks()?,
+                &ingest_options(&config, &transfer),
+            )
+            .await?;
+            println!("Ingested {} file(s) from '{}'", count, dir.display());
+        }
+        Commands::DbCheck => {
+            check_db(&conn)?;
+            println!("Database OK");
+        }
+        Commands::Backup { out } => {
+            let rows = backup_db(&conn, &out)?;
+            println!("Backed up {} row(s) to {}", rows, out.display());
+        }
+        Commands::Restore { backup, merge } => {
+            let rows = restore_db(&mut conn, &backup, merge)?;
+            println!("Restored {} row(s) from {}", rows, backup.display());
+        }
+        Commands::MergeDb {
+            source_db,
+            conflict,
+        } => {
+            let mut source = open_db(&source_db, None)?;
+            if cli.skip_migration {
+                check_schema_version(&source)?;
+            } else {
+                init_schema(&mut source)?;
+            }
+            drop(source);
+            let summary = merge_db(&mut conn, &source_db, conflict)?;
+            println!("{summary}");
+        }
+        Commands::Vacuum { dry_run } => {
+            let (count, bytes) = vacuum_storage(&conn, dry_run)?;
+            if dry_run {
+                println!(
+                    "Would remove {} item(s), freeing {}",
+                    count,
+                    format_size(bytes as i64)
+                );
+            } else {
+                println!(
+                    "Removed {} item(s), freed {}",
+                    count,
+                    format_size(bytes as i64)
+                );
+            }
+        }
+        Commands::RepairChunkCount => {
+            let fixed = repair_chunk_count(&mut conn)?;
+            for (file_id, stored, actual) in &fixed {
+                println!("file_id={}: chunk_count {} -> {}", file_id, stored, actual);
+            }
+            println!("Fixed the chunk count of {} file(s)", fixed.len());
+        }
+        Commands::CheckOrphans { repair } => {
+            let report = check_orphans(&mut conn, repair)?;
+            for (file_id, rows) in &report.chunks {
+                println!("file_id={}: {} orphaned chunk row(s)", file_id, rows);
+            }
+            for (file_id, tag_id) in &report.file_tags {
+                println!("file_tags: file_id={} tag_id={}", file_id, tag_id);
+            }
+            for (id, parent_id) in &report.directories {
+                println!("directory {}: parent {} does not exist", id, parent_id);
+            }
+            if report.is_empty() {
+                println!("No orphaned rows found");
+            } else if repair {
+                println!("Repaired");
+            } else {
+                println!("Run again with --repair to fix");
+            }
+        }
+        Commands::TruncateStorage { file_id } => {
+            let freed = truncate_storage(&conn, file_id)?;
+            println!(
+                "Removed local chunks of file_id={}, freed {}",
+                file_id,
+                format_size(freed as i64)
+            );
+        }
+        Commands::TruncateAllStorage => {
+            let (count, freed) = truncate_all_storage(&conn)?;
+            println!(
+                "Removed local chunks of {} file(s), freed {}",
+                count,
+                format_size(freed as i64)
+            );
+        }
+        Commands::List {
+            filter,
+            page,
+            with_dir,
+        } => {
+            let mut files = list_files(&mut conn, &filter, &page)?;
+            if with_dir {
+                with_directories(&conn, &mut files)?;
+            }
+            let description = filter.describe()?;
+            if cli.format == Formatter::Human && (description.is_some() || !page.is_default()) {
+                let total = count_files(&conn, &filter)?;
+                println!(
+                    "--- {} ---",
+                    list_header(description.as_deref(), &page, files.len(), total)
+                );
+            }
+            print_files(cli.format, cli.verbose, &files)?;
+            // the footer covers the whole library, so it would mislead under a filter
+            if cli.format == Formatter::Human && description.is_none() {
+                print_list_footer(&conn)?;
+            }
+        }
+        Commands::Search {
+            pattern,
+            regex,
+            dir_id,
+            mime_type,
+            size,
+        } => {
+            let files = search_files(&conn, &pattern, regex, dir_id, mime_type.as_deref(), &size)?;
+            print_files(cli.format, cli.verbose, &files)?;
+        }
+        Commands::TagFile { file_id, tags } => {
+            tag_file(&mut conn, file_id, &tags)?;
+            println!("Tagged file {} with {}", file_id, tags.join(", "));
+        }
+        Commands::UntagFile { file_id, tags } => {
+            let removed = untag_file(&conn, file_id, &tags)?;
+            println!("Removed {} tag(s) from file {}", removed, file_id);
+        }
+        Commands::ListTags => {
+            cli.format.print(&list_tags(&conn)?)?;
+        }
+        Commands::SearchByTag { tags, all } => {
+            cli.format.print(&search_by_tag(&conn, &tags, all)?)?;
+        }
+        Commands::Export {
+            file_id,
+            out,
+            stdout,
+            out_dir,
+            use_original_path,
+            verify,
+            integrity_check,
+            force,
+            retries,
+            output_format,
+            version,
+            no_integrity,
+        } => {
+            let (out, output_format) = match out_dir {
+                Some(dir) => (Some(dir), OutputFormat::Dir),
+                None => (out, output_format),
+            };
+            let file_id = select_version(&conn, file_id, version)?;
+            let (filename, status): (String, String) = conn
+                .query_row(
+                    "SELECT filename, status FROM files WHERE id = ?1",
+                    params![file_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .with_context(|| format!("no file with id {file_id}"))?;
+            if status != "complete" && !force {
+                bail!("file_id={file_id} is {status}; run `resume` first or pass --force");
+            }
+            let to_stdout = stdout || out.as_deref().is_some_and(|path| path.as_os_str() == "-");
+            let mut out = match out {
+                _ if to_stdout => None,
+                Some(out) => Some(out),
+                None if output_format == OutputFormat::File => {
+                    // the original name, without any directories it may carry
+                    let name = Path::new(&filename).file_name().with_context(|| {
+                        format!("file_id={file_id} has no usable filename; pass --out")
+                    })?;
+                    let path = PathBuf::from(name);
+                    if path.exists() {
+                        bail!(
+                            "'{}' already exists in the current directory; pass --out to overwrite it",
+                            path.display()
+                        );
+                    }
+                    Some(path)
+                }
+                None => bail!("this --output-format needs --out, or --stdout to stream it"),
+            };
+            if let (true, Some(dir)) = (use_original_path, &out) {
+                let path = original_export_path(&conn, file_id, dir)?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("failed to create {}", parent.display()))?;
+                }
+                out = Some(path);
+            }
+            if to_stdout {
+                // keep the terminal free for the data itself
+                transfer
+                    .progress
+                    .set_draw_target(ProgressDrawTarget::hidden());
+            }
+            let proxy_base = proxy_base()?;
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            let integrity = export_file(
+                &mut conn,
+                file_id,
+                &proxy_base,
+                out,
+                if integrity_check {
+                    ChunkCheck::SkipMismatched
+                } else {
+                    ChunkCheck::from_verify(verify)
+                },
+                retries,
+                output_format,
+                &transfer,
+                cipher,
+                !no_integrity,
+            )
+            .await?;
+            if let Some(ok) = integrity.message() {
+                if to_stdout {
+                    info!(file_id, "{}", ok);
+                } else {
+                    println!("{ok}");
+                }
+            }
+        }
+        Commands::Preview {
+            file_id,
+            lines,
+            force,
+        } => {
+            let proxy_base = proxy_base()?;
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            let text = preview_file(
+                &conn,
+                file_id,
+                &proxy_base,
+                lines,
+                force,
+                &transfer.pacing,
+                cipher.as_ref(),
+            )
+            .await?;
+            println!("{}", text);
+        }
+        Commands::ExportRange {
+            file_id,
+            start,
+            end,
+            out,
+            retries,
+        } => {
+            let proxy_base = proxy_base()?;
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            export_range(
+                &conn,
+                file_id,
+                start..end,
+                &out,
+                &proxy_base,
+                retries,
+                &transfer,
+                cipher,
+            )
+            .await?;
+            println!(
+                "Wrote bytes {}..{} of file_id={} to {}",
+                start,
+                end,
+                file_id,
+                out.display()
+            );
+        }
+        Commands::ExportDir {
+            dir_id,
+            out_dir,
+            recursive,
+            verify,
+        } => {
+            let proxy_base = proxy_base()?;
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            let exported = export_directory(
+                &mut conn,
+                dir_id,
+                &out_dir,
+                recursive,
+                &proxy_base,
+                verify,
+                &transfer,
+                cipher,
+            )
+            .await?;
+            println!("Exported {} file(s) to {}", exported, out_dir.display());
+        }
+        Commands::Zip {
+            file_ids,
+            dir_id,
+            out,
+            verify,
+        } => {
+            let file_ids = match dir_id {
+                Some(dir_id) => list_files_in_directory(&conn, Some(dir_id))?
+                    .into_iter()
+                    .map(|(id, _)| id)
+                    .collect(),
+                None => file_ids,
+            };
+            let proxy_base = proxy_base()?;
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            let zipped = zip_files(
+                &mut conn,
+                &file_ids,
+                &out,
+                &proxy_base,
+                verify,
+                &transfer,
+                cipher,
+            )
+            .await?;
+            println!("Zipped {} file(s) into {}", zipped, out.display());
+        }
+        Commands::ListChunks { file_id } => {
+            cli.format.print(&list_chunks(&conn, file_id)?)?;
+        }
+        Commands::ExportManifest {
+            file_id,
+            out,
+            manifest_format,
+        } => {
+            let count = export_manifest(&conn, file_id, &out, manifest_format)?;
+            println!(
+                "Wrote {} chunk URL(s) for file_id={} to {}",
+                count,
+                file_id,
+                out.display()
+            );
+        }
+        Commands::NewVersion { file_id, path } => {
+            let (new_id, version) = ingest_new_version(
+                &mut conn,
+                file_id,
                 &path,
-                chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
-                &webhook,
-            )?;
-            println!("Ingested '{}' with file_id={}", path.display(), file_id);
+                config.webhooks()?,
+                &ingest_options(&config, &transfer),
+            )
+            .await?;
+            println!(
+                "Ingested '{}' as version {} with file_id={}",
+                path.display(),
+                version,
+                new_id
+            );
+        }
+        Commands::WebhookInfo => {
+            let client = transfer.pacing.client()?;
+            let mut rows = Vec::new();
+            for url in config.webhooks()? {
+                rows.push(webhook_info(&client, url).await?);
+            }
+            cli.format.print(&rows)?;
+        }
+        Commands::SetQuota { webhook, max } => {
+            let row = set_quota(&conn, &webhook, max)?;
+            println!("Set quota: {row}");
         }
-        Commands::List => {
-            list_files(&mut conn)?;
+        Commands::ShowQuota => {
+            cli.format.print(&list_quotas(&conn)?)?;
         }
-        Commands::Export { file_id, out } => {
-            let proxy_base = std::env::var("PROXY_BASE").expect("PROXY_BASE must be set.");
-            export_file(&mut conn, file_id, &proxy_base, Some(out))?;
+        Commands::ListVersions { file_id } => {
+            cli.format.print(&version_chain(&conn, file_id)?)?;
         }
         Commands::Verify { file_id } => {
-            verify_file(&mut conn, file_id)?;
+            let proxy_base = proxy_base()?;
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            verify_file(
+                &mut conn,
+                file_id,
+                &proxy_base,
+                &transfer.pacing,
+                cipher.as_ref(),
+            )
+            .await?;
+        }
+        Commands::VerifyAll { fail_fast } => {
+            let proxy_base = proxy_base()?;
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            let rows = verify_all(
+                &state,
+                &proxy_base,
+                &transfer.pacing,
+                cipher.as_ref(),
+                transfer.concurrency,
+                fail_fast,
+            )
+            .await?;
+            cli.format.print(&rows)?;
+            let failed = rows.iter().filter(|row| row.failed()).count();
+            if failed > 0 {
+                bail!("{} of {} file(s) failed verification", failed, rows.len());
+            }
         }
         Commands::CreateDir { name } => {
-            let id = create_directory(&mut conn, &name.as_str(), None)?;
+            let id = create_directory(&mut conn, name.as_str(), None)?;
             println!("Created directory '{}' with id {}", name, id);
         }
-        Commands::ListDirs => {
-            for (id, name) in list_directories(&conn, None)? {
-                println!("{} - {}", id, name);
+        Commands::Tree { dir_id, depth } => {
+            print_tree(&conn, dir_id, depth)?;
+        }
+        Commands::MoveDir { dir_id, parent_id } => {
+            move_directory(&mut conn, dir_id, parent_id)?;
+            match parent_id {
+                Some(parent_id) => println!("Moved directory {} into {}", dir_id, parent_id),
+                None => println!("Moved directory {} to the root", dir_id),
             }
         }
+        Commands::ListDirs => {
+            let dirs: Vec<DirRow> = list_directories(&conn, None)?
+                .into_iter()
+                .map(|(id, name)| DirRow { id, name })
+                .collect();
+            cli.format.print(&dirs)?;
+        }
         Commands::MoveFile { file_id, dir_id } => {
             move_file_to_directory(&mut conn, file_id, Some(dir_id))?;
         }
         Commands::ListFileInDir { dir_id } => {
-            list_files_in_directory(&conn, Some(dir_id))?;
+            for (id, filename) in list_files_in_directory(&conn, Some(dir_id))? {
+                println!("{} - {}", id, filename);
+            }
         }
-    }
-
-    Ok(())
-}
-
-fn list_files(conn: &mut Connection) -> Result<()> {
-    let mut stmt = conn
-        .prepare("SELECT id, filename, filesize, chunk_size, created_at FROM files ORDER BY id")?;
-    let rows = stmt.query_map([], |row| {
-        Ok((
-            row.get::<_, i64>(0)?,
-            row.get::<_, String>(1)?,
-            row.get::<_, i64>(2)?,
-            row.get::<_, i64>(3)?,
-            row.get::<_, String>(4)?,
-        ))
-    })?;
-
-    for row in rows {
-        let (id, filename, size, chunk_size, created_at) = row?;
-        println!(
-            "id={:<3} size={:<10} chunk_size={:<7} created_at={} file={} ",
-            id, size, chunk_size, created_at, filename
-        );
-    }
-    Ok(())
-}
-
-fn export_file(
-    conn: &mut Connection,
-    file_id: i64,
-    proxy_base: &str,
-    out: Option<PathBuf>,
-) -> Result<()> {
-    // fetch original filename
-    let mut stmt = conn.prepare("SELECT filename FROM files WHERE id = ?1")?;
-    let filename: String = stmt.query_row(params![file_id], |row| row.get(0))?;
-
-    // prepare output writer
-    let mut out_writer: Box<dyn Write> = if let Some(path) = out {
-        if path.to_string_lossy() == "-" {
-            Box::new(std::io::stdout())
-        } else {
-            Box::new(File::create(path)?)
+        Commands::ListRootFiles => {
+            for (id, filename) in list_files_in_directory(&conn, None)? {
+                println!("{} - {}", id, filename);
+            }
         }
-    } else {
-        Box::new(File::create(filename)?)
-    };
-
-    // query chunks
-    let mut stmt =
-        conn.prepare("SELECT idx, url FROM file_chunks WHERE file_id = ?1 ORDER BY idx ASC")?;
-    let rows = stmt.query_map(params![file_id], |row| {
-        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
-    })?;
-
-    let client = Client::new();
-    for row in rows {
-        let (idx, url) = row?;
-        // wrap original discord cdn url with proxy
-        let proxied_url = format!("{proxy_base}/?{url}");
-
-        eprintln!("Downloading chunk {idx} via {proxied_url}");
-        let mut resp = client.get(&proxied_url).send()?;
-        std::io::copy(&mut resp, &mut out_writer)?;
-    }
-
-    Ok(())
-}
-
-fn ingest_file(
-    conn: &mut Connection,
-    path: &Path,
-    chunk_size: usize,
-    webhook: &str,
-) -> Result<i64> {
-    let mut f = File::open(path)?;
-    let filesize = f.metadata()?.len() as i64;
-    let filename = path.file_name().unwrap().to_string_lossy().to_string();
-
-    conn.execute(
-        "INSERT INTO files (filename, filesize, chunk_size, created_at)
-         VALUES (?1, ?2, ?3, ?4)",
-        params![
-            filename,
-            filesize,
-            chunk_size as i64,
-            Utc::now().to_rfc3339()
-        ],
-    )?;
-    let file_id = conn.last_insert_rowid();
-
-    // Prepare storage directory
-    let dir = PathBuf::from("storage").join(file_id.to_string());
-    fs::create_dir_all(&dir)?;
-
-    let client = Client::new();
-    let results: Arc<Mutex<Vec<(usize, (String, String))>>> = Arc::new(Mutex::new(Vec::new()));
-
-    // Create a channel for processing chunks with bounded capacity to control memory usage
-    let (tx, rx) = crossbeam_channel::bounded(3); // Limit to 3 chunks in flight
-
-    // Spawn worker threads for parallel processing
-    let num_workers = 3;
-    let mut handles = Vec::new();
-
-    for _ in 0..num_workers {
-        let rx = rx.clone();
-        let results = Arc::clone(&results);
-        let client = client.clone();
-        let webhook = webhook.to_string();
-        let dir = dir.clone();
-
-        let handle = thread::spawn(move || {
-            while let Ok((idx, data)) = rx.recv() {
-                let chunk_path = dir.join(format!("{}.chunk", idx));
-
-                // Write chunk to disk
-                if let Err(e) = fs::write(&chunk_path, &data) {
-                    eprintln!("[Chunk {}] Failed to write chunk: {}", idx, e);
-                    continue;
+        Commands::Rename { file_id, name } => {
+            let old = rename_file(&mut conn, file_id, &name)?;
+            println!("Renamed file {} from '{}' to '{}'", file_id, old, name);
+        }
+        Commands::CopyFile {
+            src_file_id,
+            name,
+            dir_id,
+        } => {
+            let new_id = copy_file(&mut conn, src_file_id, &name, dir_id)?;
+            println!(
+                "Copied file {} to '{}' with file_id={}",
+                src_file_id, name, new_id
+            );
+        }
+        Commands::SetDescription {
+            file_id,
+            description,
+        } => {
+            set_description(&conn, file_id, &description)?;
+            if description.is_empty() {
+                println!("Cleared description of file {}", file_id);
+            } else {
+                println!("Set description of file {}", file_id);
+            }
+        }
+        Commands::RenameDir { dir_id, name } => {
+            let old = rename_directory(&mut conn, dir_id, &name)?;
+            println!("Renamed directory {} from '{}' to '{}'", dir_id, old, name);
+        }
+        Commands::Delete {
+            file_id,
+            keep_local,
+            dry_run,
+        } => {
+            delete_file(
+                &mut conn,
+                file_id,
+                config.webhooks()?,
+                &transfer.pacing,
+                keep_local,
+                dry_run,
+            )
+            .await?;
+        }
+        Commands::SoftDelete { file_id } => {
+            set_deleted(&conn, file_id, true)?;
+            println!("Soft-deleted file_id={}", file_id);
+        }
+        Commands::Undelete { file_id } => {
+            set_deleted(&conn, file_id, false)?;
+            println!("Restored file_id={}", file_id);
+        }
+        Commands::PurgeDeleted {
+            older_than_days,
+            dry_run,
+        } => {
+            let purged = purge_deleted(
+                &mut conn,
+                older_than_days,
+                config.webhooks()?,
+                &transfer.pacing,
+                dry_run,
+            )
+            .await?;
+            if dry_run {
+                println!("Would purge {} file(s)", purged);
+            } else {
+                println!("Purged {} file(s)", purged);
+            }
+        }
+        Commands::AuditLog {
+            entity_type,
+            entity_id,
+            limit,
+        } => {
+            cli.format
+                .print(&audit_log(&conn, entity_type.as_deref(), entity_id, limit)?)?;
+        }
+        Commands::DuplicateCheck { delete_duplicates } => {
+            let groups = find_duplicates(&conn)?;
+            for group in &groups {
+                println!("sha256={}", group.sha256);
+                for (file_id, filename) in &group.files {
+                    println!("  id={:<3} file={}", file_id, filename);
                 }
-
-                // Upload with retry logic
-                match upload_chunk_with_retry(&client, &webhook, &chunk_path, idx) {
-                    Ok(res) => {
-                        results.lock().unwrap().push(res);
-                    }
-                    Err(e) => {
-                        eprintln!("[Chunk {}] Failed permanently: {}", idx, e);
+            }
+            if groups.is_empty() {
+                println!("No duplicate files");
+            } else if delete_duplicates {
+                let mut removed = 0;
+                for group in &groups {
+                    for (file_id, _) in &group.files[1..] {
+                        set_deleted(&conn, *file_id, true)?;
+                        removed += 1;
                     }
                 }
-
-                // Add a random delay after each upload to spread requests
-                let delay = rand::rng().random_range(2..=6);
-                thread::sleep(Duration::from_secs(delay));
+                println!("Soft-deleted {} duplicate(s)", removed);
             }
-        });
-
-        handles.push(handle);
-    }
-
-    // Read and process file in chunks without loading everything into memory
-    let mut buffer = vec![0u8; chunk_size];
-    let mut idx = 0;
-
-    loop {
-        let n = f.read(&mut buffer)?;
-        if n == 0 {
-            break;
         }
-
-        // Send chunk to worker threads
-        let chunk_data = buffer[..n].to_vec();
-        tx.send((idx, chunk_data))?;
-        idx += 1;
-    }
-
-    // Close the channel to signal workers to exit
-    drop(tx);
-
-    // Wait for all workers to finish
-    for handle in handles {
-        handle.join().unwrap();
-    }
-
-    // Insert results sequentially
-    let mut results = Arc::try_unwrap(results).unwrap().into_inner().unwrap();
-    results.sort_by_key(|(idx, _)| *idx);
-    for (idx, data) in results {
-        conn.execute(
-            "INSERT INTO file_chunks (file_id, idx, message_id, url) VALUES (?1, ?2, ?3, ?4)",
-            params![file_id, idx as i64, data.0, data.1],
-        )?;
-    }
-
-    Ok(file_id)
-}
-
-fn upload_chunk_with_retry(
-    client: &Client,
-    webhook: &str,
-    chunk_path: &Path,
-    idx: usize,
-) -> Result<(usize, (String, String))> {
-    let mut attempts = 0;
-    loop {
-        attempts += 1;
-        let form = reqwest::blocking::multipart::Form::new().file("file", chunk_path)?;
-        let resp = client.post(webhook).multipart(form).send();
-
-        match resp {
-            Ok(r) => {
-                if r.status().as_u16() == 429 {
-                    // Rate limited, sleep and retry
-                    let delay = rand::rng().random_range(5..=15);
-                    eprintln!("[Chunk {}] Rate limited. Sleeping {}s", idx, delay);
-                    thread::sleep(Duration::from_secs(delay));
-                    continue;
+        Commands::ImportUrls { file_id, csv } => {
+            let (inserted, skipped) = import_urls(&mut conn, file_id, &csv)?;
+            println!(
+                "Imported {} chunk(s) for file_id={}, skipped {} duplicate(s)",
+                inserted, file_id, skipped
+            );
+        }
+        Commands::ImportFromDir {
+            file_id,
+            storage_dir,
+            skip_upload,
+        } => {
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            let webhooks = if skip_upload {
+                None
+            } else {
+                Some(config.webhooks()?)
+            };
+            let count = import_from_dir(
+                &mut conn,
+                file_id,
+                &storage_dir,
+                webhooks,
+                &transfer,
+                cipher,
+            )
+            .await?;
+            println!(
+                "Imported {} chunk(s) for file_id={}{}",
+                count,
+                file_id,
+                if skip_upload { " (local only)" } else { "" }
+            );
+        }
+        Commands::Reupload { file_id, chunk_idx } => {
+            let count =
+                reupload_chunks(&mut conn, file_id, chunk_idx, config.webhooks()?, &transfer)
+                    .await?;
+            println!("Re-uploaded {} chunk(s) for file_id={}", count, file_id);
+        }
+        Commands::Refresh { file_id } => {
+            let count = refresh_file(&conn, file_id, config.webhooks()?, &transfer.pacing).await?;
+            println!("Refreshed {} chunk URL(s) for file_id={}", count, file_id);
+        }
+        Commands::ExportUrl {
+            file_id,
+            chunk_idx,
+            refresh,
+        } => {
+            let refresh = if refresh {
+                Some((config.webhooks()?, &transfer.pacing))
+            } else {
+                None
+            };
+            let url = chunk_url(&conn, file_id, chunk_idx, refresh).await?;
+            match std::env::var("PROXY_BASE") {
+                Ok(proxy_base) if !url.starts_with(LOCAL_URL_PREFIX) => {
+                    println!("{proxy_base}/?{url}")
                 }
-                let json: serde_json::Value = r.json()?;
-                let message_id = json["id"].as_str().unwrap().to_string();
-                let url = json["attachments"][0]["url"].as_str().unwrap().to_string();
-                return Ok((idx, (message_id, url)));
+                _ => println!("{url}"),
             }
-            Err(e) => {
-                if attempts < 5 {
-                    let delay = 2u64.pow(attempts);
-                    eprintln!(
-                        "[Chunk {}] Upload failed: {}. Retrying in {}s",
-                        idx, e, delay
-                    );
-                    thread::sleep(Duration::from_secs(delay));
-                    continue;
-                } else {
-                    return Err(e.into());
-                }
+        }
+        Commands::RefreshAll { older_than_hours } => {
+            let count = refresh_all(
+                &conn,
+                older_than_hours,
+                config.webhooks()?,
+                &transfer.pacing,
+            )
+            .await?;
+            println!("Refreshed {} chunk URL(s)", count);
+        }
+        Commands::Tui => {
+            #[cfg(feature = "tui")]
+            {
+                // the TUI owns the terminal, so bars would only garble it
+                transfer
+                    .progress
+                    .set_draw_target(ProgressDrawTarget::hidden());
+                let settings = tui::Settings {
+                    config: &config,
+                    ingest: ingest_options(&config, &transfer),
+                    transfer: transfer.clone(),
+                    cipher: config.encryption_key.as_deref().map(load_key).transpose()?,
+                };
+                tui::run(&mut conn, settings).await?;
             }
+            #[cfg(not(feature = "tui"))]
+            bail!("this build has no TUI; rebuild with `cargo build --release --features tui`");
         }
-    }
-}
-
-fn init_schema(conn: &mut Connection) -> Result<()> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS files (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            filename TEXT NOT NULL,
-            filesize INTEGER NOT NULL,
-            chunk_size INTEGER NOT NULL,
-            created_at TEXT NOT NULL
-        )",
-        [],
-    )?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS file_chunks (
-            file_id INTEGER NOT NULL,
-            idx INTEGER NOT NULL,
-            url TEXT NOT NULL,
-            message_id TEXT NOT NULL,
-            PRIMARY KEY(file_id, idx)
-        )",
-        [],
-    )?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS directories (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            name TEXT NOT NULL,
-            parent_id INTEGER,
-            created_at TEXT NOT NULL,
-            FOREIGN KEY(parent_id) REFERENCES directories(id)
-        )",
-        [],
-    )?;
-    Ok(())
-}
-
-fn create_directory(conn: &mut Connection, name: &str, parent_id: Option<i64>) -> Result<i64> {
-    conn.execute(
-        "INSERT INTO directories (name, parent_id, created_at) VALUES (?1, ?2, datetime('now'))",
-        params![name, parent_id],
-    )?;
-    Ok(conn.last_insert_rowid())
-}
-
-fn list_files_in_directory(conn: &Connection, dir_id: Option<i64>) -> Result<Vec<(i64, String)>> {
-    let mut stmt = match dir_id {
-        Some(_) => conn.prepare("SELECT id, filename FROM files WHERE id = ?1"),
-        None => conn.prepare("SELECT id, filename FROM files WHERE id IS NULL"),
-    }?;
-
-    let rows: Vec<(i64, String)> = match dir_id {
-        Some(did) => stmt
-            .query_map(params![did], |row| Ok((row.get(0)?, row.get(1)?)))?
-            .collect::<std::result::Result<Vec<_>, _>>()?,
-        None => stmt
-            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
-            .collect::<std::result::Result<Vec<_>, _>>()?,
-    };
-
-    Ok(rows)
-}
-
-fn move_file_to_directory(conn: &mut Connection, file_id: i64, dir_id: Option<i64>) -> Result<()> {
-    conn.execute(
-        "UPDATE files SET directory_id = ?1 WHERE id = ?2",
-        params![dir_id, file_id],
-    )?;
-    Ok(())
-}
-
-fn list_directories(conn: &Connection, parent_id: Option<i64>) -> Result<Vec<(i64, String)>> {
-    let mut stmt = match parent_id {
-        Some(_) => conn.prepare("SELECT id, name FROM directories WHERE parent_id = ?1"),
-        None => conn.prepare("SELECT id, name FROM directories WHERE parent_id IS NULL"),
-    }?;
-
-    let rows: Vec<(i64, String)> = match parent_id {
-        Some(pid) => stmt
-            .query_map(params![pid], |row| Ok((row.get(0)?, row.get(1)?)))?
-            .collect::<std::result::Result<Vec<_>, _>>()?,
-        None => stmt
-            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
-            .collect::<std::result::Result<Vec<_>, _>>()?,
-    };
-
-    Ok(rows)
-}
-
-fn verify_file(conn: &mut Connection, file_id: i64) -> Result<()> {
-    // return true if all chunk hashes match recomputed hashes
-    let mut ok_all = true;
-    let mut stmt =
-        conn.prepare("SELECT idx, data, sha256 FROM chunks WHERE file_id = ?1 ORDER BY idx ASC")?;
-    let mut rows = stmt.query(params![file_id])?;
-    while let Some(row) = rows.next()? {
-        let idx: i64 = row.get(0)?;
-        let data: Vec<u8> = row.get(1)?;
-        let stored: String = row.get(2)?;
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
-        let calc = hex::encode(hasher.finalize());
-        if calc != stored {
-            println!("Chunk {}: MISMATCH (stored={}, calc={})", idx, stored, calc);
-            ok_all = false;
+        Commands::ChangeDbPassphrase { new, .. } => {
+            change_db_passphrase(&conn, &new)?;
+            println!("Changed the passphrase of {}", config.db.display());
+        }
+        Commands::Cancel { file_id } => {
+            let sentinel = request_cancel(&conn, file_id)?;
+            println!(
+                "Cancelling file_id={}; the upload stops before its next chunk ({})",
+                file_id,
+                sentinel.display()
+            );
+        }
+        Commands::Stats { file_id } => match file_id {
+            Some(file_id) => print_file_stats(&conn, file_id)?,
+            None => print_stats(&conn)?,
+        },
+        Commands::Resume { file_id, path } => {
+            let cipher = config.encryption_key.as_deref().map(load_key).transpose()?;
+            let uploaded = resume_ingest(
+                &mut conn,
+                file_id,
+                &path,
+                config.webhooks()?,
+                &transfer,
+                cipher,
+            )
+            .await?;
+            println!(
+                "Uploaded {} missing chunk(s) for file_id={}",
+                uploaded, file_id
+            );
+        }
+        Commands::GenerateKey { out, bits } => {
+            let key = generate_key(&out, bits.unwrap_or(256))?;
+            println!(
+                "Wrote {}-byte AES-{} key to {}",
+                key.len(),
+                key.len() * 8,
+                out.display()
+            );
+            println!("Fingerprint: {}", key_fingerprint(&key));
+        }
+        Commands::ShowKeyInfo { key_file } => {
+            let key = read_key(&key_file)?;
+            println!("Length: {} bytes (AES-{})", key.len(), key.len() * 8);
+            println!("Fingerprint: {}", key_fingerprint(&key));
+        }
+        Commands::ShellCompletion { .. } => unreachable!("handled before opening the database"),
+        Commands::WorkspaceList => {
+            let rows = list_workspaces(&config);
+            if rows.is_empty() && cli.format == Formatter::Human {
+                println!("No workspaces configured");
+            } else {
+                cli.format.print(&rows)?;
+            }
+        }
+        Commands::WorkspaceAdd { name, db, webhook } => {
+            let path = add_workspace(&name, &db, &webhook)?;
+            println!("Added workspace '{}' to {}", name, path.display());
+        }
+        Commands::WorkspaceRemove { name } => {
+            let path = remove_workspace(&name)?;
+            println!("Removed workspace '{}' from {}", name, path.display());
         }
     }
-    if ok_all {
-        println!("All chunks verified for file_id={}", file_id);
-    }
+
     Ok(())
 }
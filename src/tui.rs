@@ -0,0 +1,380 @@
+//! `tui`: browse directories and files, with the usual operations on the
+//! selected file a key press away.
+
+use anyhow::{anyhow, Result};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use rusqlite::Connection;
+use std::path::PathBuf;
+
+use octo_potato::*;
+
+/// Everything the TUI needs besides the database
+pub struct Settings<'a> {
+    pub config: &'a Config,
+    pub ingest: IngestOptions,
+    pub transfer: TransferOptions,
+    pub cipher: Option<ChunkCipher>,
+}
+
+enum Entry {
+    Parent,
+    Dir(i64, String),
+    File(i64, String),
+}
+
+/// What the bottom line is waiting for
+enum Input {
+    None,
+    /// A path to ingest, when no file picker can be shown
+    IngestPath(String),
+    ExportPath(i64, String),
+    /// Space-separated tags; `-name` removes one
+    Tags(i64, String),
+    ConfirmDelete(i64),
+}
+
+struct App<'a> {
+    conn: &'a mut Connection,
+    settings: Settings<'a>,
+    /// Directories from the root down to the current one
+    path: Vec<(i64, String)>,
+    entries: Vec<Entry>,
+    state: ListState,
+    details: Option<FileDetails>,
+    input: Input,
+    status: String,
+}
+
+/// Run the TUI until `q` is pressed.
+pub async fn run(conn: &mut Connection, settings: Settings<'_>) -> Result<()> {
+    let mut app = App {
+        conn,
+        settings,
+        path: Vec::new(),
+        entries: Vec::new(),
+        state: ListState::default(),
+        details: None,
+        input: Input::None,
+        status: String::from(
+            "↑↓ move  enter open  ← back  i ingest  e export  d delete  t tags  q quit",
+        ),
+    };
+    app.reload(None)?;
+    let mut terminal = ratatui::init();
+    let result = app.event_loop(&mut terminal).await;
+    ratatui::restore();
+    result
+}
+
+impl App<'_> {
+    fn dir_id(&self) -> Option<i64> {
+        self.path.last().map(|(id, _)| *id)
+    }
+
+    /// Re-read the current directory, selecting `file_id` if it is there.
+    fn reload(&mut self, file_id: Option<i64>) -> Result<()> {
+        let dir_id = self.dir_id();
+        let mut entries = Vec::new();
+        if dir_id.is_some() {
+            entries.push(Entry::Parent);
+        }
+        for (id, name) in list_directories(self.conn, dir_id)? {
+            entries.push(Entry::Dir(id, name));
+        }
+        for (id, name) in list_files_in_directory(self.conn, dir_id)? {
+            entries.push(Entry::File(id, name));
+        }
+        let selected = file_id
+            .and_then(|file_id| {
+                entries
+                    .iter()
+                    .position(|e| matches!(e, Entry::File(id, _) if *id == file_id))
+            })
+            .or(self.state.selected())
+            .map(|i| i.min(entries.len().saturating_sub(1)));
+        self.entries = entries;
+        self.state
+            .select(selected.or((!self.entries.is_empty()).then_some(0)));
+        self.load_details()
+    }
+
+    fn selected_file(&self) -> Option<i64> {
+        match self.entries.get(self.state.selected()?)? {
+            Entry::File(id, _) => Some(*id),
+            _ => None,
+        }
+    }
+
+    fn load_details(&mut self) -> Result<()> {
+        self.details = self
+            .selected_file()
+            .map(|id| file_details(self.conn, id))
+            .transpose()?;
+        Ok(())
+    }
+
+    async fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let outcome = match self.input {
+                Input::None => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    code => self.on_key(code, terminal).await,
+                },
+                _ => self.on_input(key.code).await,
+            };
+            if let Err(e) = outcome {
+                self.status = format!("Error: {e:#}");
+            }
+            // operations print and log on their own, so repaint everything
+            terminal.clear()?;
+        }
+    }
+
+    async fn on_key(&mut self, code: KeyCode, terminal: &mut DefaultTerminal) -> Result<()> {
+        match code {
+            KeyCode::Up => {
+                self.state.select_previous();
+                self.load_details()?;
+            }
+            KeyCode::Down => {
+                self.state.select_next();
+                self.load_details()?;
+            }
+            KeyCode::Enter | KeyCode::Right => {
+                if let Some(i) = self.state.selected() {
+                    match &self.entries[i] {
+                        Entry::Parent => self.leave()?,
+                        Entry::Dir(id, name) => {
+                            self.path.push((*id, name.clone()));
+                            self.state.select(Some(0));
+                            self.reload(None)?;
+                        }
+                        Entry::File(..) => {}
+                    }
+                }
+            }
+            KeyCode::Left | KeyCode::Backspace => self.leave()?,
+            KeyCode::Char('i') => {
+                // rfd needs a desktop portal, which a plain SSH session doesn't have
+                let desktop = std::env::var_os("DISPLAY").is_some()
+                    || std::env::var_os("WAYLAND_DISPLAY").is_some();
+                if desktop {
+                    self.status = String::from("Waiting for the file picker…");
+                    terminal.draw(|frame| self.draw(frame))?;
+                    match rfd::AsyncFileDialog::new().pick_file().await {
+                        Some(handle) => self.ingest(handle.path().to_path_buf()).await?,
+                        None => self.status = String::from("No file picked"),
+                    }
+                } else {
+                    self.input = Input::IngestPath(String::new());
+                }
+            }
+            KeyCode::Char('e') => {
+                if let Some(details) = &self.details {
+                    self.input = Input::ExportPath(details.id, details.filename.clone());
+                }
+            }
+            KeyCode::Char('d') => {
+                if let Some(file_id) = self.selected_file() {
+                    self.input = Input::ConfirmDelete(file_id);
+                }
+            }
+            KeyCode::Char('t') => {
+                if let Some(details) = &self.details {
+                    self.input = Input::Tags(details.id, String::new());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) -> Result<()> {
+        if self.path.pop().is_some() {
+            self.state.select(Some(0));
+            self.reload(None)?;
+        }
+        Ok(())
+    }
+
+    async fn on_input(&mut self, code: KeyCode) -> Result<()> {
+        let input = std::mem::replace(&mut self.input, Input::None);
+        if let Input::ConfirmDelete(file_id) = input {
+            if code == KeyCode::Char('y') {
+                let webhooks = self.settings.config.webhooks()?;
+                delete_file(
+                    self.conn,
+                    file_id,
+                    webhooks,
+                    &self.settings.transfer.pacing,
+                    false,
+                    false,
+                )
+                .await?;
+                self.status = format!("Deleted file_id={file_id}");
+                self.reload(None)?;
+            }
+            return Ok(());
+        }
+        let (mut text, submit) = match (input, code) {
+            (_, KeyCode::Esc) => return Ok(()),
+            (input, KeyCode::Enter) => (input, true),
+            (mut input, KeyCode::Backspace) => {
+                if let Some(text) = input.text_mut() {
+                    text.pop();
+                }
+                (input, false)
+            }
+            (mut input, KeyCode::Char(c)) => {
+                if let Some(text) = input.text_mut() {
+                    text.push(c);
+                }
+                (input, false)
+            }
+            (input, _) => (input, false),
+        };
+        if !submit {
+            self.input = text;
+            return Ok(());
+        }
+        match &mut text {
+            Input::IngestPath(path) => self.ingest(PathBuf::from(path.trim())).await?,
+            Input::ExportPath(file_id, path) => self.export(*file_id, path.trim()).await?,
+            Input::Tags(file_id, tags) => self.tag(*file_id, tags)?,
+            Input::None | Input::ConfirmDelete(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Ingest `path` into the current directory and select it.
+    async fn ingest(&mut self, path: PathBuf) -> Result<()> {
+        let webhooks = self.settings.config.webhooks()?;
+        let file_id = ingest_file(self.conn, &path, webhooks, &self.settings.ingest).await?;
+        if self.dir_id().is_some() {
+            move_file_to_directory(self.conn, file_id, self.dir_id())?;
+        }
+        self.status = format!("Ingested '{}' with file_id={}", path.display(), file_id);
+        self.reload(Some(file_id))
+    }
+
+    async fn export(&mut self, file_id: i64, path: &str) -> Result<()> {
+        let proxy_base =
+            std::env::var("PROXY_BASE").map_err(|_| anyhow!("PROXY_BASE must be set"))?;
+        let integrity = export_file(
+            self.conn,
+            file_id,
+            &proxy_base,
+            Some(PathBuf::from(path)),
+            ChunkCheck::Off,
+            DEFAULT_DOWNLOAD_RETRIES,
+            OutputFormat::File,
+            &self.settings.transfer,
+            self.settings.cipher.clone(),
+            true,
+        )
+        .await?;
+        self.status = match integrity.message() {
+            Some(message) => format!("Exported file_id={file_id} to {path}: {message}"),
+            None => format!("Exported file_id={file_id} to {path}"),
+        };
+        Ok(())
+    }
+
+    fn tag(&mut self, file_id: i64, input: &str) -> Result<()> {
+        let (remove, add): (Vec<String>, Vec<String>) = input
+            .split_whitespace()
+            .map(String::from)
+            .partition(|t| t.starts_with('-'));
+        let remove: Vec<String> = remove.iter().map(|t| t[1..].to_string()).collect();
+        if !add.is_empty() {
+            tag_file(self.conn, file_id, &add)?;
+        }
+        if !remove.is_empty() {
+            untag_file(self.conn, file_id, &remove)?;
+        }
+        self.status = format!("Updated the tags of file_id={file_id}");
+        self.load_details()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, bottom] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main);
+
+        let title = std::iter::once("/")
+            .chain(self.path.iter().map(|(_, name)| name.as_str()))
+            .collect::<Vec<_>>()
+            .join(" / ");
+        let items: Vec<ListItem> = self
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                Entry::Parent => ListItem::new(".."),
+                Entry::Dir(_, name) => ListItem::new(format!("{name}/")),
+                Entry::File(id, name) => ListItem::new(format!("{name}  ({id})")),
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, left, &mut self.state);
+
+        let lines = match &self.details {
+            Some(d) => vec![
+                Line::from(format!("File:         {}", d.filename)),
+                Line::from(format!("ID:           {}", d.id)),
+                Line::from(format!("Size:         {}", format_size(d.filesize))),
+                Line::from(format!("Status:       {}", d.status)),
+                Line::from(format!("Chunks:       {}", d.chunks)),
+                Line::from(format!(
+                    "Hash:         {}",
+                    d.sha256.as_deref().unwrap_or("-")
+                )),
+                Line::from(format!("Tags:         {}", d.tags.join(", "))),
+                Line::from(format!(
+                    "Description:  {}",
+                    d.description.as_deref().unwrap_or("")
+                )),
+            ],
+            None => Vec::new(),
+        };
+        let details = Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(Block::default().borders(Borders::ALL).title("Details"));
+        frame.render_widget(details, right);
+
+        let prompt = match &self.input {
+            Input::None => self.status.clone(),
+            Input::IngestPath(text) => format!("Ingest path: {text}"),
+            Input::ExportPath(_, text) => format!("Export to: {text}"),
+            Input::Tags(_, text) => format!("Tags to add, -tag to remove: {text}"),
+            Input::ConfirmDelete(id) => format!("Delete file_id={id} from Discord? (y/n)"),
+        };
+        frame.render_widget(Paragraph::new(prompt), bottom);
+    }
+}
+
+impl Input {
+    fn text_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Input::IngestPath(text) | Input::ExportPath(_, text) | Input::Tags(_, text) => {
+                Some(text)
+            }
+            Input::None | Input::ConfirmDelete(_) => None,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Small formatting helpers shared by the library, the CLI and the TUI.
+
+/// `6878658` -> `6.56 MiB`, in IEC units to two decimal places; plain bytes
+/// below 1 KiB.
+pub fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let sign = if bytes < 0 { "-" } else { "" };
+    let abs = bytes.unsigned_abs();
+    if abs < 1024 {
+        return format!("{sign}{abs} B");
+    }
+    let mut size = abs as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{sign}{:.2} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_size_keeps_bytes_below_one_kib() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+    }
+
+    #[test]
+    fn format_size_switches_to_kib_at_1024() {
+        assert_eq!(format_size(1024), "1.00 KiB");
+        assert_eq!(format_size(1025), "1.00 KiB");
+        assert_eq!(format_size(1536), "1.50 KiB");
+    }
+
+    #[test]
+    fn format_size_picks_the_largest_unit() {
+        assert_eq!(format_size(7_340_032), "7.00 MiB");
+        assert_eq!(format_size(6_878_658), "6.56 MiB");
+        assert_eq!(format_size((1 << 30) + 1), "1.00 GiB");
+        assert_eq!(format_size(5 << 40), "5.00 TiB");
+        assert_eq!(format_size(2048 << 40), "2048.00 TiB");
+    }
+
+    #[test]
+    fn format_size_keeps_the_sign() {
+        assert_eq!(format_size(-512), "-512 B");
+        assert_eq!(format_size(-2048), "-2.00 KiB");
+    }
+}